@@ -2,27 +2,20 @@
 
 
 
-// MACROS
+// ATTRIBUTES
 
-macro_rules! rp
-{
-    ( $res:expr ) => (
-        match $res {
-            Ok( value_ ) => { value_ },
-            Err( error_ ) => {
-                let error_msg = format!(
-                    "cpdd: main: `{}` failed: {:?}",
-                    stringify!( $res ),
-                    error_,
-                ).replace( '\n', " " );  // FIX?: use null string
+#![ warn( clippy::all ) ]
+#![ allow( clippy::needless_return ) ]
 
-                log::error!( "{}", error_msg );
 
-                panic!( "{}", error_msg );
-            },
-        }
-    );
-}
+
+// CONSTANTS
+
+const EXIT_FATAL: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_MISMATCHES: i32 = 3;
+const EXIT_PARTIAL: i32 = 4;
+const EXIT_CANCELLED: i32 = 130;
 
 
 
@@ -51,6 +44,20 @@ struct CliArgs
     /// If this option is set, log output is written also to the given path.
     log_path: Option< String >,
 
+    #[ structopt( long, default_value = "text", possible_values = &[ "text", "json" ] ) ]
+    /// The output format.
+    /// Possible values:
+    ///     `text`: human-readable output,
+    ///     `json`: one JSON object per event, followed by a summary object.
+    output: OutputFormat,
+
+    #[ structopt( long ) ]
+    /// Do not display progress.
+    ///
+    /// By default, if stderr is a terminal,
+    /// progress is displayed on stderr while copying.
+    no_progress: bool,
+
     #[ structopt( subcommand ) ]
     /// The action to be taken.
     action: Action,
@@ -70,8 +77,120 @@ enum Action
         ///
         /// Note that existing destination directories are not overwritten
         /// but are merged or renamed, depending on the source file type.
+        /// Same as `--on-conflict overwrite`.
         overwrite_dst: bool,
 
+        #[ structopt(
+            long,
+            possible_values = cpdd::ConflictPolicy::VARIANTS,
+            conflicts_with = "overwrite-dst",
+        ) ]
+        /// The policy to apply when a destination path exists
+        /// but is not equivalent to the source path.
+        /// Possible values:
+        ///     `rename` (default): rename the destination path,
+        ///     `overwrite`: remove the destination path,
+        ///     `newer`: keep the newer path, place the older under a backup name,
+        ///     `larger`: keep the larger path, place the smaller under a backup name,
+        ///     `skip`: keep the destination path, skip the source path,
+        ///     `fail`: keep the destination path, fail with an error,
+        ///     `both`: keep the destination path, place the source under a backup name.
+        on_conflict: Option< cpdd::ConflictPolicy >,
+
+        #[ structopt( long ) ]
+        /// The conflict log path.
+        ///
+        /// If this option is set, each conflict is appended to the given path
+        /// as a JSON object on its own line.
+        conflict_log: Option< String >,
+
+        #[ structopt(
+            long,
+            default_value = "reflink",
+            possible_values = cpdd::LinkMode::VARIANTS,
+        ) ]
+        /// The link mode to use for creating destination files.
+        /// Possible values:
+        ///     `reflink`: reflink destination files,
+        ///     `hardlink`: hardlink destination files
+        ///         and make reflink directory files read-only,
+        ///     `copy`: copy destination files,
+        ///     `auto`: use the first of the above that is supported.
+        ///
+        /// Note that hardlinked files share their metadata
+        /// with the reflink directory file and with each other.
+        link_mode: cpdd::LinkMode,
+
+        #[ structopt( long, possible_values = &[ "hardlink", "copy" ] ) ]
+        /// The link mode to fall back to if the pre-flight check fails.
+        ///
+        /// With the `reflink` link mode, before copying,
+        /// a test file is reflinked from the reflink directory
+        /// to the destination directory.
+        /// By default, a failing check results in an error.
+        /// If this option is set, destination files are created
+        /// using the given link mode instead.
+        fallback_mode: Option< cpdd::LinkMode >,
+
+        #[ structopt( long ) ]
+        /// Check free space before copying.
+        ///
+        /// If this option is set, the source paths are hashed in advance
+        /// to estimate the number of bytes to be written,
+        /// which is then compared against the free space.
+        check_space: bool,
+
+        #[ structopt( long ) ]
+        /// Count the source files and bytes before copying.
+        ///
+        /// If this option is set, or if free space is checked,
+        /// the progress display includes the totals and an estimated time remaining.
+        prescan: bool,
+
+        #[ structopt( long, default_value = "0", parse( try_from_str = parse_size ) ) ]
+        /// The free space to keep in reserve.
+        ///
+        /// Copying stops with an error before writing a file
+        /// that would leave less free space than this.
+        /// Accepts the binary suffixes `K`, `M`, `G`, and `T`.
+        min_free: u64,
+
+        #[ structopt( long ) ]
+        /// The manifest path.
+        ///
+        /// If this option is set, once copying is done,
+        /// each path in the destination directory
+        /// is written to the given path as a JSON object on its own line,
+        /// with its type, size, mode, times, hash, and link target.
+        /// Files not placed by the copy are hashed.
+        /// If copying fails, no manifest is kept.
+        manifest: Option< String >,
+
+        #[ structopt( long, conflicts_with = "manifest" ) ]
+        /// The snapshot name.
+        ///
+        /// If this option is set, a manifest of the destination directory,
+        /// as for `--manifest`, is recorded as the named snapshot
+        /// in the reflink directory; see `snapshot`.
+        snapshot: Option< String >,
+
+        #[ structopt( long ) ]
+        /// Replace source files with reflinks of their reflink directory files.
+        ///
+        /// If this option is set, after a source file is inserted into the reflink directory,
+        /// it is replaced atomically with a reflink of the reflink directory file,
+        /// made to a temporary file whose ownership, permissions, times,
+        /// and extended attributes (including ACLs and security labels) are set first,
+        /// and whose content is compared with the source file right before the replacement.
+        /// The replaced source files get new inode numbers.
+        /// Source files modified since they were hashed,
+        /// with multiple hardlinks, with `chattr` flags differing from those of the reflink,
+        /// or already sharing all extents with their reflink directory files are not replaced;
+        /// failing replacements are reported as errors without stopping the copy.
+        /// Requires a test reflink from the reflink directory to each source directory
+        /// to succeed.
+        in_place_link: bool,
+
         #[ structopt( long = "skip-invalid" ) ]
         /// Skip invalid source file types.
         ///
@@ -79,9 +198,23 @@ enum Action
         /// If this option is set, invalid file types result only in a warning.
         skip_invalid_file_types: bool,
 
+        #[ structopt(
+            long,
+            default_value = "suffix",
+            possible_values = cpdd::BackupStyle::VARIANTS,
+        ) ]
+        /// The naming scheme to use for backup paths.
+        /// Possible values:
+        ///     `suffix`: append the backup suffix, renaming existing backups,
+        ///     `numbered`: append the next free number, like `file.~1~`,
+        ///     `timestamp`: append the current UTC time,
+        ///     `hash`: append a short content hash.
+        backup_style: cpdd::BackupStyle,
+
         #[ structopt( long, default_value = "~" ) ]
-        /// The backup suffix to use for renaming existing destination paths.
-        /// Must not be the null string.
+        /// The backup suffix to use for renaming existing destination paths
+        /// with the `suffix` backup style.
+        /// Must not be the null string, which is rejected as a usage error.
         backup_suffix: String,
 
         #[ structopt( short, long, required = true ) ]
@@ -98,15 +231,265 @@ enum Action
         src_paths: Vec< String >,
     },
 
+    /// Compare two directory trees by content.
+    ///
+    /// Each relative path is classified as
+    /// `only-in-a`, `only-in-b`, `identical`, `content-differs`,
+    /// `type-differs`, or `symlink-target-differs`,
+    /// using the same equivalence rules as `copy`.
+    /// Copying tree B into tree A renames the path in tree A
+    /// for each differing path, unless `--overwrite` is given.
+    Diff{
+        /// Tree A.
+        a_dir: String,
+
+        /// Tree B.
+        b_dir: String,
+    },
+
     /// Verify reflink directory file hashes.
+    ///
+    /// Each reflink directory entry is classified as valid,
+    /// a hash mismatch, a zero-length file, an unexpected name,
+    /// an unexpected file type, or unreadable.
     Verify{
+        #[ structopt( long, default_value = "1" ) ]
+        /// The number of files to hash in parallel.
+        /// If `0`, the number of available CPUs is used.
+        ///
+        /// Note that each job uses a 256 MiB read buffer,
+        /// so memory use is up to 256 MiB times the number of jobs;
+        /// with `0`, 256 MiB times the number of CPUs.
+        jobs: usize,
+
+        #[ structopt( long ) ]
+        /// The checkpoint path.
+        ///
+        /// If this option is set, the time each file is verified
+        /// is recorded to the given path as a JSON object on its own line.
+        checkpoint: Option< String >,
+
+        #[ structopt(
+            long,
+            requires = "checkpoint",
+            parse( try_from_str = parse_duration ),
+        ) ]
+        /// Skip files verified within the given interval, according to the checkpoint.
+        ///
+        /// Accepts the suffixes `s`, `m`, `h`, `d`, and `w`.
+        /// To resume an interrupted verification,
+        /// repeat it with an interval covering its duration.
+        since: Option< std::time::Duration >,
+
+        #[ structopt( long ) ]
+        /// Move mismatching files to the `quarantine` subdirectory
+        /// of the reflink directory.
+        ///
+        /// Quarantined files are no longer linked to by later copies.
+        quarantine: bool,
+
+        #[ structopt( long, requires = "from" ) ]
+        /// Repair mismatching files from the trees given by `--from`.
+        ///
+        /// The trees are searched for files with the expected hashes,
+        /// which then replace the mismatching files.
+        repair: bool,
+
+        #[ structopt( long, number_of_values = 1, requires = "repair" ) ]
+        /// A tree to search for repairing mismatching files.
+        ///
+        /// Repeat the option for each tree, as in `--from a --from b`.
+        from: Vec< String >,
+
+        #[ structopt( long ) ]
+        /// Remove foreign entries from the reflink directory.
+        ///
+        /// Foreign entries are those whose name is not a well-formed hash
+        /// and those that are not files (directories are removed with their contents),
+        /// except for the `snapshots` and `quarantine` subdirectories.
+        /// Temporary files of other runs (`.cpdd-preflight.*` and `.cpdd-repair.*`)
+        /// are kept unless modified more than a day ago.
+        clean_foreign: bool,
+
         // #[ structopt( required = true ) ]
         /// The reflink directory.
         reflink_dir: String,
     },
 
+    /// Write a manifest of a directory tree.
+    ///
+    /// Each path in the tree is written as a JSON object on its own line,
+    /// with its type, size, mode, times, hash, and link target.
+    Manifest{
+        #[ structopt( long ) ]
+        /// The manifest path.
+        /// By default, the manifest is written to stdout.
+        manifest: Option< String >,
+
+        /// The directory.
+        dir: String,
+    },
+
+    /// Verify a directory tree against a manifest.
+    ///
+    /// Each relative path is classified as
+    /// `missing`, `untracked`, `identical`, `content-differs`,
+    /// `type-differs`, `symlink-target-differs`, or `metadata-differs`,
+    /// where metadata covers the mode and the modification time.
+    /// In text output, identical paths are not listed.
+    VerifyTree{
+        #[ structopt( long, required = true ) ]
+        /// The manifest path.
+        manifest: String,
+
+        /// The directory.
+        dir: String,
+    },
+
+    /// Restore a directory tree from a manifest and the reflink directory.
+    ///
+    /// Each file listed in the manifest is linked from its reflink directory file;
+    /// directories and symlinks are recreated,
+    /// and the listed permissions and times are applied.
+    /// Files missing from the reflink directory, or not matching their hashes,
+    /// and paths below a symlink are reported as errors.
+    Restore{
+        #[ structopt( long, required = true ) ]
+        /// The manifest path.
+        manifest: String,
+
+        #[ structopt(
+            long,
+            default_value = "reflink",
+            possible_values = cpdd::LinkMode::VARIANTS,
+        ) ]
+        /// The link mode to use for creating destination files.
+        /// Possible values: see `copy`.
+        link_mode: cpdd::LinkMode,
+
+        #[ structopt( short, long, required = true ) ]
+        /// The reflink directory.
+        reflink_dir: String,
+
+        #[ structopt( short, long, required = true ) ]
+        /// The destination directory.
+        /// Created if nonexistent.
+        ///
+        /// Existing destination directories are merged;
+        /// other existing destination paths are not overwritten.
+        dst_dir: String,
+    },
+
+    /// Check that destination files share extents with their reflink directory files.
+    ///
+    /// The physical extents of each file, as reported by FIEMAP,
+    /// are compared with those of the reflink directory file named by its hash.
+    /// Files that do not fully share extents, or have no reflink directory file,
+    /// are listed, followed by the shared and unshared byte totals.
+    /// Files stored inline, like their reflink directory files,
+    /// cannot share extents; they are counted separately and never relinked.
+    /// Directories are recursed; symlinks and other file types are ignored.
+    CheckSharing{
+        #[ structopt( long ) ]
+        /// Replace files that do not fully share extents
+        /// with reflinks of their reflink directory files.
+        ///
+        /// Each reflink is made to a temporary file next to the destination file,
+        /// whose ownership, permissions, times, and extended attributes are set,
+        /// and whose content is compared with the destination file
+        /// right before it is renamed over it, giving it a new inode number.
+        /// Files modified since they were hashed, files with multiple hardlinks,
+        /// or with `chattr` flags differing from those of the reflink, are not replaced.
+        relink: bool,
+
+        #[ structopt( short, long, required = true ) ]
+        /// The reflink directory.
+        reflink_dir: String,
+
+        #[ structopt( required = true ) ]
+        /// The list of destination paths to check.
+        dst_paths: Vec< String >,
+    },
+
+    /// Report the space used by paths, accounting for shared extents.
+    ///
+    /// For each path and each directory below it, contents first,
+    /// the logical size of its files is listed,
+    /// followed by the bytes in extents used only by its files (exclusive)
+    /// and the bytes in extents shared with other files (shared),
+    /// such as reflink directory files, as reported by FIEMAP.
+    /// Files with multiple hardlinks are counted once, and as shared;
+    /// shared extents are counted for the first file found using them.
+    Du{
+        #[ structopt( long ) ]
+        /// List directories only up to the given depth below the paths.
+        max_depth: Option< usize >,
+
+        #[ structopt( required = true ) ]
+        /// The list of paths.
+        paths: Vec< String >,
+    },
+
+    /// Manage snapshots recorded in the reflink directory.
+    ///
+    /// A snapshot is a manifest of the destination directory after a copy,
+    /// recorded under the `snapshots` subdirectory of the reflink directory
+    /// by `copy --snapshot`.
+    Snapshot( SnapshotAction ),
+
+    /// Manage the reflink directory.
+    Store( StoreAction ),
+
     /// Calculate file hashes.
     Hash{
+        #[ structopt( long = "recurse" ) ]
+        /// Recurse source directories.
+        ///
+        /// If this option is set, each file in a source directory is hashed,
+        /// followed by the digest of the directory tree,
+        /// listed with a trailing slash.
+        /// The digest covers the names, file types, file contents,
+        /// and link targets of the tree, so that two trees match
+        /// if and only if their digests match.
+        recurse_dirs: bool,
+
+        #[ structopt(
+            long,
+            default_value = "blake2bp",
+            possible_values = cpdd::HashAlgorithm::VARIANTS,
+        ) ]
+        /// The hash algorithm.
+        /// Possible values:
+        ///     `blake2bp`: BLAKE2bp with a 32-byte digest,
+        ///         as used for the reflink directory,
+        ///     `blake2b`: BLAKE2b with a 64-byte digest, as computed by `b2sum`,
+        ///     `sha256`: SHA-256, as computed by `sha256sum`.
+        algorithm: cpdd::HashAlgorithm,
+
+        #[ structopt(
+            long,
+            default_value = "cpdd",
+            possible_values = cpdd::HashFormat::VARIANTS,
+        ) ]
+        /// The format of the hash lines.
+        /// Possible values:
+        ///     `cpdd`: `<hash> <path>`,
+        ///     `gnu`: `<hash>  <path>`, as written by `b2sum` and `sha256sum`,
+        ///     `bsd`: `<TAG> (<path>) = <hash>`, as written by BSD tools
+        ///         and by `--tag`.
+        format: cpdd::HashFormat,
+
+        #[ structopt( long, conflicts_with = "src-paths" ) ]
+        /// Check the hashes listed in the given file instead; `-` reads stdin.
+        ///
+        /// The listing may use any of the formats.
+        /// BSD-style lines name their algorithm;
+        /// other lines are checked using `--algorithm`.
+        /// Paths are relative to the current directory.
+        /// Directories are checked against their tree digest.
+        /// Each listed file is reported as `OK` or `FAILED`.
+        check: Option< String >,
+
         // #[ structopt( required = true ) ]
         /// The list of source paths to calculate hashes for.
         src_paths: Vec< String >,
@@ -114,177 +497,1621 @@ enum Action
 }
 
 
+#[ derive( Debug, structopt::StructOpt ) ]
+enum SnapshotAction
+{
+    /// List snapshots, oldest first.
+    List{
+        /// The reflink directory.
+        reflink_dir: String,
+    },
 
-// MAIN
+    /// Compare two snapshots.
+    ///
+    /// Each relative path is classified as in `diff`,
+    /// with snapshot A as tree A, or as `metadata-differs`.
+    Diff{
+        /// The reflink directory.
+        reflink_dir: String,
 
-fn main()
+        /// Snapshot A.
+        a_name: String,
+
+        /// Snapshot B.
+        b_name: String,
+    },
+
+    /// Restore a snapshot; see `restore`.
+    Restore{
+        #[ structopt(
+            long,
+            default_value = "reflink",
+            possible_values = cpdd::LinkMode::VARIANTS,
+        ) ]
+        /// The link mode to use for creating destination files.
+        /// Possible values: see `copy`.
+        link_mode: cpdd::LinkMode,
+
+        #[ structopt( short, long, required = true ) ]
+        /// The destination directory.
+        /// Created if nonexistent.
+        dst_dir: String,
+
+        /// The reflink directory.
+        reflink_dir: String,
+
+        /// The snapshot name.
+        name: String,
+    },
+
+    /// Remove the snapshots not kept by the retention policy.
+    ///
+    /// A snapshot is kept if any of the `--keep` options keeps it.
+    /// Days and weeks are in UTC, weeks are ISO weeks,
+    /// and snapshot times are the times the snapshots were last written.
+    /// Reflink directory files are not removed; see `gc`.
+    Expire{
+        #[ structopt( long, default_value = "0" ) ]
+        /// The number of most recent snapshots to keep.
+        keep_last: usize,
+
+        #[ structopt( long, default_value = "0" ) ]
+        /// The number of most recent days to keep the last snapshot of.
+        keep_daily: usize,
+
+        #[ structopt( long, default_value = "0" ) ]
+        /// The number of most recent weeks to keep the last snapshot of.
+        keep_weekly: usize,
+
+        #[ structopt( long ) ]
+        /// List the snapshots to remove without removing them.
+        dry_run: bool,
+
+        /// The reflink directory.
+        reflink_dir: String,
+    },
+
+    /// Remove the reflink directory files not referenced by any snapshot.
+    ///
+    /// Only use this if every copy into the reflink directory records a snapshot,
+    /// and never while copying into the reflink directory.
+    /// Nothing is removed if there are no snapshots
+    /// or if any snapshot cannot be read.
+    Gc{
+        #[ structopt( long ) ]
+        /// Confirm the removal of every unreferenced file.
+        ///
+        /// Required unless `--dry-run` is set,
+        /// since files copied without recording a snapshot are unreferenced too;
+        /// review the files listed by `--dry-run` first.
+        all_unreferenced: bool,
+
+        #[ structopt( long ) ]
+        /// List the files to remove without removing them.
+        dry_run: bool,
+
+        /// The reflink directory.
+        reflink_dir: String,
+    },
+}
+
+
+#[ derive( Debug, structopt::StructOpt ) ]
+enum StoreAction
 {
-    use structopt::StructOpt;
+    /// Report statistics of the reflink directory files.
+    ///
+    /// Lists the number and total size of the files,
+    /// a histogram of their sizes, the largest files,
+    /// and the files added per day, by the UTC day of their modification time.
+    /// If there are snapshots, the references to the files are counted as well,
+    /// each file in each snapshot being a reference.
+    /// Entries other than files with well-formed names are ignored; see `verify`.
+    Stats{
+        #[ structopt( long, default_value = "10" ) ]
+        /// The number of largest and most referenced files to list.
+        top: usize,
 
-    let cli_args = CliArgs::from_args();
+        /// The reflink directory.
+        reflink_dir: String,
+    },
 
-    let log_level =
-            match cli_args.log_level {
-                Some( 0 ) => { simplelog::LevelFilter::Off },
-                Some( 1 ) => { simplelog::LevelFilter::Error },
-                Some( 2 ) => { simplelog::LevelFilter::Warn },
-                Some( 3 ) => { simplelog::LevelFilter::Info },
-                Some( 4 ) => { simplelog::LevelFilter::Debug },
-                Some( 5 ) => { simplelog::LevelFilter::Trace },
+    /// Insert source files into the reflink directory without copying them elsewhere.
+    ///
+    /// Each file is hashed and, unless already present,
+    /// reflinked or copied into the reflink directory,
+    /// so that later copies can reflink against it.
+    /// Source directories are always recursed;
+    /// symlinks and other file types are ignored.
+    Add{
+        #[ structopt( long, default_value = "0", parse( try_from_str = parse_size ) ) ]
+        /// The free space to keep in reserve; see `copy`.
+        min_free: u64,
 
-                _ => { simplelog::LevelFilter::Info },
-            };
-    let log_config = simplelog::ConfigBuilder::new()
-            .set_target_level( simplelog::LevelFilter::Error )
-            .set_location_level( simplelog::LevelFilter::Error )
-            .set_thread_level( simplelog::LevelFilter::Error )
-            .set_time_format_str( "%FT%T%.9f%:z" )  // iso date-time, ns precision
-            .add_filter_allow_str( "cpdd" )
-            .build();
-    match &cli_args.log_path {
-        Some( log_path_ ) => {
-            rp!( simplelog::CombinedLogger::init( vec![
-                rp!( simplelog::TermLogger::new(
-                    log_level,
-                    log_config.clone(),
-                    simplelog::TerminalMode::Stderr,
-                ).ok_or( "Logger initialization failed." ) ),
-                simplelog::WriteLogger::new(
-                    log_level,
-                    log_config,
-                    rp!( std::fs::OpenOptions::new()
-                        .write( true ).create_new( true )
-                        .open( log_path_ ) ),
-                ),
-            ] ) );
+        #[ structopt( long ) ]
+        /// The manifest path; `-` writes the manifest to stdout.
+        ///
+        /// If this option is set, a manifest of the source paths is written,
+        /// with the relative paths that `copy` would place them at,
+        /// so that they can be restored with `restore`.
+        manifest: Option< String >,
 
-            log::debug!(
-                "Logger initialized: log_level: {:?}, log_path: {:?}",
-                log_level,
-                log_path_,
-            );
-        },
-        None => {
-            rp!( simplelog::TermLogger::init(
-                    log_level, log_config, simplelog::TerminalMode::Stderr ) );
+        #[ structopt( long ) ]
+        /// Replace source files with reflinks of their reflink directory files;
+        /// see `copy`.
+        in_place_link: bool,
 
-            log::debug!( "Logger initialized: log_level: {:?}", log_level );
-        },
-    };
+        #[ structopt( short, long, required = true ) ]
+        /// The reflink directory.
+        /// Created if nonexistent.
+        reflink_dir: String,
 
-    log::debug!( "{:?}", cli_args );
+        #[ structopt( required = true ) ]
+        /// The list of source paths to insert.
+        src_paths: Vec< String >,
+    },
+}
 
-    match cli_args.action {
-        Action::Copy{
-            recurse_dirs,
-            overwrite_dst,
-            skip_invalid_file_types,
-            backup_suffix,
-            reflink_dir,
-            dst_dir,
-            src_paths,
-        } => {
-            match std::fs::metadata( &reflink_dir ) {
-                Ok( metadata_ ) => {
-                    if !metadata_.is_dir() {
-                        let error_msg = format!(
-                            "Invalid reflink directory file type: \
-                                    not a directory: \
-                                path: {:?}, \
-                                type: {:?}",
-                            reflink_dir,
-                            metadata_.file_type(),
-                        );
 
-                        log::error!( "{}", error_msg );
 
-                        let error = std::io::Error::new(
-                                std::io::ErrorKind::InvalidInput, error_msg );
+// TYPES
 
-                        rp!( Err( error ) );
-                    }
-                },
-                Err( error_ ) => {
-                    match error_.kind() {
-                        std::io::ErrorKind::NotFound => {
-                            log::info!( "Reflink directory not found; creating." );
-
-                            rp!( std::fs::create_dir( &reflink_dir ) );
-                            rp!( rp!( std::fs::File::open( &reflink_dir ) )
-                                    .sync_all() );
-                        },
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+enum OutputFormat
+{
+    Text,
+    Json,
+}
 
-                        _ => { rp!( Err( error_ ) ); },
-                    }
-                },
-            }
+impl std::str::FromStr for OutputFormat
+{
+    type Err = String;
 
-            let dst_metadata = rp!( std::fs::metadata( &dst_dir ) );
-            if !dst_metadata.is_dir() {
-                let error_msg = format!(
-                    "Invalid destination directory file type: not a directory: \
-                        path: {:?}, \
-                        type: {:?}",
-                    dst_dir,
-                    dst_metadata.file_type(),
-                );
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "text" => { Ok( OutputFormat::Text ) },
+            "json" => { Ok( OutputFormat::Json ) },
 
-                log::error!( "{}", error_msg );
+            _ => { Err( format!( "Invalid output format: {:?}", s ) ) },
+        };
+    }
+}
 
-                let error = std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput, error_msg );
+#[ derive( Debug ) ]
+/// An error that ends the program with a nonzero exit code.
+enum CliError
+{
+    /// Invalid arguments or paths.
+    Usage( String ),
 
-                rp!( Err( error ) );
-            }
+    /// A failure before any changes were made.
+    Fatal( std::io::Error ),
 
-            for src_path_ in src_paths {
-                log::debug!( "Handling source path: {:?}", src_path_ );
+    /// A failure after changes were made, or for some of the source paths.
+    Partial( String ),
 
-                rp!( cpdd::cpdd(
-                    src_path_,
-                    &dst_dir,
-                    &reflink_dir,
-                    recurse_dirs,
-                    overwrite_dst,
-                    skip_invalid_file_types,
-                    &backup_suffix,
-                ) );
-            }
-        },
-        Action::Verify{ reflink_dir } => {
-            let reflink_dir_metadata = rp!( std::fs::metadata( &reflink_dir ) );
-            if !reflink_dir_metadata.is_dir() {
-                let error_msg = format!(
-                    "Invalid reflink directory file type: not a directory: \
-                        path: {:?}, \
-                        type: {:?}",
-                    reflink_dir,
-                    reflink_dir_metadata.file_type(),
-                );
+    /// The given number of files failed verification.
+    Mismatches( usize ),
 
-                log::error!( "{}", error_msg );
+    /// The given number of paths differ between the compared trees.
+    Differences( usize ),
 
-                let error = std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput, error_msg );
+    /// The given number of reflink directory entries are corrupt,
+    /// foreign, or unreadable.
+    InvalidEntries( usize ),
 
-                rp!( Err( error ) );
-            }
+    /// The given number of files do not share extents with the reflink directory.
+    Unshared( usize ),
 
-            let mismatches = rp!( cpdd::verify_reflink_dir( &reflink_dir ) );
-            if mismatches.is_empty() { println!( "No errors found." ); }
-            else { println!( "Errors found:" ); }
-            for path_ in mismatches { println!( "{}", path_.to_str().unwrap() ); }
-        },
-        Action::Hash{ src_paths } => {
-            for src_path_ in src_paths {
-                log::debug!( "Handling source path: {:?}", src_path_ );
+    /// The run was cancelled.
+    Cancelled,
+}
+
+impl CliError
+{
+    /// Converts an error from a run that has already made changes.
+    fn partial( error: std::io::Error ) -> Self
+    {
+        if cpdd::is_cancelled( &error ) { return CliError::Cancelled; }
 
-                let hash = rp!( cpdd::calc_file_hash( &src_path_ ) );
+        return CliError::Partial( format!( "copy stopped partway: {}", error ) );
+    }
 
-                log::info!( "Result: hash: {:?}, path: {:?}", hash, src_path_ );
+    fn exit_code( &self ) -> i32
+    {
+        return match self {
+            CliError::Usage( _ ) => { EXIT_USAGE },
+            CliError::Fatal( _ ) => { EXIT_FATAL },
+            CliError::Partial( _ ) => { EXIT_PARTIAL },
+            CliError::Mismatches( _ ) => { EXIT_MISMATCHES },
+            CliError::Differences( _ ) => { EXIT_MISMATCHES },
+            CliError::InvalidEntries( _ ) => { EXIT_MISMATCHES },
+            CliError::Unshared( _ ) => { EXIT_MISMATCHES },
+            CliError::Cancelled => { EXIT_CANCELLED },
+        };
+    }
+}
 
-                println!( "{} {}", hash, src_path_ );
-            }
-        },
+impl std::fmt::Display for CliError
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return match self {
+            CliError::Usage( msg_ ) => { write!( f, "error: {}", msg_ ) },
+            CliError::Fatal( error_ ) => { write!( f, "error: {}", error_ ) },
+            CliError::Partial( msg_ ) => { write!( f, "error: {}", msg_ ) },
+            CliError::Mismatches( count_ ) => {
+                write!( f, "mismatching files found: {}", count_ )
+            },
+            CliError::Differences( count_ ) => {
+                write!( f, "differing paths found: {}", count_ )
+            },
+            CliError::InvalidEntries( count_ ) => {
+                write!( f, "invalid reflink directory entries found: {}", count_ )
+            },
+            CliError::Unshared( count_ ) => {
+                write!( f, "files not sharing extents found: {}", count_ )
+            },
+            CliError::Cancelled => { write!( f, "cancelled" ) },
+        };
+    }
+}
+
+impl From< std::io::Error > for CliError
+{
+    fn from( error: std::io::Error ) -> Self
+    {
+        if cpdd::is_cancelled( &error ) { return CliError::Cancelled; }
+
+        return match error.kind() {
+            std::io::ErrorKind::InvalidInput => {
+                CliError::Usage( error.to_string() )
+            },
+
+            _ => { CliError::Fatal( error ) },
+        };
+    }
+}
+
+/// Writes events to stdout and progress to stderr, depending on the options.
+struct CliObserver
+{
+    output: OutputFormat,
+    progress: Option< ProgressDisplay >,
+
+    /// The algorithm and format for writing `hashed` events as text.
+    hash_listing: Option< ( cpdd::HashAlgorithm, cpdd::HashFormat ) >,
+}
+
+impl cpdd::CopyObserver for CliObserver
+{
+    fn on_event( &mut self, event: &cpdd::Event )
+    {
+        match self.output {
+            OutputFormat::Json => { println!( "{}", event.to_json() ); },
+            OutputFormat::Text => {
+                if let (
+                    cpdd::EventKind::Hashed,
+                    Some( ( algorithm_, format_ ) ),
+                    Some( hash_ ),
+                    Some( path_ ),
+                ) = ( event.kind, self.hash_listing, &event.hash, &event.src_path ) {
+                    println!( "{}", cpdd::format_hash_line(
+                            hash_, path_, algorithm_, format_ ) );
+                }
+            },
+        }
+    }
+
+    fn on_progress( &mut self, progress: &cpdd::Progress )
+    {
+        if let Some( display_ ) = &mut self.progress { display_.update( progress ); }
+    }
+}
+
+/// Displays progress on a single, redrawn stderr line.
+struct ProgressDisplay
+{
+    last_draw_time: Option< std::time::Instant >,
+    last_progress: Option< cpdd::Progress >,
+}
+
+impl ProgressDisplay
+{
+    const DRAW_INTERVAL: std::time::Duration = std::time::Duration::from_millis( 100 );
+
+    fn new() -> Self
+    {
+        return Self{ last_draw_time: None, last_progress: None };
+    }
+
+    fn update( &mut self, progress: &cpdd::Progress )
+    {
+        self.last_progress = Some( *progress );
+        if let Some( last_draw_time_ ) = self.last_draw_time {
+            if last_draw_time_.elapsed() < Self::DRAW_INTERVAL { return; }
+        }
+
+        self.draw( progress );
+    }
+
+    /// Draws the last progress and ends the line.
+    fn finish( &mut self )
+    {
+        if let Some( progress_ ) = self.last_progress {
+            self.draw( &progress_ );
+            eprintln!();
+        }
+    }
+
+    fn draw( &mut self, progress: &cpdd::Progress )
+    {
+        use std::io::Write;
+
+        let mut line = match progress.totals {
+            Some( totals_ ) => {
+                format!(
+                    "files: {}/{}, bytes: {}/{}",
+                    progress.files,
+                    totals_.files,
+                    format_size( progress.bytes + progress.current_bytes ),
+                    format_size( totals_.bytes ),
+                )
+            },
+            None => {
+                format!(
+                    "files: {}, bytes: {}",
+                    progress.files,
+                    format_size( progress.bytes + progress.current_bytes ),
+                )
+            },
+        };
+        if let Some( dedup_ratio_ ) = progress.dedup_ratio() {
+            line += &format!( ", dedup: {:.2}x", dedup_ratio_ );
+        }
+        line += &format!(
+                ", rate: {}/s", format_size( progress.throughput() as u64 ) );
+        if let Some( eta_ ) = progress.eta() {
+            let eta_secs = eta_.as_secs();
+            line += &format!(
+                ", eta: {}:{:02}:{:02}",
+                eta_secs / 3600,
+                eta_secs / 60 % 60,
+                eta_secs % 60,
+            );
+        }
+
+        let mut stderr = std::io::stderr();
+        let _ = write!( stderr, "\r\x1b[K{}", line );
+        let _ = stderr.flush();
+        self.last_draw_time = Some( std::time::Instant::now() );
+    }
+}
+
+
+
+// FUNCTIONS
+
+fn print_summary(
+    output: OutputFormat,
+    stats: &cpdd::Stats,
+    start_time: std::time::Instant,
+)
+{
+    if output != OutputFormat::Json { return; }
+
+    let mut summary = stats.to_json();
+    summary[ "duration" ] = start_time.elapsed().as_secs_f64().into();
+    println!( "{}", summary );
+}
+
+fn format_size( bytes: u64 ) -> String
+{
+    const UNITS: [ &str; 5 ] = [ "B", "KiB", "MiB", "GiB", "TiB" ];
+
+    let mut size = bytes as f64;
+    let mut unit_index = 0;
+    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit_index += 1;
+    }
+
+    if unit_index == 0 { return format!( "{} B", bytes ); }
+
+    return format!( "{:.1} {}", size, UNITS[ unit_index ] );
+}
+
+fn parse_size( s: &str ) -> Result< u64, String >
+{
+    let ( number, shift ) = match s.char_indices().last() {
+        Some( ( index_, 'K' ) ) => { ( &s[ ..index_ ], 10 ) },
+        Some( ( index_, 'M' ) ) => { ( &s[ ..index_ ], 20 ) },
+        Some( ( index_, 'G' ) ) => { ( &s[ ..index_ ], 30 ) },
+        Some( ( index_, 'T' ) ) => { ( &s[ ..index_ ], 40 ) },
+
+        _ => { ( s, 0 ) },
+    };
+
+    return number.parse::< u64 >().ok()
+        .and_then( |number_| number_.checked_mul( 1 << shift ) )
+        .ok_or_else( || format!( "Invalid size: {:?}", s ) );
+}
+
+
+fn parse_duration( s: &str ) -> Result< std::time::Duration, String >
+{
+    let ( number, factor ) = match s.char_indices().last() {
+        Some( ( index_, 's' ) ) => { ( &s[ ..index_ ], 1 ) },
+        Some( ( index_, 'm' ) ) => { ( &s[ ..index_ ], 60 ) },
+        Some( ( index_, 'h' ) ) => { ( &s[ ..index_ ], 60 * 60 ) },
+        Some( ( index_, 'd' ) ) => { ( &s[ ..index_ ], 24 * 60 * 60 ) },
+        Some( ( index_, 'w' ) ) => { ( &s[ ..index_ ], 7 * 24 * 60 * 60 ) },
+
+        _ => { ( s, 1 ) },
+    };
+
+    return number.parse::< u64 >().ok()
+        .and_then( |number_| number_.checked_mul( factor ) )
+        .map( std::time::Duration::from_secs )
+        .ok_or_else( || format!( "Invalid duration: {:?}", s ) );
+}
+
+/// Calculates the file hash and size, or the directory tree digest,
+/// stopping if the session is cancelled.
+fn calc_digest< P >(
+    path: P,
+    algorithm: cpdd::HashAlgorithm,
+    session: &cpdd::Session,
+) -> std::io::Result< ( String, Option< u64 > ) >
+where
+    P: AsRef< std::path::Path >,
+{
+    let path = path.as_ref();
+
+    if path.is_dir() {
+        // The files of the tree are not reported individually.
+        let mut observer = ();
+        let mut tree_session = cpdd::Session::new( &mut observer );
+        tree_session.cancel_token = session.cancel_token.clone();
+        let digest = cpdd::calc_tree_digest( path, algorithm, &mut tree_session )?;
+
+        return Ok( ( digest, None ) );
+    }
+
+    let hash = cpdd::calc_file_digest( path, algorithm, |_| {
+        if session.cancel_token.is_cancelled() {
+            return Err( std::io::Error::other( cpdd::Cancelled ) );
+        }
+
+        return Ok( () );
+    } )?;
+    let size = std::fs::metadata( path )?.len();
+
+    return Ok( ( hash, Some( size ) ) );
+}
+
+/// Checks the hashes listed in the given file, reporting each as OK or FAILED.
+fn check_hashes(
+    listing_path: &str,
+    algorithm: cpdd::HashAlgorithm,
+    output: OutputFormat,
+    session: &mut cpdd::Session,
+) -> Result< (), CliError >
+{
+    use std::io::BufRead;
+
+    let reader: Box< dyn BufRead > =
+            if listing_path == "-" { Box::new( std::io::stdin().lock() ) }
+            else {
+                Box::new( std::io::BufReader::new(
+                        std::fs::File::open( listing_path )? ) )
+            };
+
+    let mut line_count = 0;
+    let mut malformed_count = 0;
+    let mut mismatch_count = 0;
+    let mut failure_count = 0;
+    for ( index_, line_res_ ) in reader.split( b'\n' ).enumerate() {
+        let line_bytes = line_res_?;
+        if line_bytes.is_empty() { continue; }
+
+        let hash_line = String::from_utf8( line_bytes ).ok()
+            .and_then( |line_| cpdd::parse_hash_line( &line_ ) )
+            .filter( |hash_line_| {
+                let algorithm = hash_line_.algorithm.unwrap_or( algorithm );
+
+                hash_line_.hash.len() == algorithm.hex_length()
+            } );
+        let hash_line = match hash_line {
+            Some( hash_line_ ) => { hash_line_ },
+            None => {
+                log::warn!(
+                    "Improperly formatted hash line: {}: line: {}",
+                    listing_path,
+                    index_ + 1,
+                );
+
+                malformed_count += 1;
+
+                continue;
+            },
+        };
+        line_count += 1;
+
+        let path = &hash_line.path;
+        let start_time = std::time::Instant::now();
+        let algorithm = hash_line.algorithm.unwrap_or( algorithm );
+        let ( hash, size ) = match calc_digest( path, algorithm, session ) {
+            Ok( value_ ) => { value_ },
+            Err( error_ ) => {
+                if cpdd::is_cancelled( &error_ ) { return Err( CliError::Cancelled ); }
+
+                log::error!(
+                    "Hashing failed: path: {:?}, error: {}", path, error_ );
+
+                if output == OutputFormat::Text {
+                    println!( "{}: FAILED open or read", path.display() );
+                }
+
+                session.emit( cpdd::Event{
+                    src_path: Some( path.clone() ),
+                    message: Some( error_.to_string() ),
+                    ..cpdd::Event::new( cpdd::EventKind::Error )
+                } );
+                failure_count += 1;
+
+                continue;
+            },
+        };
+
+        let event_kind =
+                if hash == hash_line.hash { cpdd::EventKind::Verified }
+                else {
+                    log::warn!(
+                        "Hash mismatch: path: {:?}, expected: {:?}, hash: {:?}",
+                        path,
+                        hash_line.hash,
+                        hash,
+                    );
+
+                    mismatch_count += 1;
+
+                    cpdd::EventKind::Mismatch
+                };
+
+        if output == OutputFormat::Text {
+            let status =
+                    if event_kind == cpdd::EventKind::Verified { "OK" }
+                    else { "FAILED" };
+            println!( "{}: {}", path.display(), status );
+        }
+
+        session.emit( cpdd::Event{
+            src_path: Some( path.clone() ),
+            hash: Some( hash ),
+            size,
+            duration: Some( start_time.elapsed() ),
+            ..cpdd::Event::new( event_kind )
+        } );
+    }
+
+    if malformed_count > 0 {
+        log::warn!( "Improperly formatted hash lines: {}", malformed_count );
+    }
+
+    if line_count == 0 {
+        return Err( CliError::Usage( format!(
+                "No properly formatted hash lines found: {:?}", listing_path ) ) );
+    }
+    if mismatch_count > 0 { return Err( CliError::Mismatches( mismatch_count ) ); }
+    if failure_count > 0 {
+        return Err( CliError::Partial( format!(
+            "failed to read {} of {} listed files",
+            failure_count,
+            line_count,
+        ) ) );
+    }
+
+    return Ok( () );
+}
+
+fn init_logger( log_level: Option< u32 >, log_path: &Option< String > )
+    -> std::io::Result< () >
+{
+    let log_level =
+            match log_level {
+                Some( 0 ) => { simplelog::LevelFilter::Off },
+                Some( 1 ) => { simplelog::LevelFilter::Error },
+                Some( 2 ) => { simplelog::LevelFilter::Warn },
+                Some( 3 ) => { simplelog::LevelFilter::Info },
+                Some( 4 ) => { simplelog::LevelFilter::Debug },
+                Some( 5 ) => { simplelog::LevelFilter::Trace },
+
+                _ => { simplelog::LevelFilter::Info },
+            };
+    let log_config = simplelog::ConfigBuilder::new()
+            .set_target_level( simplelog::LevelFilter::Error )
+            .set_location_level( simplelog::LevelFilter::Error )
+            .set_thread_level( simplelog::LevelFilter::Error )
+            .set_time_format_str( "%FT%T%.9f%:z" )  // iso date-time, ns precision
+            .add_filter_allow_str( "cpdd" )
+            .build();
+    match log_path {
+        Some( log_path_ ) => {
+            let term_logger = simplelog::TermLogger::new(
+                log_level,
+                log_config.clone(),
+                simplelog::TerminalMode::Stderr,
+            ).ok_or_else( || {
+                std::io::Error::other( "Logger initialization failed." )
+            } )?;
+            let write_logger = simplelog::WriteLogger::new(
+                log_level,
+                log_config,
+                std::fs::OpenOptions::new()
+                    .write( true ).create_new( true )
+                    .open( log_path_ )?,
+            );
+            simplelog::CombinedLogger::init( vec![ term_logger, write_logger ] )
+                    .map_err( std::io::Error::other )?;
+
+            log::debug!(
+                "Logger initialized: log_level: {:?}, log_path: {:?}",
+                log_level,
+                log_path_,
+            );
+        },
+        None => {
+            simplelog::TermLogger::init(
+                    log_level, log_config, simplelog::TerminalMode::Stderr )
+                .map_err( std::io::Error::other )?;
+
+            log::debug!( "Logger initialized: log_level: {:?}", log_level );
+        },
+    };
+
+    return Ok( () );
+}
+
+fn print_verify_report( report: &cpdd::VerifyReport )
+{
+    let groups = [
+        ( "Hash mismatches", &report.mismatches ),
+        ( "Zero-length files", &report.truncated ),
+        ( "Unexpected names", &report.unexpected_names ),
+        ( "Unexpected file types", &report.unexpected_types ),
+        ( "Unreadable files", &report.unreadable ),
+    ];
+
+    println!( "Valid files: {}", report.valid );
+    if groups.iter().all( |( _, paths_ )| paths_.is_empty() ) {
+        println!( "No errors found." );
+
+        return;
+    }
+
+    for ( title_, paths_ ) in &groups {
+        if paths_.is_empty() { continue; }
+
+        println!( "{} ({}):", title_, paths_.len() );
+        for path_ in paths_.iter() {
+            println!( "{}", path_.display() );
+        }
+    }
+}
+
+fn print_store_stats( stats: &cpdd::StoreStats )
+{
+    println!( "Entries: {}", stats.total.entries );
+    println!( "Total size: {}", format_size( stats.total.bytes ) );
+
+    println!( "Sizes:" );
+    let mut min_bytes = 0;
+    for ( max_bytes_, count_ ) in &stats.size_histogram {
+        let label = match max_bytes_ {
+            Some( 1 ) => { "0 B".to_owned() },
+            Some( max_bytes_ ) => {
+                format!( "{} - {}", format_size( min_bytes ), format_size( *max_bytes_ ) )
+            },
+            None => { format!( ">= {}", format_size( min_bytes ) ) },
+        };
+        println!(
+            "  {:<22} {:>10} {:>10}",
+            label,
+            count_.entries,
+            format_size( count_.bytes ),
+        );
+        min_bytes = max_bytes_.unwrap_or( min_bytes );
+    }
+
+    println!( "Largest entries:" );
+    for ( hash_, size_ ) in &stats.largest {
+        println!( "  {:>10}  {}", format_size( *size_ ), hash_ );
+    }
+
+    println!( "Entries added per day:" );
+    for ( day_, count_ ) in &stats.daily {
+        println!( "  {}  {:>10} {:>10}", day_, count_.entries, format_size( count_.bytes ) );
+    }
+
+    let references = match &stats.references {
+        Some( references_ ) => { references_ },
+        None => {
+            println!( "References: no snapshots found" );
+
+            return;
+        },
+    };
+
+    println!(
+        "References: {} in {} snapshots",
+        references.references,
+        references.snapshots,
+    );
+    println!(
+        "  Unreferenced entries: {} ({})",
+        references.unreferenced.entries,
+        format_size( references.unreferenced.bytes ),
+    );
+    println!( "  Referenced hashes without entries: {}", references.missing );
+    println!( "  Entries per reference count:" );
+    for ( count_, entries_ ) in &references.counts {
+        println!( "    {:>10} {:>10}", count_, entries_ );
+    }
+    println!( "  Most referenced entries:" );
+    for ( hash_, count_ ) in &references.most_referenced {
+        println!( "    {:>10}  {}", count_, hash_ );
+    }
+}
+
+fn print_sharing_entry( entry: &cpdd::SharingEntry )
+{
+    if entry.relinked {
+        println!( "Relinked: {}", entry.path.display() );
+    }
+
+    match entry.shared_bytes {
+        Some( _ ) if entry.inline => {},
+        Some( shared_bytes_ ) if shared_bytes_ < entry.size => {
+            println!(
+                "Not fully shared: {} of {}: {}",
+                format_size( shared_bytes_ ),
+                format_size( entry.size ),
+                entry.path.display(),
+            );
+        },
+        None => { println!( "Not stored: {}", entry.path.display() ); },
+
+        _ => {},
+    }
+}
+
+fn print_usage_line( usage: &cpdd::SpaceUsage, name: &str )
+{
+    println!(
+        "{:>10} {:>10} {:>10}  {}",
+        format_size( usage.logical_bytes ),
+        format_size( usage.exclusive_bytes ),
+        format_size( usage.shared_bytes ),
+        name,
+    );
+}
+
+fn print_diff_entry( entry: &cpdd::DiffEntry, output: OutputFormat )
+{
+    match output {
+        OutputFormat::Text => {
+            println!( "{:<22} {}", entry.kind.as_str(), entry.path.display() );
+        },
+        OutputFormat::Json => { println!( "{}", entry.to_json() ); },
+    }
+}
+
+/// Checks that the reflink directory is a directory, creating it if nonexistent.
+fn ensure_reflink_dir( reflink_dir: &str ) -> Result< (), CliError >
+{
+    match std::fs::metadata( reflink_dir ) {
+        Ok( metadata_ ) => {
+            if !metadata_.is_dir() {
+                let error_msg = format!(
+                    "Invalid reflink directory file type: not a directory: \
+                        path: {:?}, \
+                        type: {:?}",
+                    reflink_dir,
+                    metadata_.file_type(),
+                );
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg ) );
+            }
+        },
+        Err( error_ ) => {
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => {
+                    log::info!( "Reflink directory not found; creating." );
+
+                    std::fs::create_dir( reflink_dir )?;
+                    std::fs::File::open( reflink_dir )?.sync_all()?;
+                },
+
+                _ => { return Err( error_.into() ); },
+            }
+        },
+    }
+
+    return Ok( () );
+}
+
+/// Checks that the source paths can be reflinked from the reflink directory
+/// by a test reflink into source directories, or the parent directories of other source paths,
+/// so that other subvolumes of the same Btrfs file system are accepted.
+fn check_in_place_link( reflink_dir: &str, src_paths: &[ String ] ) -> Result< (), CliError >
+{
+    for src_path_ in src_paths {
+        let src_path = std::path::Path::new( src_path_ );
+        let src_dir =
+                if src_path.symlink_metadata()?.is_dir() { src_path }
+                else {
+                    src_path.parent()
+                        .filter( |parent_path_| !parent_path_.as_os_str().is_empty() )
+                        .unwrap_or( std::path::Path::new( "." ) )
+                };
+        cpdd::preflight_check( reflink_dir, src_dir )?;
+    }
+
+    return Ok( () );
+}
+
+/// Fails if replacing any source file with a reflink failed;
+/// other errors stop the copy.
+fn check_relink_failures( failure_count: u64 ) -> Result< (), CliError >
+{
+    if failure_count > 0 {
+        return Err( CliError::Partial( format!(
+            "failed to replace {} source files with reflinks",
+            failure_count,
+        ) ) );
+    }
+
+    return Ok( () );
+}
+
+/// Restores the manifest entries under the destination directory,
+/// creating it if nonexistent.
+fn restore_tree(
+    entries: &[ cpdd::ManifestEntry ],
+    link_mode: cpdd::LinkMode,
+    reflink_dir: &str,
+    dst_dir: &str,
+    session: &mut cpdd::Session,
+) -> Result< (), CliError >
+{
+    if let Err( error_ ) = std::fs::symlink_metadata( dst_dir ) {
+        match error_.kind() {
+            std::io::ErrorKind::NotFound => {
+                log::info!( "Destination directory not found; creating." );
+
+                std::fs::create_dir( dst_dir )?;
+                std::fs::File::open( dst_dir )?.sync_all()?;
+            },
+
+            _ => { return Err( error_.into() ); },
+        }
+    }
+
+    let link_mode = match link_mode {
+        cpdd::LinkMode::Reflink => {
+            cpdd::preflight_check( reflink_dir, dst_dir )?;
+
+            link_mode
+        },
+        cpdd::LinkMode::Auto => { cpdd::detect_link_mode( reflink_dir, dst_dir )? },
+
+        _ => { link_mode },
+    };
+
+    let failure_count = cpdd::restore( entries, dst_dir, reflink_dir, link_mode, session )
+        .map_err( CliError::partial )?;
+    if failure_count > 0 {
+        return Err( CliError::Partial( format!(
+            "failed to restore {} of {} paths",
+            failure_count,
+            entries.len(),
+        ) ) );
+    }
+
+    return Ok( () );
+}
+
+fn run_snapshot(
+    action: SnapshotAction,
+    output: OutputFormat,
+    session: &mut cpdd::Session,
+) -> Result< (), CliError >
+{
+    match action {
+        SnapshotAction::List{ reflink_dir } => {
+            for snapshot_ in cpdd::list_snapshots( &reflink_dir )? {
+                match output {
+                    OutputFormat::Text => {
+                        let time = chrono::DateTime::< chrono::Utc >::from( snapshot_.time );
+
+                        println!(
+                            "{}  {}",
+                            time.format( "%Y-%m-%dT%H:%M:%SZ" ),
+                            snapshot_.name,
+                        );
+                    },
+                    OutputFormat::Json => { println!( "{}", snapshot_.to_json() ); },
+                }
+            }
+        },
+        SnapshotAction::Diff{ reflink_dir, a_name, b_name } => {
+            let a_entries =
+                    cpdd::read_manifest( cpdd::snapshot_path( &reflink_dir, &a_name )? )?;
+            let b_entries =
+                    cpdd::read_manifest( cpdd::snapshot_path( &reflink_dir, &b_name )? )?;
+
+            let mut difference_count = 0;
+            cpdd::diff_manifests( &a_entries, &b_entries, |entry_| {
+                if entry_.kind != cpdd::DiffKind::Identical { difference_count += 1; }
+
+                print_diff_entry( entry_, output );
+            } );
+
+            if difference_count > 0 {
+                return Err( CliError::Differences( difference_count ) );
+            }
+        },
+        SnapshotAction::Restore{ link_mode, dst_dir, reflink_dir, name } => {
+            let entries =
+                    cpdd::read_manifest( cpdd::snapshot_path( &reflink_dir, &name )? )?;
+            restore_tree( &entries, link_mode, &reflink_dir, &dst_dir, session )?;
+        },
+        SnapshotAction::Expire{
+            keep_last,
+            keep_daily,
+            keep_weekly,
+            dry_run,
+            reflink_dir,
+        } => {
+            if keep_last == 0 && keep_daily == 0 && keep_weekly == 0 {
+                let error_msg = "Invalid retention policy: no snapshots kept; \
+                        set `--keep-last`, `--keep-daily`, or `--keep-weekly`";
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg.to_owned() ) );
+            }
+
+            let policy = cpdd::RetentionPolicy{ keep_last, keep_daily, keep_weekly };
+            let expired = cpdd::expire_snapshots( &reflink_dir, &policy, dry_run, session )?;
+            if output == OutputFormat::Text {
+                for snapshot_ in &expired {
+                    if dry_run { println!( "Would remove: {}", snapshot_.name ); }
+                    else { println!( "Removed: {}", snapshot_.name ); }
+                }
+            }
+        },
+        SnapshotAction::Gc{ all_unreferenced, dry_run, reflink_dir } => {
+            if !dry_run && !all_unreferenced {
+                let error_msg = "Removal not confirmed: \
+                        set `--all-unreferenced`, or list the files with `--dry-run`";
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg.to_owned() ) );
+            }
+
+            let removed = cpdd::collect_garbage(
+                    &reflink_dir, dry_run, all_unreferenced, session )?;
+            if output == OutputFormat::Text {
+                for path_ in &removed {
+                    if dry_run { println!( "Would remove: {}", path_.display() ); }
+                    else { println!( "Removed: {}", path_.display() ); }
+                }
+            }
+        },
+    }
+
+    return Ok( () );
+}
+
+fn run_store(
+    action: StoreAction,
+    output: OutputFormat,
+    session: &mut cpdd::Session,
+) -> Result< (), CliError >
+{
+    match action {
+        StoreAction::Stats{ top, reflink_dir } => {
+            let stats = cpdd::calc_store_stats( &reflink_dir, top, session )?;
+            match output {
+                OutputFormat::Text => { print_store_stats( &stats ); },
+                OutputFormat::Json => { println!( "{}", stats.to_json() ); },
+            }
+        },
+        StoreAction::Add{ min_free, manifest, in_place_link, reflink_dir, src_paths } => {
+            ensure_reflink_dir( &reflink_dir )?;
+            if in_place_link { check_in_place_link( &reflink_dir, &src_paths )?; }
+
+            session.manifest = match manifest.as_deref() {
+                Some( "-" ) => {
+                    if output == OutputFormat::Json {
+                        let error_msg =
+                                "The JSON output format requires a manifest path";
+
+                        log::error!( "{}", error_msg );
+
+                        return Err( CliError::Usage( error_msg.to_owned() ) );
+                    }
+
+                    Some( cpdd::ManifestWriter::new(
+                            Box::new( std::io::LineWriter::new( std::io::stdout() ) ), "" ) )
+                },
+                Some( manifest_path_ ) => {
+                    Some( cpdd::ManifestWriter::create( manifest_path_, "" )? )
+                },
+                None => { None },
+            };
+
+            let options = cpdd::CpddOptions{
+                min_free_bytes: min_free,
+                in_place_link,
+                ..cpdd::CpddOptions::default()
+            };
+            let error_count = session.stats.errors;
+            let mut add_res = Ok( () );
+            for src_path_ in src_paths {
+                log::debug!( "Handling source path: {:?}", src_path_ );
+
+                add_res = cpdd::store_add( src_path_, &reflink_dir, &options, session )
+                    .map_err( CliError::partial );
+                if add_res.is_err() { break; }
+            }
+
+            let finish_res = session.manifest.take().map( |manifest_| manifest_.finish() );
+            add_res?;
+            finish_res.transpose()?;
+            check_relink_failures( session.stats.errors - error_count )?;
+        },
+    }
+
+    return Ok( () );
+}
+
+fn run( action: Action, output: OutputFormat, session: &mut cpdd::Session )
+    -> Result< (), CliError >
+{
+    for signal_ in &[ signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM ] {
+        // The first signal cancels the run, a second one terminates immediately.
+        signal_hook::flag::register_conditional_shutdown(
+                *signal_, EXIT_CANCELLED, session.cancel_token.flag() )?;
+        signal_hook::flag::register( *signal_, session.cancel_token.flag() )?;
+    }
+
+    match action {
+        Action::Copy{
+            recurse_dirs,
+            overwrite_dst,
+            on_conflict,
+            conflict_log,
+            link_mode,
+            fallback_mode,
+            check_space,
+            prescan,
+            min_free,
+            manifest,
+            snapshot,
+            in_place_link,
+            skip_invalid_file_types,
+            backup_style,
+            backup_suffix,
+            reflink_dir,
+            dst_dir,
+            src_paths,
+        } => {
+            if backup_suffix.is_empty() {
+                let error_msg = "Invalid backup suffix: null string";
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg.to_owned() ) );
+            }
+
+            ensure_reflink_dir( &reflink_dir )?;
+
+            let dst_metadata = std::fs::metadata( &dst_dir )?;
+            if !dst_metadata.is_dir() {
+                let error_msg = format!(
+                    "Invalid destination directory file type: not a directory: \
+                        path: {:?}, \
+                        type: {:?}",
+                    dst_dir,
+                    dst_metadata.file_type(),
+                );
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg ) );
+            }
+
+            let link_mode = match link_mode {
+                cpdd::LinkMode::Reflink => {
+                    match cpdd::preflight_check( &reflink_dir, &dst_dir ) {
+                        Ok( () ) => { cpdd::LinkMode::Reflink },
+                        Err( error_ ) => {
+                            match fallback_mode {
+                                Some( fallback_mode_ ) => {
+                                    log::warn!(
+                                        "Pre-flight check failed; \
+                                                falling back to link mode: {:?}",
+                                        fallback_mode_,
+                                    );
+
+                                    fallback_mode_
+                                },
+                                None => { return Err( error_.into() ); },
+                            }
+                        },
+                    }
+                },
+                cpdd::LinkMode::Auto => {
+                    cpdd::detect_link_mode( &reflink_dir, &dst_dir )?
+                },
+
+                _ => { link_mode },
+            };
+
+            let on_conflict =
+                    if overwrite_dst { cpdd::ConflictPolicy::Overwrite }
+                    else { on_conflict.unwrap_or( cpdd::ConflictPolicy::Rename ) };
+            let options = cpdd::CpddOptions{
+                recurse_dirs,
+                skip_invalid_file_types,
+                on_conflict,
+                backup_style,
+                backup_suffix,
+                conflict_log_path: conflict_log.map( std::path::PathBuf::from ),
+                link_mode,
+                min_free_bytes: min_free,
+                in_place_link,
+            };
+
+            cpdd::check_paths( &src_paths, &dst_dir, &reflink_dir, recurse_dirs )?;
+            if in_place_link { check_in_place_link( &reflink_dir, &src_paths )?; }
+
+            if check_space {
+                let plan = cpdd::plan_copy(
+                        &src_paths, &dst_dir, &reflink_dir, &options )?;
+                cpdd::check_free_space( &plan, &dst_dir, &reflink_dir, min_free )?;
+                session.totals = Some( cpdd::Totals{
+                    files: plan.file_count,
+                    bytes: plan.total_bytes,
+                } );
+            }
+            else if prescan {
+                session.totals = Some( cpdd::scan_totals(
+                        &src_paths, &dst_dir, &reflink_dir, &options )? );
+            }
+
+            let manifest_path = match ( &manifest, &snapshot ) {
+                ( Some( manifest_path_ ), _ ) => {
+                    session.manifest =
+                            Some( cpdd::ManifestWriter::create( manifest_path_, &dst_dir )? );
+
+                    Some( std::path::PathBuf::from( manifest_path_ ) )
+                },
+                ( None, Some( snapshot_name_ ) ) => {
+                    session.manifest = Some( cpdd::create_snapshot(
+                            &reflink_dir, snapshot_name_, &dst_dir )? );
+
+                    Some( cpdd::snapshot_path( &reflink_dir, snapshot_name_ )? )
+                },
+                ( None, None ) => { None },
+            };
+
+            let error_count = session.stats.errors;
+            let mut copy_res = Ok( () );
+            for src_path_ in src_paths {
+                log::debug!( "Handling source path: {:?}", src_path_ );
+
+                copy_res =
+                        cpdd::cpdd( src_path_, &dst_dir, &reflink_dir, &options, session )
+                            .map_err( CliError::partial );
+                if copy_res.is_err() { break; }
+            }
+
+            // The manifest lists the whole destination directory,
+            // reusing the hashes of the files placed by the copy.
+            if let ( Some( mut manifest_ ), Some( manifest_path_ ) ) =
+                    ( session.manifest.take(), &manifest_path ) {
+                let write_res =
+                        if copy_res.is_ok() {
+                            log::info!( "Writing manifest: {:?}", manifest_path_ );
+
+                            cpdd::write_manifest( &dst_dir, &mut manifest_, session )
+                                .and_then( |()| manifest_.finish() )
+                        }
+                        else { Ok( () ) };
+                if copy_res.is_err() || write_res.is_err() {
+                    // An incomplete manifest would list paths as missing.
+                    log::warn!( "Removing incomplete manifest: {:?}", manifest_path_ );
+
+                    std::fs::remove_file( manifest_path_ ).ok();
+                }
+                write_res.map_err( CliError::partial )?;
+            }
+            copy_res?;
+            check_relink_failures( session.stats.errors - error_count )?;
+        },
+        Action::Diff{ a_dir, b_dir } => {
+            let mut difference_count = 0;
+            cpdd::diff_trees( &a_dir, &b_dir, session, |entry_| {
+                if entry_.kind != cpdd::DiffKind::Identical { difference_count += 1; }
+
+                print_diff_entry( entry_, output );
+            } )?;
+
+            if difference_count > 0 {
+                return Err( CliError::Differences( difference_count ) );
+            }
+        },
+        Action::Verify{
+            jobs,
+            checkpoint,
+            since,
+            quarantine,
+            repair,
+            from,
+            clean_foreign,
+            reflink_dir,
+        } => {
+            let reflink_dir_metadata = std::fs::metadata( &reflink_dir )?;
+            if !reflink_dir_metadata.is_dir() {
+                let error_msg = format!(
+                    "Invalid reflink directory file type: not a directory: \
+                        path: {:?}, \
+                        type: {:?}",
+                    reflink_dir,
+                    reflink_dir_metadata.file_type(),
+                );
+
+                log::error!( "{}", error_msg );
+
+                return Err( CliError::Usage( error_msg ) );
+            }
+
+            let options = cpdd::VerifyOptions{
+                jobs,
+                checkpoint_path: checkpoint.map( std::path::PathBuf::from ),
+                since,
+            };
+            let report = cpdd::verify_reflink_dir_with( &reflink_dir, &options, session )?;
+            if output == OutputFormat::Text { print_verify_report( &report ); }
+
+            let corrupt = report.corrupt().collect::< Vec< _ > >();
+            if quarantine {
+                for path_ in &corrupt {
+                    let quarantine_path =
+                            cpdd::quarantine_reflink_file( &reflink_dir, path_, session )?;
+                    if output == OutputFormat::Text {
+                        println!(
+                            "Quarantined: {} -> {}",
+                            path_.display(),
+                            quarantine_path.display(),
+                        );
+                    }
+                }
+            }
+
+            let mut unrepaired_count = corrupt.len();
+            if repair && !corrupt.is_empty() {
+                let hashes = corrupt.iter()
+                    .filter_map( |path_| path_.file_name() )
+                    .map( |name_| name_.to_string_lossy().into_owned() )
+                    .collect::< Vec< _ > >();
+                let repaired =
+                        cpdd::repair_reflink_files( &reflink_dir, &hashes, &from, session )?;
+                if output == OutputFormat::Text {
+                    for hash_ in &hashes {
+                        if repaired.contains( hash_ ) { println!( "Repaired: {}", hash_ ); }
+                        else { println!( "Not repaired: {}", hash_ ); }
+                    }
+                }
+                unrepaired_count -= repaired.len();
+            }
+
+            let mut foreign_count = report.foreign().count();
+            if clean_foreign && foreign_count > 0 {
+                let removed = cpdd::clean_foreign_entries( &report, session )?;
+                if output == OutputFormat::Text {
+                    for path_ in &removed {
+                        println!( "Removed: {}", path_.display() );
+                    }
+                }
+                foreign_count -= removed.len();
+            }
+
+            let invalid_count = unrepaired_count + foreign_count + report.unreadable.len();
+            if invalid_count > 0 {
+                return Err( CliError::InvalidEntries( invalid_count ) );
+            }
+        },
+        Action::Manifest{ manifest, dir } => {
+            let mut writer = match &manifest {
+                Some( manifest_path_ ) => {
+                    cpdd::ManifestWriter::create( manifest_path_, &dir )?
+                },
+                None => {
+                    if output == OutputFormat::Json {
+                        let error_msg =
+                                "The JSON output format requires a manifest path";
+
+                        log::error!( "{}", error_msg );
+
+                        return Err( CliError::Usage( error_msg.to_owned() ) );
+                    }
+
+                    cpdd::ManifestWriter::new(
+                            Box::new( std::io::LineWriter::new( std::io::stdout() ) ), &dir )
+                },
+            };
+
+            let write_res = cpdd::write_manifest( &dir, &mut writer, session );
+            let finish_res = writer.finish();
+            write_res?;
+            finish_res?;
+        },
+        Action::VerifyTree{ manifest, dir } => {
+            let entries = cpdd::read_manifest( &manifest )?;
+
+            let mut difference_count = 0;
+            cpdd::verify_tree( &dir, &entries, session, |entry_| {
+                if entry_.kind != cpdd::DiffKind::Identical { difference_count += 1; }
+
+                match output {
+                    OutputFormat::Text => {
+                        let label = match entry_.kind {
+                            cpdd::DiffKind::Identical => { return; },
+                            cpdd::DiffKind::OnlyInA => { "missing" },
+                            cpdd::DiffKind::OnlyInB => { "untracked" },
+
+                            _ => { entry_.kind.as_str() },
+                        };
+
+                        println!( "{:<22} {}", label, entry_.path.display() );
+                    },
+                    OutputFormat::Json => { println!( "{}", entry_.to_json() ); },
+                }
+            } )?;
+
+            if output == OutputFormat::Text && difference_count == 0 {
+                println!( "No errors found." );
+            }
+            if difference_count > 0 {
+                return Err( CliError::Differences( difference_count ) );
+            }
+        },
+        Action::Restore{ manifest, link_mode, reflink_dir, dst_dir } => {
+            let entries = cpdd::read_manifest( &manifest )?;
+            restore_tree( &entries, link_mode, &reflink_dir, &dst_dir, session )?;
+        },
+        Action::CheckSharing{ relink, reflink_dir, dst_paths } => {
+            let error_count = session.stats.errors;
+            let stats = cpdd::check_sharing(
+                &dst_paths,
+                &reflink_dir,
+                relink,
+                session,
+                |entry_| {
+                    match output {
+                        OutputFormat::Text => { print_sharing_entry( entry_ ); },
+                        OutputFormat::Json => { println!( "{}", entry_.to_json() ); },
+                    }
+                },
+            )?;
+            match output {
+                OutputFormat::Text => {
+                    println!(
+                        "Files: {}, shared: {}, not shared: {}, inline: {}, relinked: {}",
+                        stats.files,
+                        format_size( stats.shared_bytes ),
+                        format_size(
+                            stats.bytes - stats.shared_bytes - stats.inline_bytes ),
+                        format_size( stats.inline_bytes ),
+                        stats.relinked,
+                    );
+                },
+                OutputFormat::Json => { println!( "{}", stats.to_json() ); },
+            }
+
+            let failure_count = session.stats.errors - error_count;
+            if failure_count > 0 {
+                return Err( CliError::Partial( format!(
+                    "failed to check {} paths",
+                    failure_count,
+                ) ) );
+            }
+            if stats.unshared > 0 {
+                return Err( CliError::Unshared( stats.unshared as usize ) );
+            }
+        },
+        Action::Du{ max_depth, paths } => {
+            let error_count = session.stats.errors;
+            if output == OutputFormat::Text {
+                println!( "{:>10} {:>10} {:>10}  Path", "Logical", "Exclusive", "Shared" );
+            }
+            let usage = cpdd::calc_space_usage( &paths, session, |entry_| {
+                if max_depth.is_some_and( |max_depth_| entry_.depth > max_depth_ ) { return; }
+
+                match output {
+                    OutputFormat::Text => {
+                        print_usage_line( &entry_.usage, &entry_.path.to_string_lossy() );
+                    },
+                    OutputFormat::Json => { println!( "{}", entry_.to_json() ); },
+                }
+            } )?;
+            match output {
+                OutputFormat::Text => {
+                    if paths.len() > 1 { print_usage_line( &usage, "total" ); }
+                },
+                OutputFormat::Json => {
+                    let mut value = usage.to_json();
+                    value[ "event" ] = serde_json::json!( "usage-totals" );
+                    println!( "{}", value );
+                },
+            }
+
+            let failure_count = session.stats.errors - error_count;
+            if failure_count > 0 {
+                return Err( CliError::Partial( format!(
+                    "failed to read {} paths",
+                    failure_count,
+                ) ) );
+            }
+        },
+        Action::Snapshot( snapshot_action_ ) => {
+            run_snapshot( snapshot_action_, output, session )?;
+        },
+        Action::Store( store_action_ ) => {
+            run_store( store_action_, output, session )?;
+        },
+        Action::Hash{ algorithm, check: Some( listing_path_ ), .. } => {
+            check_hashes( &listing_path_, algorithm, output, session )?;
+        },
+        Action::Hash{ recurse_dirs, algorithm, check: None, src_paths, .. } => {
+            let mut failure_count = 0;
+            for src_path_ in &src_paths {
+                log::debug!( "Handling source path: {:?}", src_path_ );
+
+                match cpdd::hash_path( src_path_, algorithm, recurse_dirs, session ) {
+                    Ok( hash_ ) => {
+                        log::info!(
+                                "Result: hash: {:?}, path: {:?}", hash_, src_path_ );
+                    },
+                    Err( error_ ) => {
+                        if cpdd::is_cancelled( &error_ ) {
+                            return Err( CliError::Cancelled );
+                        }
+
+                        log::error!(
+                            "Hashing failed: path: {:?}, error: {}",
+                            src_path_,
+                            error_,
+                        );
+
+                        failure_count += 1;
+                    },
+                }
+            }
+
+            if failure_count > 0 {
+                return Err( CliError::Partial( format!(
+                    "failed to hash {} of {} source paths",
+                    failure_count,
+                    src_paths.len(),
+                ) ) );
+            }
+        },
+    }
+
+    return Ok( () );
+}
+
+
+
+// MAIN
+
+fn main()
+{
+    use structopt::StructOpt;
+
+    let cli_args = match CliArgs::from_args_safe() {
+        Ok( cli_args_ ) => { cli_args_ },
+        Err( error_ ) => {
+            // Help and version output are not errors.
+            if !error_.use_stderr() {
+                println!( "{}", error_.message );
+
+                std::process::exit( 0 );
+            }
+
+            eprintln!( "{}", error_.message );
+
+            std::process::exit( EXIT_USAGE );
+        },
+    };
+
+    if let Err( error_ ) = init_logger( cli_args.log_level, &cli_args.log_path ) {
+        eprintln!( "cpdd: error: {}", error_ );
+
+        std::process::exit( EXIT_FATAL );
+    }
+
+    log::debug!( "{:?}", cli_args );
+
+    let output = cli_args.output;
+    let start_time = std::time::Instant::now();
+    let show_progress = {
+        use std::io::IsTerminal;
+
+        !cli_args.no_progress && std::io::stderr().is_terminal()
+    };
+    let hash_listing = match &cli_args.action {
+        Action::Hash{ algorithm, format, .. } => { Some( ( *algorithm, *format ) ) },
+
+        _ => { None },
+    };
+    let mut observer = CliObserver{
+        output,
+        progress: if show_progress { Some( ProgressDisplay::new() ) } else { None },
+        hash_listing,
+    };
+    let mut session = cpdd::Session::new( &mut observer );
+
+    let res = run( cli_args.action, output, &mut session );
+
+    print_summary( output, &session.stats, start_time );
+
+    if let Some( display_ ) = &mut observer.progress { display_.finish(); }
+
+    if let Err( error_ ) = res {
+        log::debug!( "Exiting with error: {:?}", error_ );
+
+        eprintln!( "cpdd: {}", error_ );
+
+        std::process::exit( error_.exit_code() );
+    }
+}
+
+
+#[ cfg( test ) ]
+mod tests
+{
+    use super::*;
+
+    #[ test ]
+    fn parse_duration_units()
+    {
+        assert_eq!( parse_duration( "90" ), Ok( std::time::Duration::from_secs( 90 ) ) );
+        assert_eq!( parse_duration( "90s" ), Ok( std::time::Duration::from_secs( 90 ) ) );
+        assert_eq!( parse_duration( "5m" ), Ok( std::time::Duration::from_secs( 300 ) ) );
+        assert_eq!( parse_duration( "2h" ), Ok( std::time::Duration::from_secs( 7200 ) ) );
+        assert_eq!(
+            parse_duration( "30d" ),
+            Ok( std::time::Duration::from_secs( 2_592_000 ) ),
+        );
+        assert_eq!( parse_duration( "1w" ), Ok( std::time::Duration::from_secs( 604_800 ) ) );
+    }
+
+    #[ test ]
+    fn parse_duration_invalid()
+    {
+        for s_ in &[ "", "d", "-1d", "1.5h", "1y", "1 d", "18446744073709551615w" ] {
+            assert!( parse_duration( s_ ).is_err(), "{:?}", s_ );
+        }
     }
 }
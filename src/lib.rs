@@ -15,758 +15,7242 @@
 
 const HASH_LENGTH: usize = 32;  // bytes
 const BUFFER_LENGTH: usize = 1 << 28;  // bytes
+const COMPARE_BUFFER_LENGTH: usize = 1 << 20;  // bytes
+const BACKUP_HASH_LENGTH: usize = 16;  // hex digits
+const PREFLIGHT_PREFIX: &str = ".cpdd-preflight.";
+const PREFLIGHT_CONTENT: &[ u8 ] = b"cpdd pre-flight check\n";
+const SNAPSHOTS_DIR_NAME: &str = "snapshots";
+const QUARANTINE_DIR_NAME: &str = "quarantine";
+const RESERVED_DIR_NAMES: [ &str; 2 ] = [ SNAPSHOTS_DIR_NAME, QUARANTINE_DIR_NAME ];
+const REPAIR_PREFIX: &str = ".cpdd-repair.";
+const RELINK_PREFIX: &str = ".cpdd-relink.";
+const TEMP_PREFIXES: [ &str; 2 ] = [ PREFLIGHT_PREFIX, REPAIR_PREFIX ];
+const TEMP_MAX_AGE: std::time::Duration = std::time::Duration::from_secs( 24 * 60 * 60 );
+const FS_IOC_FIEMAP: u64 = 0xC020_660B;
+const FS_IOC_GETFLAGS: u64 = 0x8008_6601;
+const FS_FL_USER_VISIBLE: u32 = 0x0003_DFFF;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_UNKNOWN: u32 = 0x2;
+const FIEMAP_EXTENT_DELALLOC: u32 = 0x4;
+const FIEMAP_EXTENT_DATA_INLINE: u32 = 0x200;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+const FIEMAP_BATCH_LENGTH: usize = 256;  // extents
+const SIZE_BUCKET_BOUNDS: [ u64; 7 ] = [  // bytes
+    1,
+    4 * 1024,
+    64 * 1024,
+    1024 * 1024,
+    16 * 1024 * 1024,
+    256 * 1024 * 1024,
+    4 * 1024 * 1024 * 1024,
+];
+
+
+
+// TYPES
+
+#[ derive( Clone, Debug ) ]
+/// The options for copying source paths.
+pub struct CpddOptions
+{
+    /// Recurse source directories.
+    pub recurse_dirs: bool,
+
+    /// Skip invalid source file types.
+    ///
+    /// By default, invalid source file types result in an error.
+    /// If this option is set, invalid file types result only in a warning.
+    pub skip_invalid_file_types: bool,
+
+    /// The policy to apply when a destination path exists
+    /// but is not equivalent to the source path.
+    pub on_conflict: ConflictPolicy,
+
+    /// The naming scheme to use for backup paths.
+    pub backup_style: BackupStyle,
+
+    /// The backup suffix to use for renaming existing destination paths
+    /// with `BackupStyle::Suffix`.
+    /// Must not be the null string; renaming fails with an `InvalidInput` error otherwise.
+    pub backup_suffix: String,
+
+    /// The path to append conflict records to, one JSON object per line.
+    pub conflict_log_path: Option< std::path::PathBuf >,
+
+    /// How destination files are created from reflink directory files.
+    pub link_mode: LinkMode,
+
+    /// The free space to keep in reserve, in bytes.
+    ///
+    /// Copying stops with an error before writing a file
+    /// that would leave less free space than this.
+    pub min_free_bytes: u64,
+
+    /// Replace source files with reflinks of their reflink directory files.
+    ///
+    /// Each source file is replaced atomically once its reflink has been compared with it,
+    /// keeping its ownership, permissions, times, and extended attributes,
+    /// but not its inode number.
+    /// Source files that already share all extents with their reflink directory files,
+    /// or that have multiple hardlinks, are not replaced.
+    /// Failing replacements are reported as errors and do not stop the copy.
+    pub in_place_link: bool,
+}
+
+impl Default for CpddOptions
+{
+    fn default() -> Self
+    {
+        return Self{
+            recurse_dirs: false,
+            skip_invalid_file_types: false,
+            on_conflict: ConflictPolicy::Rename,
+            backup_style: BackupStyle::Suffix,
+            backup_suffix: "~".to_owned(),
+            conflict_log_path: None,
+            link_mode: LinkMode::Reflink,
+            min_free_bytes: 0,
+            in_place_link: false,
+        };
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// The options for verifying the reflink directory.
+pub struct VerifyOptions
+{
+    /// The number of files to hash in parallel.
+    /// If `0`, the available parallelism is used.
+    pub jobs: usize,
+
+    /// The path to record the time each file was last verified to,
+    /// one JSON object per line.
+    pub checkpoint_path: Option< std::path::PathBuf >,
+
+    /// Skip files verified within this interval, according to the checkpoint.
+    pub since: Option< std::time::Duration >,
+}
+
+impl Default for VerifyOptions
+{
+    fn default() -> Self
+    {
+        return Self{ jobs: 1, checkpoint_path: None, since: None };
+    }
+}
+
+#[ derive( Clone, Debug, Default ) ]
+/// The reflink directory entries, classified by verification.
+///
+/// The reserved `snapshots` and `quarantine` subdirectories are not included.
+pub struct VerifyReport
+{
+    /// The number of files whose hash matches their name.
+    pub valid: u64,
+
+    /// The files whose hash differs from their name.
+    pub mismatches: Vec< std::path::PathBuf >,
+
+    /// The empty files not named by the hash of empty content.
+    pub truncated: Vec< std::path::PathBuf >,
+
+    /// The entries whose name is not a well-formed hash.
+    pub unexpected_names: Vec< std::path::PathBuf >,
+
+    /// The entries that are not files.
+    pub unexpected_types: Vec< std::path::PathBuf >,
+
+    /// The files that could not be read.
+    pub unreadable: Vec< std::path::PathBuf >,
+}
+
+impl VerifyReport
+{
+    /// Returns the files with a well-formed name but mismatching content.
+    pub fn corrupt( &self ) -> impl Iterator< Item = &std::path::PathBuf >
+    {
+        return self.mismatches.iter().chain( &self.truncated );
+    }
+
+    /// Returns the entries that do not belong in the reflink directory.
+    pub fn foreign( &self ) -> impl Iterator< Item = &std::path::PathBuf >
+    {
+        return self.unexpected_names.iter().chain( &self.unexpected_types );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The policy to apply when a destination path exists
+/// but is not equivalent to the source path.
+pub enum ConflictPolicy
+{
+    /// Rename the destination path and copy the source path in its place.
+    Rename,
+
+    /// Remove the destination path and copy the source path in its place.
+    ///
+    /// Destination directories are not removed but renamed.
+    Overwrite,
+
+    /// Keep the newer path (by modification time) in place
+    /// and place the older path under a backup name.
+    Newer,
+
+    /// Keep the larger path (by size) in place
+    /// and place the smaller path under a backup name.
+    Larger,
+
+    /// Keep the destination path and skip the source path.
+    Skip,
+
+    /// Keep the destination path and fail with an error.
+    Fail,
+
+    /// Keep the destination path and copy the source path
+    /// alongside it under a backup name.
+    Both,
+}
+
+impl ConflictPolicy
+{
+    pub const VARIANTS: &'static [ &'static str ] =
+            &[ "rename", "overwrite", "newer", "larger", "skip", "fail", "both" ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            ConflictPolicy::Rename => { "rename" },
+            ConflictPolicy::Overwrite => { "overwrite" },
+            ConflictPolicy::Newer => { "newer" },
+            ConflictPolicy::Larger => { "larger" },
+            ConflictPolicy::Skip => { "skip" },
+            ConflictPolicy::Fail => { "fail" },
+            ConflictPolicy::Both => { "both" },
+        };
+    }
+}
+
+impl std::str::FromStr for ConflictPolicy
+{
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "rename" => { Ok( ConflictPolicy::Rename ) },
+            "overwrite" => { Ok( ConflictPolicy::Overwrite ) },
+            "newer" => { Ok( ConflictPolicy::Newer ) },
+            "larger" => { Ok( ConflictPolicy::Larger ) },
+            "skip" => { Ok( ConflictPolicy::Skip ) },
+            "fail" => { Ok( ConflictPolicy::Fail ) },
+            "both" => { Ok( ConflictPolicy::Both ) },
+
+            _ => { Err( format!( "Invalid conflict policy: {:?}", s ) ) },
+        };
+    }
+}
+
+impl std::fmt::Display for ConflictPolicy
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The naming scheme for backup paths.
+pub enum BackupStyle
+{
+    /// Append the backup suffix, for example, `file~`.
+    ///
+    /// Existing backup paths are renamed recursively,
+    /// for example, `file~` to `file~~`.
+    Suffix,
+
+    /// Append the next free backup number, for example, `file.~1~`.
+    Numbered,
+
+    /// Append the current UTC time,
+    /// for example, `file.2024-01-01T00:00:00.000000000Z`.
+    Timestamp,
+
+    /// Append a short hash of the backed-up content,
+    /// for example, `file.0123456789abcdef`.
+    ///
+    /// Symlinks are hashed by link contents;
+    /// directories fall back to `BackupStyle::Timestamp`.
+    Hash,
+}
+
+impl BackupStyle
+{
+    pub const VARIANTS: &'static [ &'static str ] =
+            &[ "suffix", "numbered", "timestamp", "hash" ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            BackupStyle::Suffix => { "suffix" },
+            BackupStyle::Numbered => { "numbered" },
+            BackupStyle::Timestamp => { "timestamp" },
+            BackupStyle::Hash => { "hash" },
+        };
+    }
+}
+
+impl std::str::FromStr for BackupStyle
+{
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "suffix" => { Ok( BackupStyle::Suffix ) },
+            "numbered" => { Ok( BackupStyle::Numbered ) },
+            "timestamp" => { Ok( BackupStyle::Timestamp ) },
+            "hash" => { Ok( BackupStyle::Hash ) },
+
+            _ => { Err( format!( "Invalid backup style: {:?}", s ) ) },
+        };
+    }
+}
+
+impl std::fmt::Display for BackupStyle
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// How destination files are created from reflink directory files.
+pub enum LinkMode
+{
+    /// Reflink destination files; this is required for deduplication.
+    Reflink,
+
+    /// Hardlink destination files and make reflink directory files read-only.
+    ///
+    /// Note that hardlinked files share their inode, and thus their metadata,
+    /// with the reflink directory file and with each other;
+    /// source metadata is not copied to hardlinked destination files.
+    Hardlink,
+
+    /// Copy destination files; this requires space for each copy.
+    Copy,
+
+    /// Use `Reflink` if supported, otherwise `Hardlink` if the reflink
+    /// directory and the destination directory reside in the same device,
+    /// otherwise `Copy`.
+    Auto,
+}
+
+impl LinkMode
+{
+    pub const VARIANTS: &'static [ &'static str ] =
+            &[ "reflink", "hardlink", "copy", "auto" ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            LinkMode::Reflink => { "reflink" },
+            LinkMode::Hardlink => { "hardlink" },
+            LinkMode::Copy => { "copy" },
+            LinkMode::Auto => { "auto" },
+        };
+    }
+}
+
+impl std::str::FromStr for LinkMode
+{
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "reflink" => { Ok( LinkMode::Reflink ) },
+            "hardlink" => { Ok( LinkMode::Hardlink ) },
+            "copy" => { Ok( LinkMode::Copy ) },
+            "auto" => { Ok( LinkMode::Auto ) },
+
+            _ => { Err( format!( "Invalid link mode: {:?}", s ) ) },
+        };
+    }
+}
+
+impl std::fmt::Display for LinkMode
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The hash algorithm to use for hash listings.
+pub enum HashAlgorithm
+{
+    /// BLAKE2bp with a 32-byte digest, as used for reflink directory files.
+    Blake2bp,
+
+    /// BLAKE2b with a 64-byte digest, as computed by `b2sum`.
+    Blake2b,
+
+    /// SHA-256, as computed by `sha256sum`.
+    Sha256,
+}
+
+impl HashAlgorithm
+{
+    pub const VARIANTS: &'static [ &'static str ] =
+            &[ "blake2bp", "blake2b", "sha256" ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            HashAlgorithm::Blake2bp => { "blake2bp" },
+            HashAlgorithm::Blake2b => { "blake2b" },
+            HashAlgorithm::Sha256 => { "sha256" },
+        };
+    }
+
+    /// Returns the tag used in BSD-style hash listings.
+    pub fn tag( self ) -> &'static str
+    {
+        return match self {
+            HashAlgorithm::Blake2bp => { "BLAKE2bp-256" },
+            HashAlgorithm::Blake2b => { "BLAKE2b" },
+            HashAlgorithm::Sha256 => { "SHA256" },
+        };
+    }
+
+    pub fn from_tag( tag: &str ) -> Option< Self >
+    {
+        return match tag {
+            "BLAKE2bp-256" => { Some( HashAlgorithm::Blake2bp ) },
+            "BLAKE2b" => { Some( HashAlgorithm::Blake2b ) },
+            "SHA256" => { Some( HashAlgorithm::Sha256 ) },
+
+            _ => { None },
+        };
+    }
+
+    /// Returns the digest length, in hex digits.
+    pub fn hex_length( self ) -> usize
+    {
+        return match self {
+            HashAlgorithm::Blake2bp => { 2 * HASH_LENGTH },
+            HashAlgorithm::Blake2b => { 128 },
+            HashAlgorithm::Sha256 => { 64 },
+        };
+    }
+}
+
+impl std::str::FromStr for HashAlgorithm
+{
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "blake2bp" => { Ok( HashAlgorithm::Blake2bp ) },
+            "blake2b" => { Ok( HashAlgorithm::Blake2b ) },
+            "sha256" => { Ok( HashAlgorithm::Sha256 ) },
+
+            _ => { Err( format!( "Invalid hash algorithm: {:?}", s ) ) },
+        };
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The line format of hash listings.
+pub enum HashFormat
+{
+    /// `<hash> <path>`.
+    ///
+    /// Paths starting with a space or `*` are written as in the `gnu` format,
+    /// since a single space followed by either would be read as the `gnu` separator.
+    Cpdd,
+
+    /// `<hash>  <path>`, as written by `b2sum` and `sha256sum`.
+    Gnu,
+
+    /// `<TAG> (<path>) = <hash>`, as written by BSD tools and by `--tag`.
+    Bsd,
+}
+
+impl HashFormat
+{
+    pub const VARIANTS: &'static [ &'static str ] = &[ "cpdd", "gnu", "bsd" ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            HashFormat::Cpdd => { "cpdd" },
+            HashFormat::Gnu => { "gnu" },
+            HashFormat::Bsd => { "bsd" },
+        };
+    }
+}
+
+impl std::str::FromStr for HashFormat
+{
+    type Err = String;
+
+    fn from_str( s: &str ) -> Result< Self, Self::Err >
+    {
+        return match s {
+            "cpdd" => { Ok( HashFormat::Cpdd ) },
+            "gnu" => { Ok( HashFormat::Gnu ) },
+            "bsd" => { Ok( HashFormat::Bsd ) },
+
+            _ => { Err( format!( "Invalid hash format: {:?}", s ) ) },
+        };
+    }
+}
+
+impl std::fmt::Display for HashFormat
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Debug, PartialEq, Eq ) ]
+/// A parsed line of a hash listing.
+pub struct HashLine
+{
+    /// The algorithm named by the line, for BSD-style lines.
+    pub algorithm: Option< HashAlgorithm >,
+
+    /// The hash, in lowercase hex digits.
+    pub hash: String,
+
+    pub path: std::path::PathBuf,
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The classification of a relative path when comparing two trees.
+///
+/// Paths present in both trees are compared using the equivalence rules
+/// of copying: directories are always equivalent, files if their hashes match,
+/// and symlinks if their link contents match.
+/// Hence, copying tree B into tree A renames the path in tree A
+/// for each path classified as differing, unless `overwrite` is used.
+pub enum DiffKind
+{
+    /// The path exists only in tree A.
+    OnlyInA,
+
+    /// The path exists only in tree B.
+    OnlyInB,
+
+    /// The paths are equivalent.
+    Identical,
+
+    /// The paths are files whose contents differ.
+    ContentDiffers,
+
+    /// The paths have different file types.
+    TypeDiffers,
+
+    /// The paths are symlinks whose link contents differ.
+    SymlinkTargetDiffers,
+
+    /// The paths are equivalent but their permissions or modification times
+    /// differ; only reported when comparing against manifests.
+    MetadataDiffers,
+}
+
+impl DiffKind
+{
+    pub const VARIANTS: &'static [ &'static str ] = &[
+        "only-in-a",
+        "only-in-b",
+        "identical",
+        "content-differs",
+        "type-differs",
+        "symlink-target-differs",
+        "metadata-differs",
+    ];
+
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            DiffKind::OnlyInA => { "only-in-a" },
+            DiffKind::OnlyInB => { "only-in-b" },
+            DiffKind::Identical => { "identical" },
+            DiffKind::ContentDiffers => { "content-differs" },
+            DiffKind::TypeDiffers => { "type-differs" },
+            DiffKind::SymlinkTargetDiffers => { "symlink-target-differs" },
+            DiffKind::MetadataDiffers => { "metadata-differs" },
+        };
+    }
+}
+
+impl std::fmt::Display for DiffKind
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return f.write_str( self.as_str() );
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// A relative path classified when comparing two trees.
+pub struct DiffEntry
+{
+    pub kind: DiffKind,
+
+    /// The path relative to the tree roots.
+    pub path: std::path::PathBuf,
+
+    /// The file type in tree A, if present.
+    pub a_type: Option< &'static str >,
+
+    /// The file type in tree B, if present.
+    pub b_type: Option< &'static str >,
+
+    /// The file hash in tree A, if calculated.
+    pub a_hash: Option< String >,
+
+    /// The file hash in tree B, if calculated.
+    pub b_hash: Option< String >,
+}
+
+impl DiffEntry
+{
+    fn new( kind: DiffKind, path: std::path::PathBuf ) -> Self
+    {
+        return Self{
+            kind,
+            path,
+            a_type: None,
+            b_type: None,
+            a_hash: None,
+            b_hash: None,
+        };
+    }
+
+    /// Returns the entry as a JSON object with `event` set to `diff`.
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": "diff",
+            "kind": self.kind.as_str(),
+            "path": self.path.to_string_lossy(),
+            "a_type": self.a_type,
+            "b_type": self.b_type,
+            "a_hash": self.a_hash,
+            "b_hash": self.b_hash,
+        } );
+    }
+}
+
+#[ derive( Clone, Debug, PartialEq, Eq ) ]
+/// A manifest entry: the state of a path relative to a tree root.
+///
+/// Manifests are written as one JSON object per entry and line.
+pub struct ManifestEntry
+{
+    /// The path relative to the tree root.
+    pub path: std::path::PathBuf,
+
+    /// The file type: `dir`, `file`, `symlink`, or `other`.
+    pub file_type: &'static str,
+
+    pub size: u64,
+
+    /// The permission bits.
+    pub mode: u32,
+
+    /// The access time, in nanoseconds since the Unix epoch.
+    pub atime_ns: i64,
+
+    /// The modification time, in nanoseconds since the Unix epoch.
+    pub mtime_ns: i64,
+
+    /// The file hash, for files.
+    pub hash: Option< String >,
+
+    /// The link contents, for symlinks.
+    pub target: Option< std::path::PathBuf >,
+}
+
+impl ManifestEntry
+{
+    /// Creates an entry from the metadata of the path, reading symlink contents.
+    pub fn from_metadata< P, Q >(
+        path: P,
+        rel_path: Q,
+        metadata: &std::fs::Metadata,
+        hash: Option< String >,
+    ) -> std::io::Result< Self >
+    where
+        P: AsRef< std::path::Path >,
+        Q: AsRef< std::path::Path >,
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let target =
+                if metadata.file_type().is_symlink() {
+                    Some( path.as_ref().read_link()? )
+                }
+                else { None };
+
+        return Ok( Self{
+            path: rel_path.as_ref().to_owned(),
+            file_type: file_type_name( metadata.file_type() ),
+            size: metadata.len(),
+            mode: metadata.mode() & 0o7777,
+            atime_ns: metadata.atime() * 1_000_000_000 + metadata.atime_nsec(),
+            mtime_ns: metadata.mtime() * 1_000_000_000 + metadata.mtime_nsec(),
+            hash,
+            target,
+        } );
+    }
+
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "path": self.path.to_string_lossy(),
+            "type": self.file_type,
+            "size": self.size,
+            "mode": self.mode,
+            "atime_ns": self.atime_ns,
+            "mtime_ns": self.mtime_ns,
+            "hash": self.hash,
+            "target": self.target.as_ref().map( |target_| target_.to_string_lossy() ),
+        } );
+    }
+
+    pub fn from_json( value: &serde_json::Value ) -> Result< Self, String >
+    {
+        let field = |name_: &str| {
+            return value.get( name_ )
+                .ok_or_else( || format!( "Missing manifest field: {:?}", name_ ) );
+        };
+        let invalid = |name_: &str| format!( "Invalid manifest field: {:?}", name_ );
+
+        let path = field( "path" )?.as_str().ok_or_else( || invalid( "path" ) )?;
+        let file_type = match field( "type" )?.as_str() {
+            Some( "dir" ) => { "dir" },
+            Some( "file" ) => { "file" },
+            Some( "symlink" ) => { "symlink" },
+            Some( "other" ) => { "other" },
+
+            _ => { return Err( invalid( "type" ) ); },
+        };
+        let hash = match field( "hash" )? {
+            serde_json::Value::Null => { None },
+            serde_json::Value::String( hash_ ) if is_hex( hash_ ) => {
+                Some( hash_.to_owned() )
+            },
+
+            _ => { return Err( invalid( "hash" ) ); },
+        };
+        let target = match field( "target" )? {
+            serde_json::Value::Null => { None },
+            serde_json::Value::String( target_ ) => { Some( target_.into() ) },
+
+            _ => { return Err( invalid( "target" ) ); },
+        };
+
+        return Ok( Self{
+            path: path.into(),
+            file_type,
+            size: field( "size" )?.as_u64().ok_or_else( || invalid( "size" ) )?,
+            mode: field( "mode" )?.as_u64()
+                .filter( |mode_| *mode_ <= 0o7777 ).map( |mode_| mode_ as u32 )
+                .ok_or_else( || invalid( "mode" ) )?,
+            atime_ns: field( "atime_ns" )?.as_i64()
+                .ok_or_else( || invalid( "atime_ns" ) )?,
+            mtime_ns: field( "mtime_ns" )?.as_i64()
+                .ok_or_else( || invalid( "mtime_ns" ) )?,
+            hash,
+            target,
+        } );
+    }
+}
+
+/// A writer of manifest entries for paths under a tree root.
+pub struct ManifestWriter
+{
+    root: std::path::PathBuf,
+    writer: Box< dyn std::io::Write >,
+
+    /// The known file hashes, with the sizes and modification times they are valid for.
+    hashes: std::collections::HashMap<
+        std::path::PathBuf,
+        ( u64, std::time::SystemTime, String ),
+    >,
+}
+
+impl ManifestWriter
+{
+    pub fn new< P >( writer: Box< dyn std::io::Write >, root: P ) -> Self
+    where
+        P: AsRef< std::path::Path >,
+    {
+        return Self{
+            root: root.as_ref().to_owned(),
+            writer,
+            hashes: std::collections::HashMap::new(),
+        };
+    }
+
+    /// Creates a manifest file, truncating it if it exists.
+    pub fn create< P, Q >( path: P, root: Q ) -> std::io::Result< Self >
+    where
+        P: AsRef< std::path::Path >,
+        Q: AsRef< std::path::Path >,
+    {
+        let file = std::fs::File::create( path )?;
+
+        return Ok( Self::new(
+                Box::new( std::io::BufWriter::new( file ) ), root ) );
+    }
+
+    /// Remembers the hash of the file, so that [`write_manifest`] need not
+    /// hash it again unless its size or modification time changes.
+    pub fn remember_hash< P >( &mut self, path: P, hash: String ) -> std::io::Result< () >
+    where
+        P: AsRef< std::path::Path >,
+    {
+        let path = path.as_ref();
+
+        let metadata = path.symlink_metadata()?;
+        self.hashes.insert( path.to_owned(), ( metadata.len(), metadata.modified()?, hash ) );
+
+        return Ok( () );
+    }
+
+    /// Returns the remembered hash of the file, if it is still valid.
+    fn known_hash( &self, path: &std::path::Path, metadata: &std::fs::Metadata )
+        -> Option< String >
+    {
+        let ( size, time, hash ) = self.hashes.get( path )?;
+        if *size != metadata.len() || metadata.modified().ok()? != *time { return None; }
+
+        return Some( hash.clone() );
+    }
+
+    pub fn write_entry( &mut self, entry: &ManifestEntry ) -> std::io::Result< () >
+    {
+        return writeln!( self.writer, "{}", entry.to_json() );
+    }
+
+    /// Flushes the manifest.
+    pub fn finish( mut self ) -> std::io::Result< () >
+    {
+        return self.writer.flush();
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// A named manifest recorded in the reflink directory.
+pub struct Snapshot
+{
+    pub name: String,
+
+    /// The manifest path.
+    pub path: std::path::PathBuf,
+
+    /// The time the snapshot was last written.
+    pub time: std::time::SystemTime,
+}
+
+impl Snapshot
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": "snapshot",
+            "name": self.name,
+            "path": self.path.to_string_lossy(),
+            "time": unix_time( self.time ),
+        } );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// A retention policy for snapshots.
+///
+/// A snapshot is kept if any of the rules keeps it;
+/// days and weeks are in UTC, and weeks are ISO weeks.
+pub struct RetentionPolicy
+{
+    /// The number of most recent snapshots to keep.
+    pub keep_last: usize,
+
+    /// The number of most recent days to keep the last snapshot of.
+    pub keep_daily: usize,
+
+    /// The number of most recent weeks to keep the last snapshot of.
+    pub keep_weekly: usize,
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// A file extent, as reported by FIEMAP.
+pub struct Extent
+{
+    /// The offset in the file, in bytes.
+    pub logical: u64,
+
+    /// The offset on the device, in bytes.
+    pub physical: u64,
+
+    pub length: u64,
+
+    /// The FIEMAP extent flags.
+    pub flags: u32,
+}
+
+impl Extent
+{
+    /// Returns whether the file system reports the extent as shared with other files.
+    pub fn is_shared( &self ) -> bool
+    {
+        return self.flags & FIEMAP_EXTENT_SHARED != 0;
+    }
+
+    /// Returns whether the data is stored inline with the file metadata.
+    pub fn is_inline( &self ) -> bool
+    {
+        return self.flags & FIEMAP_EXTENT_DATA_INLINE != 0;
+    }
+
+    /// Returns whether the physical offset is known.
+    pub fn is_located( &self ) -> bool
+    {
+        let unlocated_flags =
+                FIEMAP_EXTENT_UNKNOWN | FIEMAP_EXTENT_DELALLOC | FIEMAP_EXTENT_DATA_INLINE;
+
+        return self.flags & unlocated_flags == 0;
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// The extent sharing of a destination file with its reflink directory file.
+pub struct SharingEntry
+{
+    pub path: std::path::PathBuf,
+    pub hash: String,
+
+    /// The file size, in bytes.
+    pub size: u64,
+
+    /// The bytes sharing physical extents with the reflink directory file,
+    /// or `None` if there is no reflink directory file.
+    pub shared_bytes: Option< u64 >,
+
+    /// Whether both the file and the reflink directory file are stored inline,
+    /// so that they cannot share extents.
+    pub inline: bool,
+
+    /// Whether the file was replaced by a reflink of the reflink directory file.
+    pub relinked: bool,
+}
+
+impl SharingEntry
+{
+    /// Returns whether all of the file shares extents with the reflink directory file.
+    pub fn is_shared( &self ) -> bool
+    {
+        return self.shared_bytes == Some( self.size );
+    }
+
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": "sharing",
+            "path": self.path.to_string_lossy(),
+            "hash": self.hash,
+            "size": self.size,
+            "shared_bytes": self.shared_bytes,
+            "inline": self.inline,
+            "relinked": self.relinked,
+        } );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// The totals of checking extent sharing.
+pub struct SharingStats
+{
+    pub files: u64,
+
+    /// The total size of files, in bytes.
+    pub bytes: u64,
+
+    /// The bytes sharing physical extents with reflink directory files.
+    pub shared_bytes: u64,
+
+    /// The number of files not fully sharing extents,
+    /// other than inline files.
+    pub unshared: u64,
+
+    /// The number of files stored inline, to which sharing does not apply.
+    pub inline: u64,
+
+    /// The total size of inline files, in bytes.
+    pub inline_bytes: u64,
+
+    /// The number of files without a reflink directory file.
+    pub unstored: u64,
+
+    pub relinked: u64,
+}
+
+impl SharingStats
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": "sharing-totals",
+            "files": self.files,
+            "bytes": self.bytes,
+            "shared_bytes": self.shared_bytes,
+            "unshared": self.unshared,
+            "inline": self.inline,
+            "inline_bytes": self.inline_bytes,
+            "unstored": self.unstored,
+            "relinked": self.relinked,
+        } );
+    }
+
+    fn record( &mut self, entry: &SharingEntry )
+    {
+        self.files += 1;
+        self.bytes += entry.size;
+        self.shared_bytes += entry.shared_bytes.unwrap_or( 0 );
+        if entry.inline {
+            self.inline += 1;
+            self.inline_bytes += entry.size;
+        }
+        else if !entry.is_shared() { self.unshared += 1; }
+        if entry.shared_bytes.is_none() { self.unstored += 1; }
+        if entry.relinked { self.relinked += 1; }
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// The space used by files, counting each inode and each physical extent once.
+pub struct SpaceUsage
+{
+    pub files: u64,
+
+    /// The total size of files, in bytes.
+    pub logical_bytes: u64,
+
+    /// The bytes in extents used by a single file.
+    pub exclusive_bytes: u64,
+
+    /// The bytes in extents shared with other files,
+    /// such as reflink directory files,
+    /// including all extents of files with multiple hardlinks.
+    ///
+    /// Each extent counts towards the first file found using it.
+    pub shared_bytes: u64,
+}
+
+impl SpaceUsage
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "files": self.files,
+            "logical_bytes": self.logical_bytes,
+            "exclusive_bytes": self.exclusive_bytes,
+            "shared_bytes": self.shared_bytes,
+        } );
+    }
+
+    fn merge( &mut self, other: &SpaceUsage )
+    {
+        self.files += other.files;
+        self.logical_bytes += other.logical_bytes;
+        self.exclusive_bytes += other.exclusive_bytes;
+        self.shared_bytes += other.shared_bytes;
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// The space used by a path, including the contents of directories.
+pub struct UsageEntry
+{
+    pub path: std::path::PathBuf,
+
+    /// The directory depth below the given path, `0` for the given path itself.
+    pub depth: usize,
+
+    pub usage: SpaceUsage,
+}
+
+impl UsageEntry
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        let mut value = self.usage.to_json();
+        value[ "event" ] = serde_json::json!( "usage" );
+        value[ "path" ] = serde_json::json!( self.path.to_string_lossy() );
+        value[ "depth" ] = serde_json::json!( self.depth );
+
+        return value;
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default, PartialEq, Eq ) ]
+/// A number of reflink directory files and their total size.
+pub struct EntryCount
+{
+    pub entries: u64,
+
+    /// The total size, in bytes.
+    pub bytes: u64,
+}
+
+impl EntryCount
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( { "entries": self.entries, "bytes": self.bytes } );
+    }
+
+    fn add( &mut self, size: u64 )
+    {
+        self.entries += 1;
+        self.bytes += size;
+    }
+}
+
+#[ derive( Clone, Debug, Default ) ]
+/// Statistics of the reflink directory files.
+pub struct StoreStats
+{
+    pub total: EntryCount,
+
+    /// The files by size, each bucket with its exclusive upper size bound,
+    /// or `None` for the last one.
+    pub size_histogram: Vec< ( Option< u64 >, EntryCount ) >,
+
+    /// The hashes and sizes of the largest files, largest first.
+    pub largest: Vec< ( String, u64 ) >,
+
+    /// The files by the UTC day of their modification time,
+    /// which is the time they were added.
+    pub daily: std::collections::BTreeMap< chrono::NaiveDate, EntryCount >,
+
+    /// The references by snapshots, or `None` if there are no snapshots.
+    pub references: Option< ReferenceStats >,
+}
+
+impl StoreStats
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        let size_histogram = self.size_histogram.iter()
+            .map( |( max_bytes_, count_ )| {
+                let mut value = count_.to_json();
+                value[ "max_bytes" ] = serde_json::json!( max_bytes_ );
+
+                return value;
+            } )
+            .collect::< Vec< _ > >();
+        let largest = self.largest.iter()
+            .map( |( hash_, size_ )| serde_json::json!( { "hash": hash_, "size": size_ } ) )
+            .collect::< Vec< _ > >();
+        let daily = self.daily.iter()
+            .map( |( day_, count_ )| {
+                let mut value = count_.to_json();
+                value[ "day" ] = serde_json::json!( day_.to_string() );
+
+                return value;
+            } )
+            .collect::< Vec< _ > >();
+
+        return serde_json::json!( {
+            "event": "store-stats",
+            "entries": self.total.entries,
+            "bytes": self.total.bytes,
+            "size_histogram": size_histogram,
+            "largest": largest,
+            "daily": daily,
+            "references": self.references.as_ref().map( ReferenceStats::to_json ),
+        } );
+    }
+}
+
+#[ derive( Clone, Debug, Default ) ]
+/// Statistics of the references to reflink directory files by snapshots.
+///
+/// Each file entry of each snapshot counts as a reference to the file named by its hash.
+pub struct ReferenceStats
+{
+    pub snapshots: usize,
+    pub references: u64,
+
+    /// The files not referenced by any snapshot.
+    pub unreferenced: EntryCount,
+
+    /// The number of files by their number of references, for referenced files.
+    pub counts: std::collections::BTreeMap< u64, u64 >,
+
+    /// The hashes and reference counts of the most referenced files, most referenced first.
+    pub most_referenced: Vec< ( String, u64 ) >,
+
+    /// The number of referenced hashes without a reflink directory file.
+    pub missing: u64,
+}
+
+impl ReferenceStats
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        let counts = self.counts.iter()
+            .map( |( references_, entries_ )| {
+                return serde_json::json!( {
+                    "references": references_,
+                    "entries": entries_,
+                } );
+            } )
+            .collect::< Vec< _ > >();
+        let most_referenced = self.most_referenced.iter()
+            .map( |( hash_, references_ )| {
+                return serde_json::json!( { "hash": hash_, "references": references_ } );
+            } )
+            .collect::< Vec< _ > >();
+
+        return serde_json::json!( {
+            "snapshots": self.snapshots,
+            "references": self.references,
+            "unreferenced": self.unreferenced.to_json(),
+            "counts": counts,
+            "most_referenced": most_referenced,
+            "missing": self.missing,
+        } );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// An estimate of the space required for copying source paths.
+pub struct CopyPlan
+{
+    /// The number of source files.
+    pub file_count: u64,
+
+    /// The total size of source files, in bytes.
+    pub total_bytes: u64,
+
+    /// The total size of unique source files
+    /// not yet in the reflink directory, in bytes.
+    pub new_bytes: u64,
+
+    /// The number of bytes to be written in the reflink directory.
+    pub reflink_dir_bytes: u64,
+
+    /// The number of bytes to be written in the destination directory.
+    pub dst_dir_bytes: u64,
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The kind of an event.
+pub enum EventKind
+{
+    /// A directory or symlink was created, or a file was copied.
+    Copied,
+
+    /// A file was reflinked.
+    Reflinked,
+
+    /// A file was hardlinked.
+    Hardlinked,
+
+    /// A file was inserted into the reflink directory.
+    Stored,
+
+    /// A source path was skipped.
+    Skipped,
+
+    /// A path was renamed to a backup path.
+    Renamed,
+
+    /// A destination path was in conflict with a source path.
+    Conflict,
+
+    /// An operation failed.
+    Error,
+
+    /// A reflink directory file hash did not match its name.
+    Mismatch,
+
+    /// A reflink directory file hash matched its name.
+    Verified,
+
+    /// A file hash was calculated.
+    Hashed,
+
+    /// An expired snapshot or an unreferenced reflink directory file was removed.
+    Removed,
+}
+
+impl EventKind
+{
+    pub fn as_str( self ) -> &'static str
+    {
+        return match self {
+            EventKind::Copied => { "copied" },
+            EventKind::Reflinked => { "reflinked" },
+            EventKind::Hardlinked => { "hardlinked" },
+            EventKind::Stored => { "stored" },
+            EventKind::Skipped => { "skipped" },
+            EventKind::Renamed => { "renamed" },
+            EventKind::Conflict => { "conflict" },
+            EventKind::Error => { "error" },
+            EventKind::Mismatch => { "mismatch" },
+            EventKind::Verified => { "verified" },
+            EventKind::Hashed => { "hashed" },
+            EventKind::Removed => { "removed" },
+        };
+    }
+}
+
+#[ derive( Clone, Debug ) ]
+/// An event reported for a single path.
+pub struct Event
+{
+    pub kind: EventKind,
+
+    /// The source path, or for renames, the renamed path.
+    pub src_path: Option< std::path::PathBuf >,
+
+    /// The destination path, or for renames, the backup path.
+    pub dst_path: Option< std::path::PathBuf >,
+
+    pub hash: Option< String >,
+
+    /// The file size, in bytes.
+    pub size: Option< u64 >,
+
+    /// The time taken by the operation.
+    pub duration: Option< std::time::Duration >,
+
+    /// The error message, the reason for skipping,
+    /// or for conflicts, the action taken.
+    pub message: Option< String >,
+}
+
+impl Event
+{
+    pub fn new( kind: EventKind ) -> Self
+    {
+        return Self{
+            kind,
+            src_path: None,
+            dst_path: None,
+            hash: None,
+            size: None,
+            duration: None,
+            message: None,
+        };
+    }
+
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": self.kind.as_str(),
+            "src_path": self.src_path.as_ref().map( |path_| path_.to_string_lossy() ),
+            "dst_path": self.dst_path.as_ref().map( |path_| path_.to_string_lossy() ),
+            "hash": self.hash,
+            "size": self.size,
+            "duration": self.duration.map( |duration_| duration_.as_secs_f64() ),
+            "message": self.message,
+        } );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// The statistics of a run, accumulated from events.
+pub struct Stats
+{
+    /// The number of source files processed.
+    pub files: u64,
+
+    /// The total size of source files processed, in bytes.
+    pub bytes: u64,
+
+    /// The total size of files inserted into the reflink directory, in bytes.
+    pub stored_bytes: u64,
+
+    pub copied: u64,
+    pub reflinked: u64,
+    pub hardlinked: u64,
+    pub stored: u64,
+    pub skipped: u64,
+    pub renamed: u64,
+    pub conflicts: u64,
+    pub errors: u64,
+    pub mismatches: u64,
+    pub verified: u64,
+    pub hashed: u64,
+    pub removed: u64,
+}
+
+impl Stats
+{
+    pub fn to_json( &self ) -> serde_json::Value
+    {
+        return serde_json::json!( {
+            "event": "summary",
+            "files": self.files,
+            "bytes": self.bytes,
+            "stored_bytes": self.stored_bytes,
+            "copied": self.copied,
+            "reflinked": self.reflinked,
+            "hardlinked": self.hardlinked,
+            "stored": self.stored,
+            "skipped": self.skipped,
+            "renamed": self.renamed,
+            "conflicts": self.conflicts,
+            "errors": self.errors,
+            "mismatches": self.mismatches,
+            "verified": self.verified,
+            "hashed": self.hashed,
+            "removed": self.removed,
+        } );
+    }
+
+    fn record( &mut self, event: &Event )
+    {
+        match event.kind {
+            EventKind::Copied => { self.copied += 1; },
+            EventKind::Reflinked => { self.reflinked += 1; },
+            EventKind::Hardlinked => { self.hardlinked += 1; },
+            EventKind::Stored => {
+                self.stored += 1;
+                self.stored_bytes += event.size.unwrap_or( 0 );
+            },
+            EventKind::Skipped => { self.skipped += 1; },
+            EventKind::Renamed => { self.renamed += 1; },
+            EventKind::Conflict => { self.conflicts += 1; },
+            EventKind::Error => { self.errors += 1; },
+            EventKind::Mismatch => { self.mismatches += 1; },
+            EventKind::Verified => { self.verified += 1; },
+            EventKind::Hashed => { self.hashed += 1; },
+            EventKind::Removed => { self.removed += 1; },
+        }
+    }
+}
+
+#[ derive( Clone, Copy, Debug, Default ) ]
+/// The totals of source paths to be processed.
+pub struct Totals
+{
+    /// The number of source files.
+    pub files: u64,
+
+    /// The total size of source files, in bytes.
+    pub bytes: u64,
+}
+
+#[ derive( Clone, Copy, Debug ) ]
+/// The progress of a run.
+pub struct Progress
+{
+    /// The number of source files processed.
+    pub files: u64,
+
+    /// The total size of source files processed, in bytes.
+    pub bytes: u64,
+
+    /// The number of bytes of the current source file hashed so far.
+    pub current_bytes: u64,
+
+    /// The total size of files inserted into the reflink directory, in bytes.
+    pub stored_bytes: u64,
+
+    /// The totals, if known in advance.
+    pub totals: Option< Totals >,
+
+    /// The time elapsed since the start of the run.
+    pub elapsed: std::time::Duration,
+}
+
+impl Progress
+{
+    /// Returns the throughput, in bytes per second.
+    pub fn throughput( &self ) -> f64
+    {
+        let elapsed = self.elapsed.as_secs_f64();
+        if elapsed == 0.0 { return 0.0; }
+
+        return ( self.bytes + self.current_bytes ) as f64 / elapsed;
+    }
+
+    /// Returns the ratio of processed bytes to stored bytes, if any were stored.
+    pub fn dedup_ratio( &self ) -> Option< f64 >
+    {
+        if self.stored_bytes == 0 { return None; }
+
+        return Some( self.bytes as f64 / self.stored_bytes as f64 );
+    }
+
+    /// Returns the estimated time remaining, if the totals are known.
+    pub fn eta( &self ) -> Option< std::time::Duration >
+    {
+        let totals = self.totals?;
+        let throughput = self.throughput();
+        if throughput == 0.0 { return None; }
+
+        let remaining_bytes =
+                totals.bytes.saturating_sub( self.bytes + self.current_bytes );
+
+        return Some( std::time::Duration::from_secs_f64(
+                remaining_bytes as f64 / throughput ) );
+    }
+}
+
+#[ derive( Clone, Copy, Debug, PartialEq, Eq ) ]
+/// The decision of an observer on whether to copy a source path.
+pub enum CopyDecision
+{
+    /// Copy the source path.
+    Proceed,
+
+    /// Skip the source path (and, for directories, their contents).
+    Skip,
+
+    /// Stop the run with an error.
+    Abort,
+}
+
+/// An observer of the events and progress of a run.
+///
+/// All methods have empty default implementations,
+/// so that implementors need to implement only the methods of interest.
+pub trait CopyObserver
+{
+    fn on_event( &mut self, _event: &Event ) {}
+
+    /// Called after each source file and after each read while hashing.
+    fn on_progress( &mut self, _progress: &Progress ) {}
+
+    /// Called before copying a source directory, file, or symlink.
+    ///
+    /// The destination path is the intended path,
+    /// which may still change if it is in conflict.
+    fn before_copy(
+        &mut self,
+        _src_path: &std::path::Path,
+        _dst_path: &std::path::Path,
+        _src_metadata: &std::fs::Metadata,
+    ) -> CopyDecision
+    {
+        return CopyDecision::Proceed;
+    }
+
+    /// Called after creating a destination directory.
+    fn on_dir_created(
+        &mut self,
+        _src_path: &std::path::Path,
+        _dst_path: &std::path::Path,
+    ) {}
+
+    /// Called after inserting a source file into the reflink directory.
+    fn on_store_insert(
+        &mut self,
+        _src_path: &std::path::Path,
+        _reflink_path: &std::path::Path,
+        _hash: &str,
+    ) {}
+
+    /// Called after creating a destination file from the reflink directory,
+    /// with the link mode used.
+    fn on_reflinked(
+        &mut self,
+        _reflink_path: &std::path::Path,
+        _dst_path: &std::path::Path,
+        _link_mode: LinkMode,
+    ) {}
+
+    /// Called when a source path is skipped
+    /// because an equivalent destination path exists.
+    fn on_skipped_identical(
+        &mut self,
+        _src_path: &std::path::Path,
+        _dst_path: &std::path::Path,
+    ) {}
+
+    /// Called after renaming an existing path to its backup path.
+    fn on_backup_renamed(
+        &mut self,
+        _path: &std::path::Path,
+        _backup_path: &std::path::Path,
+    ) {}
+
+    /// Called when handling a source path fails.
+    fn on_error( &mut self, _path: &std::path::Path, _error: &std::io::Error ) {}
+}
+
+impl CopyObserver for () {}
+
+#[ derive( Clone, Debug, Default ) ]
+/// A token for cancelling a run from another thread or a signal handler.
+///
+/// A cancelled run stops between files and between hash buffer reads,
+/// and fails with a [`Cancelled`] error (see [`is_cancelled`]).
+pub struct CancelToken
+{
+    flag: std::sync::Arc< std::sync::atomic::AtomicBool >,
+}
+
+impl CancelToken
+{
+    pub fn new() -> Self
+    {
+        return Self::default();
+    }
+
+    pub fn cancel( &self )
+    {
+        self.flag.store( true, std::sync::atomic::Ordering::SeqCst );
+    }
+
+    pub fn is_cancelled( &self ) -> bool
+    {
+        return self.flag.load( std::sync::atomic::Ordering::SeqCst );
+    }
+
+    /// Returns the underlying flag, for example, for registering signal handlers.
+    pub fn flag( &self ) -> std::sync::Arc< std::sync::atomic::AtomicBool >
+    {
+        return self.flag.clone();
+    }
+}
+
+#[ derive( Debug ) ]
+/// The error payload of a cancelled run.
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled
+{
+    fn fmt( &self, f: &mut std::fmt::Formatter ) -> std::fmt::Result
+    {
+        return write!( f, "Cancelled" );
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// The state of a run: the statistics and the observer to report events to.
+pub struct Session< 'a >
+{
+    pub stats: Stats,
+
+    /// The totals, if known in advance, for progress reporting.
+    pub totals: Option< Totals >,
+
+    /// The token checked for cancellation between files and hash buffer reads.
+    pub cancel_token: CancelToken,
+
+    /// The manifest to remember the hashes of placed destination files in, if any,
+    /// for writing it once copying is done; see [`write_manifest`].
+    pub manifest: Option< ManifestWriter >,
+
+    observer: &'a mut dyn CopyObserver,
+    start_time: std::time::Instant,
+    current_bytes: u64,
+}
+
+impl< 'a > Session< 'a >
+{
+    pub fn new( observer: &'a mut dyn CopyObserver ) -> Self
+    {
+        return Self{
+            stats: Stats::default(),
+            totals: None,
+            cancel_token: CancelToken::new(),
+            manifest: None,
+            observer,
+            start_time: std::time::Instant::now(),
+            current_bytes: 0,
+        };
+    }
+
+    pub fn progress( &self ) -> Progress
+    {
+        return Progress{
+            files: self.stats.files,
+            bytes: self.stats.bytes,
+            current_bytes: self.current_bytes,
+            stored_bytes: self.stats.stored_bytes,
+            totals: self.totals,
+            elapsed: self.start_time.elapsed(),
+        };
+    }
+
+    /// Advances the progress of the current file.
+    pub fn advance( &mut self, bytes: u64 )
+    {
+        self.current_bytes += bytes;
+        let progress = self.progress();
+        self.observer.on_progress( &progress );
+    }
+
+    /// Finishes the current file, replacing its progress by its size.
+    pub fn finish_file( &mut self, bytes: u64 )
+    {
+        self.stats.files += 1;
+        self.stats.bytes += bytes;
+        self.current_bytes = 0;
+        let progress = self.progress();
+        self.observer.on_progress( &progress );
+    }
+
+    /// Finishes one of several files in progress at once,
+    /// replacing its progress by its size.
+    fn finish_concurrent_file( &mut self, bytes: u64, advanced_bytes: u64 )
+    {
+        self.current_bytes = self.current_bytes.saturating_sub( advanced_bytes );
+        self.stats.files += 1;
+        self.stats.bytes += bytes;
+        let progress = self.progress();
+        self.observer.on_progress( &progress );
+    }
+
+    /// Records the event in the statistics and reports it to the observer.
+    pub fn emit( &mut self, event: Event )
+    {
+        self.stats.record( &event );
+        self.observer.on_event( &event );
+    }
+
+    fn report_error< T, P >( &mut self, res: std::io::Result< T >, path: P )
+        -> std::io::Result< T >
+    where
+        P: AsRef< std::path::Path >,
+    {
+        if let Err( error_ ) = &res {
+            if is_cancelled( error_ ) { return res; }
+
+            self.observer.on_error( path.as_ref(), error_ );
+            self.emit( Event{
+                src_path: Some( path.as_ref().to_owned() ),
+                message: Some( error_.to_string() ),
+                ..Event::new( EventKind::Error )
+            } );
+        }
+
+        return res;
+    }
+
+    /// Fails with a [`Cancelled`] error if the run has been cancelled.
+    fn check_cancelled( &self ) -> std::io::Result< () >
+    {
+        if self.cancel_token.is_cancelled() {
+            log::warn!( "Cancelled; stopping." );
+
+            return Err( std::io::Error::other( Cancelled ) );
+        }
+
+        return Ok( () );
+    }
+
+    /// Consults the observer on whether to copy the source path.
+    ///
+    /// Returns `false` if the source path is to be skipped.
+    fn before_copy(
+        &mut self,
+        src_path: &std::path::Path,
+        dst_path: &std::path::Path,
+        src_metadata: &std::fs::Metadata,
+    ) -> std::io::Result< bool >
+    {
+        match self.observer.before_copy( src_path, dst_path, src_metadata ) {
+            CopyDecision::Proceed => { return Ok( true ); },
+            CopyDecision::Skip => {
+                let skip_msg = "Vetoed by observer";
+
+                log::info!( "{}; skipping: path: {:?}", skip_msg, src_path );
+
+                self.emit( Event{
+                    src_path: Some( src_path.to_owned() ),
+                    dst_path: Some( dst_path.to_owned() ),
+                    message: Some( skip_msg.to_owned() ),
+                    ..Event::new( EventKind::Skipped )
+                } );
+
+                return Ok( false );
+            },
+            CopyDecision::Abort => {
+                let error_msg =
+                        format!( "Aborted by observer: path: {:?}", src_path );
+
+                log::error!( "{}", error_msg );
+
+                return Err( std::io::Error::other( error_msg ) );
+            },
+        }
+    }
+}
+
+/// A message from a verifying thread.
+enum VerifyMessage
+{
+    /// Bytes of the indexed file were hashed.
+    Progress( usize, u64 ),
+
+    /// The indexed file was hashed.
+    Done( usize, std::io::Result< String >, std::time::Duration ),
+}
+
+/// The times reflink directory files were last verified,
+/// persisted as one JSON object per line.
+struct Checkpoint
+{
+    times: std::collections::HashMap< std::ffi::OsString, f64 >,
+    writer: std::io::LineWriter< std::fs::File >,
+}
+
+impl Checkpoint
+{
+    /// Opens the checkpoint, compacting it.
+    fn open( path: &std::path::Path ) -> std::io::Result< Self >
+    {
+        use std::io::{ BufRead, Write };
+
+        log::debug!( "Opening checkpoint: {:?}", path );
+
+        let mut times = std::collections::HashMap::< std::ffi::OsString, f64 >::new();
+        if optional_metadata( path )?.is_some() {
+            let file = std::fs::File::open( path )?;
+            for ( index_, line_res_ ) in std::io::BufReader::new( file ).lines().enumerate() {
+                let record = serde_json::from_str::< serde_json::Value >( &line_res_? )
+                    .ok()
+                    .and_then( |value_| {
+                        let hash = value_[ "hash" ].as_str()?.to_owned();
+                        let time = value_[ "time" ].as_f64()?;
+
+                        return Some( ( hash, time ) );
+                    } );
+                match record {
+                    Some( ( hash_, time_ ) ) => { times.insert( hash_.into(), time_ ); },
+                    None => {
+                        log::warn!(
+                            "Invalid checkpoint line; skipping: path: {:?}, line: {}",
+                            path,
+                            index_ + 1,
+                        );
+                    },
+                }
+            }
+        }
+
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push( ".tmp" );
+        let mut temp_writer =
+                std::io::BufWriter::new( std::fs::File::create( &temp_path )? );
+        for ( hash_, time_ ) in &times {
+            writeln!(
+                temp_writer,
+                "{}",
+                serde_json::json!( { "hash": hash_.to_string_lossy(), "time": time_ } ),
+            )?;
+        }
+        temp_writer.into_inner()?.sync_all()?;
+        std::fs::rename( &temp_path, path )?;
+        let parent_path = path.parent()
+            .filter( |parent_path_| !parent_path_.as_os_str().is_empty() )
+            .unwrap_or( std::path::Path::new( "." ) );
+        std::fs::File::open( parent_path )?.sync_all()?;
+
+        let file = std::fs::OpenOptions::new().append( true ).open( path )?;
+
+        return Ok( Self{ times, writer: std::io::LineWriter::new( file ) } );
+    }
+
+    fn verified_within( &self, hash: &std::ffi::OsStr, interval: std::time::Duration )
+        -> bool
+    {
+        let now = unix_time( std::time::SystemTime::now() );
+
+        return self.times.get( hash )
+            .is_some_and( |time_| now - time_ < interval.as_secs_f64() );
+    }
+
+    fn record( &mut self, hash: &std::ffi::OsStr ) -> std::io::Result< () >
+    {
+        use std::io::Write;
+
+        let time = unix_time( std::time::SystemTime::now() );
+        self.times.insert( hash.to_owned(), time );
+
+        return writeln!(
+            self.writer,
+            "{}",
+            serde_json::json!( { "hash": hash.to_string_lossy(), "time": time } ),
+        );
+    }
+}
+
+#[ repr( C ) ]
+#[ derive( Clone, Copy, Default ) ]
+/// The kernel `struct fiemap_extent`.
+struct FiemapExtent
+{
+    fe_logical: u64,
+    fe_physical: u64,
+    fe_length: u64,
+    fe_reserved64: [ u64; 2 ],
+    fe_flags: u32,
+    fe_reserved: [ u32; 3 ],
+}
+
+#[ repr( C ) ]
+/// The kernel `struct fiemap`, with room for a batch of extents.
+struct FiemapRequest
+{
+    fm_start: u64,
+    fm_length: u64,
+    fm_flags: u32,
+    fm_mapped_extents: u32,
+    fm_extent_count: u32,
+    fm_reserved: u32,
+    fm_extents: [ FiemapExtent; FIEMAP_BATCH_LENGTH ],
+}
+
+/// The incremental state of a hash calculation.
+enum Hasher
+{
+    Blake2bp( Box< blake2b_simd::blake2bp::State > ),
+    Blake2b( blake2b_simd::State ),
+    Sha256( sha2::Sha256 ),
+}
+
+impl Hasher
+{
+    fn new( algorithm: HashAlgorithm ) -> Self
+    {
+        use sha2::Digest;
+
+        return match algorithm {
+            HashAlgorithm::Blake2bp => {
+                Hasher::Blake2bp( Box::new( blake2b_simd::blake2bp::Params::new()
+                        .hash_length( HASH_LENGTH )
+                        .to_state() ) )
+            },
+            HashAlgorithm::Blake2b => {
+                Hasher::Blake2b( blake2b_simd::Params::new().to_state() )
+            },
+            HashAlgorithm::Sha256 => { Hasher::Sha256( sha2::Sha256::new() ) },
+        };
+    }
+
+    fn update( &mut self, bytes: &[ u8 ] )
+    {
+        use sha2::Digest;
+
+        match self {
+            Hasher::Blake2bp( state_ ) => { state_.update( bytes ); },
+            Hasher::Blake2b( state_ ) => { state_.update( bytes ); },
+            Hasher::Sha256( state_ ) => { state_.update( bytes ); },
+        }
+    }
+
+    fn finalize( self ) -> String
+    {
+        use sha2::Digest;
+
+        return match self {
+            Hasher::Blake2bp( state_ ) => {
+                state_.finalize().to_hex().as_str().to_owned()
+            },
+            Hasher::Blake2b( state_ ) => {
+                state_.finalize().to_hex().as_str().to_owned()
+            },
+            Hasher::Sha256( state_ ) => { format!( "{:x}", state_.finalize() ) },
+        };
+    }
+}
+
+/// The directories that a source walk must not enter.
+struct WalkGuard
+{
+    /// The device and inode numbers of the excluded directories.
+    excluded: Vec< ( u64, u64 ) >,
+}
+
+impl WalkGuard
+{
+    fn new( dirs: &[ &std::path::Path ] ) -> std::io::Result< Self >
+    {
+        let mut excluded = Vec::new();
+        for dir_ in dirs {
+            excluded.push( dir_id( &dir_.metadata()? ) );
+        }
+
+        return Ok( Self{ excluded } );
+    }
+
+    fn excludes( &self, metadata: &std::fs::Metadata ) -> bool
+    {
+        return self.excluded.contains( &dir_id( metadata ) );
+    }
+}
+
+/// The outcome of resolving a conflict.
+enum Resolution
+{
+    /// Place the source path at the given path.
+    Place( std::path::PathBuf ),
+
+    /// Keep the destination path and do not place the source path.
+    Keep,
+}
+
+#[ derive( Default ) ]
+/// A set of byte ranges, kept as disjoint ranges by start.
+struct RangeSet
+{
+    /// The end of each range, by its start.
+    ranges: std::collections::BTreeMap< u64, u64 >,
+}
+
+impl RangeSet
+{
+    /// Adds the range from `start` to `end`,
+    /// returning the number of bytes not already in the set.
+    fn insert( &mut self, start: u64, end: u64 ) -> u64
+    {
+        if end <= start { return 0; }
+
+        // The ranges touching the new range; they end in order, since they are disjoint.
+        let touching = self.ranges.range( ..=end ).rev()
+            .take_while( |( _, end_ )| **end_ >= start )
+            .map( |( start_, end_ )| ( *start_, *end_ ) )
+            .collect::< Vec< _ > >();
+
+        let mut covered = 0;
+        let ( mut merged_start, mut merged_end ) = ( start, end );
+        for ( start_, end_ ) in touching {
+            covered += end_.min( end ).saturating_sub( start_.max( start ) );
+            merged_start = merged_start.min( start_ );
+            merged_end = merged_end.max( end_ );
+            self.ranges.remove( &start_ );
+        }
+        self.ranges.insert( merged_start, merged_end );
+
+        return end - start - covered;
+    }
+}
+
+
+
+// PUBLIC FUNCTIONS
+
+pub fn cpdd< P, Q, R >(
+    src_path: P,
+    dst_dir: Q,
+    reflink_dir: R,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `cpdd`." );
+
+    let src_path = src_path.as_ref();
+    let dst_dir = dst_dir.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    check_paths( &[ src_path ], dst_dir, reflink_dir, options.recurse_dirs )?;
+
+    let mut options = std::borrow::Cow::Borrowed( options );
+    if options.link_mode == LinkMode::Auto {
+        options.to_mut().link_mode = detect_link_mode( reflink_dir, dst_dir )?;
+    }
+
+    let guard = WalkGuard::new( &[ dst_dir, reflink_dir ] )?;
+    cpdd_path( src_path, dst_dir, reflink_dir, &options, &guard, session )?;
+
+    log::trace!( "End `cpdd`." );
+
+    return Ok( () );
+}
+
+/// Inserts the files of the source path into the reflink directory,
+/// without placing them anywhere else.
+///
+/// Source directories are always recursed; symlinks and other file types are ignored.
+/// If the session has a manifest, each source path is recorded in it
+/// under the relative path that copying it would place it at.
+pub fn store_add< P, Q >(
+    src_path: P,
+    reflink_dir: Q,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `store_add`." );
+
+    let src_path = src_path.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    let canonical_reflink_dir = reflink_dir.canonicalize()?;
+    check_src_paths( &[ src_path ], &[ ( "reflink", &canonical_reflink_dir ) ] )?;
+
+    let rel_path = src_path.file_name().ok_or_else( || {
+        let error_msg = format!( "Invalid source path: {:?}", src_path );
+
+        log::error!( "{}", error_msg );
+
+        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
+    } )?;
+
+    let guard = WalkGuard::new( &[ reflink_dir ] )?;
+    store_path( src_path, rel_path.as_ref(), reflink_dir, options, &guard, session )?;
+
+    log::trace!( "End `store_add`." );
+
+    return Ok( () );
+}
+
+pub fn check_paths< P, Q, R >(
+    src_paths: &[ P ],
+    dst_dir: Q,
+    reflink_dir: R,
+    recurse_dirs: bool,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `check_paths`." );
+
+    let dst_dir = dst_dir.as_ref().canonicalize()?;
+    let reflink_dir = reflink_dir.as_ref().canonicalize()?;
+
+    log::debug!(
+        "Checking paths: \
+            dst_dir: {:?}, \
+            reflink_dir: {:?}",
+        dst_dir,
+        reflink_dir,
+    );
+
+    if dst_dir.starts_with( &reflink_dir ) {
+        let error_msg = format!(
+            "Invalid destination directory: inside reflink directory: \
+                dst_dir: {:?}, \
+                reflink_dir: {:?}",
+            dst_dir,
+            reflink_dir,
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
+
+        return Err( error );
+    }
+
+    if !recurse_dirs {
+        log::trace!( "End `check_paths`." );
+
+        return Ok( () );
+    }
+
+    check_src_paths(
+            src_paths, &[ ( "destination", &dst_dir ), ( "reflink", &reflink_dir ) ] )?;
+
+    log::trace!( "End `check_paths`." );
+
+    return Ok( () );
+}
+
+pub fn preflight_check< P, Q >( reflink_dir: P, dst_dir: Q )
+    -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `preflight_check`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+    let dst_dir = dst_dir.as_ref();
+
+    log::debug!(
+        "Checking reflink support: \
+            reflink_dir: {:?}, \
+            dst_dir: {:?}",
+        reflink_dir,
+        dst_dir,
+    );
+
+    // Only a test reflink is conclusive, since paths in different Btrfs subvolumes
+    // differ in both device and file system id but can still be reflinked.
+    if !probe_reflink( reflink_dir, dst_dir )? {
+        let reason =
+                if is_same_file_system( reflink_dir, dst_dir )? {
+                    "file system does not support reflinking"
+                }
+                else {
+                    "reflink and destination directories reside in different file systems"
+                };
+        let error_msg = format!(
+            "Pre-flight check failed: {}: \
+                reflink_dir: {:?}, \
+                dst_dir: {:?}",
+            reason,
+            reflink_dir,
+            dst_dir,
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::other( error_msg );
+
+        return Err( error );
+    }
+
+    log::debug!( "Pre-flight check succeeded." );
+
+    log::trace!( "End `preflight_check`." );
+
+    return Ok( () );
+}
+
+pub fn detect_link_mode< P, Q >( reflink_dir: P, dst_dir: Q )
+    -> std::io::Result< LinkMode >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    use std::os::unix::fs::MetadataExt;
+
+    log::trace!( "Begin `detect_link_mode`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+    let dst_dir = dst_dir.as_ref();
+
+    let link_mode =
+            // Reflinks may work across devices, such as Btrfs subvolumes.
+            if probe_reflink( reflink_dir, dst_dir )? { LinkMode::Reflink }
+            // Hardlinks require the same device, unlike reflinks.
+            else if reflink_dir.metadata()?.dev() == dst_dir.metadata()?.dev() {
+                LinkMode::Hardlink
+            }
+            else { LinkMode::Copy };
+
+    log::info!( "Detected link mode: {:?}", link_mode );
+
+    log::trace!( "End `detect_link_mode`." );
+
+    return Ok( link_mode );
+}
+
+pub fn scan_totals< P, Q, R >(
+    src_paths: &[ P ],
+    dst_dir: Q,
+    reflink_dir: R,
+    options: &CpddOptions,
+) -> std::io::Result< Totals >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `scan_totals`." );
+
+    log::info!( "Scanning source paths." );
+
+    let guard = WalkGuard::new( &[ dst_dir.as_ref(), reflink_dir.as_ref() ] )?;
+    let mut totals = Totals::default();
+    for src_path_ in src_paths {
+        scan_path( src_path_, options, &guard, &mut totals )?;
+    }
+
+    log::info!(
+        "Source totals: files: {:?}, bytes: {:?}",
+        totals.files,
+        totals.bytes,
+    );
+
+    log::trace!( "End `scan_totals`." );
+
+    return Ok( totals );
+}
+
+pub fn plan_copy< P, Q, R >(
+    src_paths: &[ P ],
+    dst_dir: Q,
+    reflink_dir: R,
+    options: &CpddOptions,
+) -> std::io::Result< CopyPlan >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `plan_copy`." );
+
+    let dst_dir = dst_dir.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    log::info!( "Planning copy." );
+
+    let guard = WalkGuard::new( &[ dst_dir, reflink_dir ] )?;
+    let mut plan = CopyPlan::default();
+    let mut hashes = std::collections::HashSet::new();
+    for src_path_ in src_paths {
+        let src_path = src_path_.as_ref();
+
+        // Store files are reflinked if possible, which requires no space.
+        let reflinkable = options.link_mode == LinkMode::Reflink
+                && is_same_file_system( src_path, reflink_dir )?;
+
+        plan_path(
+            src_path,
+            reflink_dir,
+            reflinkable,
+            options,
+            &guard,
+            &mut plan,
+            &mut hashes,
+        )?;
+    }
+
+    log::info!(
+        "Copy plan: \
+            file_count: {:?}, \
+            total_bytes: {:?}, \
+            new_bytes: {:?}, \
+            reflink_dir_bytes: {:?}, \
+            dst_dir_bytes: {:?}",
+        plan.file_count,
+        plan.total_bytes,
+        plan.new_bytes,
+        plan.reflink_dir_bytes,
+        plan.dst_dir_bytes,
+    );
+
+    log::trace!( "End `plan_copy`." );
+
+    return Ok( plan );
+}
+
+pub fn check_free_space< P, Q >(
+    plan: &CopyPlan,
+    dst_dir: P,
+    reflink_dir: Q,
+    min_free_bytes: u64,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `check_free_space`." );
+
+    let dst_dir = dst_dir.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    if is_same_file_system( dst_dir, reflink_dir )? {
+        ensure_free_space(
+            dst_dir,
+            plan.reflink_dir_bytes + plan.dst_dir_bytes,
+            min_free_bytes,
+        )?;
+    }
+    else {
+        ensure_free_space( reflink_dir, plan.reflink_dir_bytes, min_free_bytes )?;
+        ensure_free_space( dst_dir, plan.dst_dir_bytes, min_free_bytes )?;
+    }
+
+    log::trace!( "End `check_free_space`." );
+
+    return Ok( () );
+}
+
+pub fn verify_reflink_dir< P >( path: P, session: &mut Session )
+    -> std::io::Result< Vec< std::path::PathBuf > >
+where
+    P: AsRef< std::path::Path >,
+{
+    let report = verify_reflink_dir_with( path, &VerifyOptions::default(), session )?;
+
+    return Ok( report.mismatches );
+}
+
+/// Verifies the reflink directory, classifying its entries.
+///
+/// Only files with well-formed names are hashed;
+/// other entries and unreadable files are reported, not failed on.
+/// The files are hashed in parallel according to the options,
+/// and the totals of the files to verify are set in the session.
+pub fn verify_reflink_dir_with< P >(
+    path: P,
+    options: &VerifyOptions,
+    session: &mut Session,
+) -> std::io::Result< VerifyReport >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `verify_reflink_dir_with`." );
+
+    let path = path.as_ref();
+
+    log::debug!( "Verifying reflink directory: {:?}, options: {:?}", path, options );
+
+    let mut checkpoint = match &options.checkpoint_path {
+        Some( checkpoint_path_ ) => { Some( Checkpoint::open( checkpoint_path_ )? ) },
+        None => { None },
+    };
+
+    let empty_hash = calc_hash( b"" );
+    let mut report = VerifyReport::default();
+    let mut reflink_entries = Vec::new();
+    for reflink_entry_res_ in std::fs::read_dir( path )? {
+        let reflink_entry = reflink_entry_res_?;
+        let reflink_path = reflink_entry.path();
+        let reflink_name = reflink_entry.file_name();
+
+        session.check_cancelled()?;
+
+        if RESERVED_DIR_NAMES.iter().any( |name_| reflink_name == *name_ )
+                && reflink_entry.file_type()?.is_dir() {
+            log::debug!( "Skipping reserved directory: {:?}", reflink_path );
+
+            continue;
+        }
+
+        let metadata = reflink_entry.metadata()?;
+        let invalid_msg =
+                if !metadata.is_file() {
+                    report.unexpected_types.push( reflink_path.clone() );
+
+                    Some( "Unexpected file type: not a file" )
+                }
+                else if !is_reflink_name( &reflink_name ) {
+                    report.unexpected_names.push( reflink_path.clone() );
+
+                    Some( "Unexpected name: not a hash" )
+                }
+                else { None };
+        if let Some( invalid_msg_ ) = invalid_msg {
+            log::warn!( "{}: {:?}", invalid_msg_, reflink_path );
+
+            session.emit( Event{
+                src_path: Some( reflink_path ),
+                message: Some( invalid_msg_.to_owned() ),
+                ..Event::new( EventKind::Skipped )
+            } );
+
+            continue;
+        }
+
+        if metadata.len() == 0 && reflink_name != empty_hash.as_str() {
+            let mismatch_msg = "Zero-length file";
+
+            log::warn!( "Hash mismatch: {}: {:?}", mismatch_msg, reflink_path );
+
+            session.emit( Event{
+                src_path: Some( reflink_path.clone() ),
+                size: Some( 0 ),
+                hash: Some( empty_hash.clone() ),
+                message: Some( mismatch_msg.to_owned() ),
+                ..Event::new( EventKind::Mismatch )
+            } );
+            report.truncated.push( reflink_path );
+
+            continue;
+        }
+
+        if let ( Some( checkpoint_ ), Some( since_ ) ) = ( &checkpoint, options.since ) {
+            if checkpoint_.verified_within( &reflink_name, since_ ) {
+                let skip_msg = "Recently verified";
+
+                log::debug!( "{}; skipping: {:?}", skip_msg, reflink_path );
+
+                session.emit( Event{
+                    src_path: Some( reflink_path ),
+                    message: Some( skip_msg.to_owned() ),
+                    ..Event::new( EventKind::Skipped )
+                } );
+
+                continue;
+            }
+        }
+
+        reflink_entries.push( ( reflink_path, reflink_name, metadata.len() ) );
+    }
+    reflink_entries.sort();
+
+    session.totals = Some( Totals{
+        files: reflink_entries.len() as u64,
+        bytes: reflink_entries.iter().map( |( _, _, size_ )| size_ ).sum(),
+    } );
+
+    let jobs = match options.jobs {
+        0 => { std::thread::available_parallelism().map_or( 1, |jobs_| jobs_.get() ) },
+
+        _ => { options.jobs },
+    };
+
+    log::debug!( "Hashing files: count: {}, jobs: {}", reflink_entries.len(), jobs );
+
+    let next_index = std::sync::atomic::AtomicUsize::new( 0 );
+    std::thread::scope( |scope_| -> std::io::Result< () > {
+        let ( sender, receiver ) = std::sync::mpsc::channel();
+        for _ in 0..jobs.min( reflink_entries.len() ) {
+            let sender = sender.clone();
+            let cancel_token = session.cancel_token.clone();
+            let reflink_entries = &reflink_entries;
+            let next_index = &next_index;
+            scope_.spawn( move || {
+                loop {
+                    let index = next_index.fetch_add(
+                            1, std::sync::atomic::Ordering::SeqCst );
+                    let ( reflink_path, _, _ ) = match reflink_entries.get( index ) {
+                        Some( reflink_entry_ ) => { reflink_entry_ },
+                        None => { break; },
+                    };
+
+                    log::info!( "Verifying: {:?}", reflink_path );
+
+                    let start_time = std::time::Instant::now();
+                    let hash_res = calc_file_hash_with( reflink_path, |count_| {
+                        // The receiver is dropped when verifying stops early.
+                        let sent = sender.send( VerifyMessage::Progress( index, count_ ) );
+                        if sent.is_err() || cancel_token.is_cancelled() {
+                            return Err( std::io::Error::other( Cancelled ) );
+                        }
+
+                        return Ok( () );
+                    } );
+                    let message = VerifyMessage::Done( index, hash_res, start_time.elapsed() );
+                    if sender.send( message ).is_err() { break; }
+                }
+            } );
+        }
+        drop( sender );
+
+        // Events are emitted on this thread, as results arrive.
+        let mut advanced_bytes = std::collections::HashMap::new();
+        for message_ in receiver {
+            match message_ {
+                VerifyMessage::Progress( index_, count_ ) => {
+                    *advanced_bytes.entry( index_ ).or_insert( 0 ) += count_;
+                    session.advance( count_ );
+                },
+                VerifyMessage::Done( index_, hash_res_, duration_ ) => {
+                    let ( reflink_path, reflink_name, size ) = &reflink_entries[ index_ ];
+
+                    session.check_cancelled()?;
+                    session.finish_concurrent_file(
+                            *size, advanced_bytes.remove( &index_ ).unwrap_or( 0 ) );
+                    let hash = match session.report_error( hash_res_, reflink_path ) {
+                        Ok( hash_ ) => { hash_ },
+                        Err( error_ ) => {
+                            if is_cancelled( &error_ ) { return Err( error_ ); }
+
+                            report.unreadable.push( reflink_path.clone() );
+
+                            continue;
+                        },
+                    };
+
+                    let event = Event{
+                        src_path: Some( reflink_path.clone() ),
+                        hash: Some( hash.clone() ),
+                        size: Some( *size ),
+                        duration: Some( duration_ ),
+                        ..Event::new( EventKind::Verified )
+                    };
+
+                    if hash.as_str() != reflink_name {
+                        log::warn!(
+                            "Hash mismatch: file name differs from hash: \
+                                path: {:?}, \
+                                name: {:?}, \
+                                hash: {:?}",
+                            reflink_path,
+                            reflink_name,
+                            hash,
+                        );
+
+                        session.emit( Event{ kind: EventKind::Mismatch, ..event } );
+
+                        report.mismatches.push( reflink_path.clone() );
+                    }
+                    else {
+                        report.valid += 1;
+
+                        if let Some( checkpoint_ ) = &mut checkpoint {
+                            let record_res = checkpoint_.record( reflink_name );
+                            session.report_error( record_res, reflink_path )?;
+                        }
+
+                        session.emit( event );
+                    }
+                },
+            }
+        }
+
+        return Ok( () );
+    } )?;
+    report.mismatches.sort();
+    report.truncated.sort();
+    report.unexpected_names.sort();
+    report.unexpected_types.sort();
+    report.unreadable.sort();
+
+    log::trace!( "End `verify_reflink_dir_with`." );
+
+    return Ok( report );
+}
+
+/// Moves the reflink directory file to the `quarantine` subdirectory,
+/// returning the quarantine path.
+///
+/// If the quarantine path exists, the current UTC time is appended to it.
+pub fn quarantine_reflink_file< P, Q >( reflink_dir: P, path: Q, session: &mut Session )
+    -> std::io::Result< std::path::PathBuf >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `quarantine_reflink_file`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+    let path = path.as_ref();
+
+    let quarantine_dir = reflink_dir.join( QUARANTINE_DIR_NAME );
+    if optional_metadata( &quarantine_dir )?.is_none() {
+        log::info!( "Quarantine directory not found; creating." );
+
+        std::fs::create_dir( &quarantine_dir )?;
+        std::fs::File::open( reflink_dir )?.sync_all()?;
+    }
+
+    let name = path.file_name().ok_or_else( || {
+        let error_msg = format!( "Invalid reflink file path: {:?}", path );
+
+        log::error!( "{}", error_msg );
+
+        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
+    } )?;
+    let mut quarantine_path = quarantine_dir.join( name );
+    if optional_metadata( &quarantine_path )?.is_some() {
+        quarantine_path = free_backup_path( &quarantine_path, &backup_timestamp() )?;
+    }
+
+    log::info!( "Quarantining: {:?} -> {:?}", path, quarantine_path );
+
+    std::fs::rename( path, &quarantine_path )?;
+    std::fs::File::open( &quarantine_dir )?.sync_all()?;
+    std::fs::File::open( reflink_dir )?.sync_all()?;
+
+    session.emit( Event{
+        src_path: Some( path.to_owned() ),
+        dst_path: Some( quarantine_path.clone() ),
+        message: Some( "Quarantined".to_owned() ),
+        ..Event::new( EventKind::Renamed )
+    } );
+
+    log::trace!( "End `quarantine_reflink_file`." );
+
+    return Ok( quarantine_path );
+}
+
+/// Restores the reflink directory files with the given hashes
+/// from matching files found in the given trees, returning the repaired hashes.
+///
+/// Files of the same size as the corrupt reflink directory files are hashed first.
+/// Each repaired file is placed atomically, after validating its hash.
+pub fn repair_reflink_files< P, Q >(
+    reflink_dir: P,
+    hashes: &[ String ],
+    from_paths: &[ Q ],
+    session: &mut Session,
+) -> std::io::Result< Vec< String > >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `repair_reflink_files`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+
+    log::debug!( "Repairing reflink directory files: {:?}", hashes );
+
+    let mut wanted = std::collections::HashSet::new();
+    let mut wanted_sizes = std::collections::HashSet::new();
+    for hash_ in hashes {
+        wanted.insert( hash_.as_str() );
+        if let Some( metadata_ ) = optional_metadata( reflink_dir.join( hash_ ) )? {
+            wanted_sizes.insert( metadata_.len() );
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for from_path_ in from_paths {
+        collect_files( from_path_.as_ref(), &mut candidates, session )?;
+    }
+    // Corruption usually preserves the size.
+    candidates.sort_by_key( |( _, size_ )| !wanted_sizes.contains( size_ ) );
+
+    let mut repaired = Vec::new();
+    for ( path_, size_ ) in candidates {
+        if wanted.is_empty() { break; }
+
+        session.check_cancelled()?;
+
+        let hash_res = track_file_digest( &path_, HashAlgorithm::Blake2bp, size_, session );
+        let hash = match session.report_error( hash_res, &path_ ) {
+            Ok( hash_ ) => { hash_ },
+            Err( error_ ) => {
+                if is_cancelled( &error_ ) { return Err( error_ ); }
+
+                continue;
+            },
+        };
+        if !wanted.contains( hash.as_str() ) { continue; }
+
+        let start_time = std::time::Instant::now();
+        let reflink_path = reflink_dir.join( &hash );
+
+        log::info!( "Repairing: {:?} -> {:?}", path_, reflink_path );
+
+        let replace_res = replace_reflink_file( &path_, &reflink_path, &hash );
+        if session.report_error( replace_res, &reflink_path ).is_err() { continue; }
+
+        session.emit( Event{
+            src_path: Some( path_ ),
+            dst_path: Some( reflink_path ),
+            hash: Some( hash.clone() ),
+            size: Some( size_ ),
+            duration: Some( start_time.elapsed() ),
+            message: Some( "Repaired".to_owned() ),
+            ..Event::new( EventKind::Stored )
+        } );
+
+        wanted.remove( hash.as_str() );
+        repaired.push( hash );
+    }
+
+    for hash_ in &wanted {
+        log::warn!( "No matching file found; not repaired: {:?}", hash_ );
+    }
+
+    log::trace!( "End `repair_reflink_files`." );
+
+    return Ok( repaired );
+}
+
+/// Removes the reflink directory entries that do not belong there,
+/// as classified by verification, including the contents of directories,
+/// returning the removed paths.
+///
+/// Temporary files of pre-flight checks and repairs are kept
+/// unless modified more than a day ago, since another run may be using them.
+pub fn clean_foreign_entries( report: &VerifyReport, session: &mut Session )
+    -> std::io::Result< Vec< std::path::PathBuf > >
+{
+    log::trace!( "Begin `clean_foreign_entries`." );
+
+    let mut removed = Vec::new();
+    for path_ in report.foreign() {
+        session.check_cancelled()?;
+
+        let metadata = session.report_error( path_.symlink_metadata(), path_ )?;
+        let is_temp = path_.file_name()
+            .and_then( |name_| name_.to_str() )
+            .is_some_and( |name_| {
+                TEMP_PREFIXES.iter().any( |prefix_| name_.starts_with( prefix_ ) )
+            } );
+        // A modification time in the future counts as recent.
+        let is_recent = metadata.modified()?.elapsed()
+            .map_or( true, |age_| age_ < TEMP_MAX_AGE );
+        if is_temp && is_recent {
+            log::info!( "Keeping recent temporary entry: {:?}", path_ );
+
+            continue;
+        }
+
+        log::info!( "Removing foreign entry: {:?}", path_ );
+
+        let remove_res =
+                if metadata.is_dir() { std::fs::remove_dir_all( path_ ) }
+                else { std::fs::remove_file( path_ ) };
+        session.report_error( remove_res, path_ )?;
+
+        session.emit( Event{
+            src_path: Some( path_.clone() ),
+            size: Some( metadata.len() ),
+            message: Some( "Foreign entry".to_owned() ),
+            ..Event::new( EventKind::Removed )
+        } );
+        removed.push( path_.clone() );
+    }
+
+    log::trace!( "End `clean_foreign_entries`." );
+
+    return Ok( removed );
+}
+
+/// Returns the extents of the file, as reported by FIEMAP.
+pub fn file_extents< P >( path: P ) -> std::io::Result< Vec< Extent > >
+where
+    P: AsRef< std::path::Path >,
+{
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open( path )?;
+
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut request = FiemapRequest{
+            fm_start: start,
+            fm_length: u64::MAX - start,
+            fm_flags: FIEMAP_FLAG_SYNC,
+            fm_mapped_extents: 0,
+            fm_extent_count: FIEMAP_BATCH_LENGTH as u32,
+            fm_reserved: 0,
+            fm_extents: [ FiemapExtent::default(); FIEMAP_BATCH_LENGTH ],
+        };
+        // SAFETY: `request` is a `struct fiemap` with room for `fm_extent_count` extents.
+        let request_res = unsafe {
+            libc::ioctl( file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut request )
+        };
+        if request_res != 0 { return Err( std::io::Error::last_os_error() ); }
+
+        let mapped_extents = &request.fm_extents[ ..request.fm_mapped_extents as usize ];
+        for extent_ in mapped_extents {
+            extents.push( Extent{
+                logical: extent_.fe_logical,
+                physical: extent_.fe_physical,
+                length: extent_.fe_length,
+                flags: extent_.fe_flags,
+            } );
+        }
+
+        match mapped_extents.last() {
+            Some( extent_ ) if extent_.fe_flags & FIEMAP_EXTENT_LAST == 0 => {
+                start = extent_.fe_logical + extent_.fe_length;
+            },
+
+            _ => { break; },
+        }
+    }
+
+    return Ok( extents );
+}
+
+/// Checks that the destination files share physical extents
+/// with their reflink directory files, calling `on_entry` for each file.
+///
+/// Directories are recursed; symlinks and other file types are ignored.
+/// If `relink` is set, files with matching content that do not fully share extents
+/// are atomically replaced by reflinks of their reflink directory files,
+/// keeping their permissions and times;
+/// files with multiple hardlinks are not replaced.
+/// Failing files are reported as errors and skipped.
+pub fn check_sharing< P, Q, F >(
+    dst_paths: &[ P ],
+    reflink_dir: Q,
+    relink: bool,
+    session: &mut Session,
+    mut on_entry: F,
+) -> std::io::Result< SharingStats >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    F: FnMut( &SharingEntry ),
+{
+    log::trace!( "Begin `check_sharing`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+
+    let mut files = Vec::new();
+    for dst_path_ in dst_paths {
+        collect_files( dst_path_.as_ref(), &mut files, session )?;
+    }
+
+    let mut stats = SharingStats::default();
+    for ( path_, size_ ) in files {
+        session.check_cancelled()?;
+
+        log::info!( "Checking sharing: {:?}", path_ );
+
+        let entry_res = check_file_sharing( &path_, size_, reflink_dir, relink, session );
+        match session.report_error( entry_res, &path_ ) {
+            Ok( entry_ ) => {
+                stats.record( &entry_ );
+                on_entry( &entry_ );
+            },
+            Err( error_ ) => {
+                if is_cancelled( &error_ ) { return Err( error_ ); }
+            },
+        }
+    }
+
+    log::trace!( "End `check_sharing`." );
+
+    return Ok( stats );
+}
+
+/// Calculates the space used by the paths, as reported by FIEMAP,
+/// calling `on_entry` for each path and each directory below it, contents first.
+///
+/// Files with multiple hardlinks are counted once,
+/// and physical extents shared by several files are counted for the first of them,
+/// so that the totals do not exceed the space actually used.
+/// Extents without a known physical location, such as inline data, are not counted.
+/// Symlinks and other file types are ignored.
+/// Failing paths are reported as errors and skipped.
+pub fn calc_space_usage< P, F >( paths: &[ P ], session: &mut Session, mut on_entry: F )
+    -> std::io::Result< SpaceUsage >
+where
+    P: AsRef< std::path::Path >,
+    F: FnMut( &UsageEntry ),
+{
+    log::trace!( "Begin `calc_space_usage`." );
+
+    let mut inodes = std::collections::HashSet::new();
+    let mut ranges = std::collections::HashMap::new();
+    let mut usage = SpaceUsage::default();
+    for path_ in paths {
+        let path_ = path_.as_ref();
+        let metadata = match session.report_error( path_.symlink_metadata(), path_ ) {
+            Ok( metadata_ ) => { metadata_ },
+            Err( error_ ) => {
+                if is_cancelled( &error_ ) { return Err( error_ ); }
+
+                continue;
+            },
+        };
+
+        let path_usage = usage_path(
+                path_, &metadata, 0, &mut inodes, &mut ranges, session, &mut on_entry )?;
+        usage.merge( &path_usage );
+    }
+
+    log::trace!( "End `calc_space_usage`." );
+
+    return Ok( usage );
+}
+
+/// Returns whether the error is a [`Cancelled`] error.
+pub fn is_cancelled( error: &std::io::Error ) -> bool
+{
+    return error.get_ref().is_some_and( |error_| error_.is::< Cancelled >() );
+}
+
+pub fn calc_file_hash< P >( path: P ) -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+{
+    return calc_file_hash_with( path, |_| Ok( () ) );
+}
+
+/// Calculates the file hash, calling `on_read` with the byte count of each read.
+///
+/// An error returned by `on_read` stops the calculation.
+pub fn calc_file_hash_with< P, F >( path: P, on_read: F )
+    -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+    F: FnMut( u64 ) -> std::io::Result< () >,
+{
+    return calc_file_digest( path, HashAlgorithm::Blake2bp, on_read );
+}
+
+/// Calculates the file hash using the given algorithm,
+/// calling `on_read` with the byte count of each read.
+///
+/// An error returned by `on_read` stops the calculation.
+pub fn calc_file_digest< P, F >(
+    path: P,
+    algorithm: HashAlgorithm,
+    mut on_read: F,
+) -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+    F: FnMut( u64 ) -> std::io::Result< () >,
+{
+    use std::io::Read;
+
+    log::trace!( "Begin `calc_file_digest`." );
+
+    let path = path.as_ref();
+
+    log::debug!( "Calculating file hash: {:?}, algorithm: {:?}", path, algorithm );
+
+    let mut file = std::fs::File::open( path )?;  // FIX?: refactor read into fn
+    let mut buffer = vec![ 0; BUFFER_LENGTH ];
+    let mut state = Hasher::new( algorithm );
+    loop {
+        match file.read( &mut buffer ) {
+            Ok( 0 ) => { break; },
+            Ok( count_ ) => {
+                state.update( &buffer[ ..count_ ] );
+                on_read( count_ as u64 )?;
+            },
+            Err( error_ ) => {
+                match error_.kind() {
+                    std::io::ErrorKind::Interrupted => { continue; },
+
+                    _ => { return Err( error_ ); },
+                }
+            },
+        }
+    }
+    let hash = state.finalize();
+
+    log::debug!( "File hash: {:?}", hash );
+
+    log::trace!( "End `calc_file_digest`." );
+
+    return Ok( hash );
+}
+
+/// Calculates the hash of a file or, if `recurse_dirs` is set,
+/// the digest of a directory tree (see [`calc_tree_digest`]).
+///
+/// Each file hashed is reported as a `Hashed` event,
+/// followed, for a directory, by one for the digest of the whole tree,
+/// whose path is reported with a trailing slash, as in hash listings;
+/// the digests of subdirectories are not reported.
+/// Symlinks are followed.
+pub fn hash_path< P >(
+    path: P,
+    algorithm: HashAlgorithm,
+    recurse_dirs: bool,
+    session: &mut Session,
+) -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `hash_path`." );
+
+    let path = path.as_ref();
+
+    let metadata = session.report_error( std::fs::metadata( path ), path )?;
+    let hash = if metadata.is_dir() {
+        if !recurse_dirs {
+            let error_msg = format!(
+                    "Invalid source path: directory without recursion: {:?}", path );
+
+            log::error!( "{}", error_msg );
+
+            let error = std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput, error_msg );
+
+            return session.report_error( Err( error ), path );
+        }
+
+        let start_time = std::time::Instant::now();
+        let digest = calc_tree_digest( path, algorithm, session )?;
+
+        let mut listing_path = path.as_os_str().to_owned();
+        if !std::os::unix::ffi::OsStrExt::as_bytes( path.as_os_str() ).ends_with( b"/" ) {
+            listing_path.push( "/" );
+        }
+        session.emit( Event{
+            src_path: Some( listing_path.into() ),
+            hash: Some( digest.clone() ),
+            duration: Some( start_time.elapsed() ),
+            ..Event::new( EventKind::Hashed )
+        } );
+
+        digest
+    }
+    else { hash_file( path, algorithm, metadata.len(), session )? };
+
+    log::trace!( "End `hash_path`." );
+
+    return Ok( hash );
+}
+
+/// Calculates the digest of a directory tree using the given algorithm.
+///
+/// The digest is the hash of the directory entries sorted by name,
+/// each encoded as its type (`d`, `f`, `l`, or `o` for other file types),
+/// length-prefixed name, and length-prefixed value:
+/// the digest for directories, the hash for files,
+/// the link target for symlinks, and nothing for other file types.
+/// Hence, two trees have the same digest if and only if
+/// their names, file types, file contents, and link targets match.
+/// Each file hashed is reported as a `Hashed` event.
+pub fn calc_tree_digest< P >(
+    path: P,
+    algorithm: HashAlgorithm,
+    session: &mut Session,
+) -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+{
+    use std::os::unix::ffi::OsStrExt;
+
+    log::trace!( "Begin `calc_tree_digest`." );
+
+    let path = path.as_ref();
+
+    log::debug!( "Calculating tree digest: {:?}", path );
+
+    let mut state = Hasher::new( algorithm );
+    for ( name_, metadata_ ) in read_dir_sorted( path, session )? {
+        session.check_cancelled()?;
+
+        let entry_path = path.join( &name_ );
+        let ( entry_type, value ) =
+                if metadata_.is_dir() {
+                    let digest = calc_tree_digest( &entry_path, algorithm, session )?;
+
+                    ( b'd', digest.into_bytes() )
+                }
+                else if metadata_.is_file() {
+                    let hash = hash_file(
+                            &entry_path, algorithm, metadata_.len(), session )?;
+
+                    ( b'f', hash.into_bytes() )
+                }
+                else if metadata_.file_type().is_symlink() {
+                    let link = session.report_error(
+                            entry_path.read_link(), &entry_path )?;
+
+                    ( b'l', link.as_os_str().as_bytes().to_vec() )
+                }
+                else {
+                    log::warn!(
+                        "Tree entry is not a directory, file, or symlink; \
+                                including only its name: \
+                            path: {:?}, \
+                            type: {:?}",
+                        entry_path,
+                        metadata_.file_type(),
+                    );
+
+                    ( b'o', Vec::new() )
+                };
+
+        let name = name_.as_bytes();
+        state.update( &[ entry_type ] );
+        state.update( &( name.len() as u64 ).to_le_bytes() );
+        state.update( name );
+        state.update( &( value.len() as u64 ).to_le_bytes() );
+        state.update( &value );
+    }
+    let digest = state.finalize();
+
+    log::debug!( "Tree digest: {:?}", digest );
+
+    log::trace!( "End `calc_tree_digest`." );
+
+    return Ok( digest );
+}
+
+/// Compares two directory trees, calling `on_entry` for each relative path.
+///
+/// Paths are visited in name order, each directory before its contents;
+/// the contents of directories present in only one of the trees
+/// are classified likewise.
+/// Files of equal size are hashed to compare them.
+pub fn diff_trees< P, Q, F >(
+    a_dir: P,
+    b_dir: Q,
+    session: &mut Session,
+    mut on_entry: F,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    F: FnMut( &DiffEntry ),
+{
+    log::trace!( "Begin `diff_trees`." );
+
+    let a_dir = a_dir.as_ref();
+    let b_dir = b_dir.as_ref();
+
+    log::debug!( "Comparing trees: a_dir: {:?}, b_dir: {:?}", a_dir, b_dir );
+
+    for dir_ in &[ a_dir, b_dir ] {
+        let metadata = session.report_error( std::fs::metadata( dir_ ), dir_ )?;
+        if !metadata.is_dir() {
+            let error_msg = format!(
+                "Invalid tree file type: not a directory: \
+                    path: {:?}, \
+                    type: {:?}",
+                dir_,
+                metadata.file_type(),
+            );
+
+            log::error!( "{}", error_msg );
+
+            let error = std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput, error_msg );
+
+            return Err( error );
+        }
+    }
+
+    diff_dirs( a_dir, b_dir, std::path::Path::new( "" ), session, &mut on_entry )?;
+
+    log::trace!( "End `diff_trees`." );
+
+    return Ok( () );
+}
+
+/// Reads a manifest file.
+pub fn read_manifest< P >( path: P ) -> std::io::Result< Vec< ManifestEntry > >
+where
+    P: AsRef< std::path::Path >,
+{
+    use std::io::BufRead;
+
+    log::trace!( "Begin `read_manifest`." );
+
+    let path = path.as_ref();
+
+    log::debug!( "Reading manifest: {:?}", path );
+
+    let file = std::fs::File::open( path )?;
+    let mut entries = Vec::new();
+    for ( index_, line_res_ ) in std::io::BufReader::new( file ).lines().enumerate() {
+        let line = line_res_?;
+        if line.trim().is_empty() { continue; }
+
+        let entry = serde_json::from_str( &line )
+            .map_err( |error_| error_.to_string() )
+            .and_then( |value_| ManifestEntry::from_json( &value_ ) )
+            .map_err( |error_| {
+                let error_msg = format!(
+                    "Invalid manifest line: \
+                        path: {:?}, \
+                        line: {}, \
+                        error: {}",
+                    path,
+                    index_ + 1,
+                    error_,
+                );
+
+                log::error!( "{}", error_msg );
+
+                std::io::Error::new( std::io::ErrorKind::InvalidData, error_msg )
+            } )?;
+        entries.push( entry );
+    }
+
+    log::trace!( "End `read_manifest`." );
+
+    return Ok( entries );
+}
+
+/// Writes a manifest entry for each path in the directory tree, in name order,
+/// each directory before its contents; the root itself is not included.
+///
+/// Files are hashed unless their hashes are remembered by the writer.
+pub fn write_manifest< P >(
+    dir: P,
+    writer: &mut ManifestWriter,
+    session: &mut Session,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `write_manifest`." );
+
+    let dir = dir.as_ref();
+
+    log::debug!( "Writing manifest: {:?}", dir );
+
+    for ( name_, metadata_ ) in read_dir_sorted( dir, session )? {
+        session.check_cancelled()?;
+
+        let path = dir.join( &name_ );
+        let hash =
+                if !metadata_.is_file() { None }
+                else if let Some( hash_ ) = writer.known_hash( &path, &metadata_ ) {
+                    Some( hash_ )
+                }
+                else {
+                    let hash_res = track_file_digest(
+                            &path, HashAlgorithm::Blake2bp, metadata_.len(), session );
+                    Some( session.report_error( hash_res, &path )? )
+                };
+        let rel_path = path.strip_prefix( &writer.root ).unwrap_or( &path );
+        let entry_res = ManifestEntry::from_metadata( &path, rel_path, &metadata_, hash )
+            .and_then( |entry_| writer.write_entry( &entry_ ) );
+        session.report_error( entry_res, &path )?;
+
+        if metadata_.is_dir() { write_manifest( &path, writer, session )?; }
+    }
+
+    log::trace!( "End `write_manifest`." );
+
+    return Ok( () );
+}
+
+/// Compares the directory tree against a manifest of it,
+/// calling `on_entry` for each relative path; tree A is the manifest.
+///
+/// Paths are visited in name order, each directory before its contents,
+/// followed by the paths missing from the tree.
+/// Files of the size given in the manifest are hashed to compare them.
+pub fn verify_tree< P, F >(
+    dir: P,
+    manifest: &[ ManifestEntry ],
+    session: &mut Session,
+    mut on_entry: F,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    F: FnMut( &DiffEntry ),
+{
+    log::trace!( "Begin `verify_tree`." );
+
+    let dir = dir.as_ref();
+
+    log::debug!( "Verifying tree: {:?}", dir );
+
+    let mut expected = manifest.iter()
+        .map( |entry_| ( entry_.path.as_path(), entry_ ) )
+        .collect::< std::collections::BTreeMap< _, _ > >();
+    verify_tree_dir(
+        dir,
+        std::path::Path::new( "" ),
+        &mut expected,
+        session,
+        &mut on_entry,
+    )?;
+
+    for ( rel_path_, entry_ ) in expected {
+        on_entry( &DiffEntry{
+            a_type: Some( entry_.file_type ),
+            a_hash: entry_.hash.clone(),
+            ..DiffEntry::new( DiffKind::OnlyInA, rel_path_.to_owned() )
+        } );
+    }
+
+    log::trace!( "End `verify_tree`." );
+
+    return Ok( () );
+}
+
+/// Restores the paths listed in a manifest under the destination directory,
+/// linking each file from its reflink directory file,
+/// and applies the listed permissions and times.
+///
+/// Reflink directory files are verified against their hashes,
+/// and paths below a symlink are rejected.
+/// Failing paths are reported as errors and skipped;
+/// returns the number of failing paths.
+/// Existing destination directories are merged;
+/// other existing destination paths are not overwritten.
+pub fn restore< P, Q >(
+    manifest: &[ ManifestEntry ],
+    dst_dir: P,
+    reflink_dir: Q,
+    link_mode: LinkMode,
+    session: &mut Session,
+) -> std::io::Result< usize >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `restore`." );
+
+    let dst_dir = dst_dir.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    log::debug!(
+        "Restoring: \
+            dst_dir: {:?}, \
+            reflink_dir: {:?}, \
+            link_mode: {:?}",
+        dst_dir,
+        reflink_dir,
+        link_mode,
+    );
+
+    let dst_metadata = std::fs::metadata( dst_dir )?;
+    if !dst_metadata.is_dir() {
+        let error_msg = format!(
+            "Invalid destination directory file type: not a directory: \
+                path: {:?}, \
+                type: {:?}",
+            dst_dir,
+            dst_metadata.file_type(),
+        );
+
+        log::error!( "{}", error_msg );
+
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg ) );
+    }
+
+    // Sorting by path places each directory before its contents.
+    let mut entries = manifest.iter().collect::< Vec< _ > >();
+    entries.sort_by( |entry_1_, entry_2_| entry_1_.path.cmp( &entry_2_.path ) );
+
+    let mut failure_count = 0;
+    let mut restored = Vec::new();
+    for entry_ in entries {
+        session.check_cancelled()?;
+
+        let dst_path = dst_dir.join( &entry_.path );
+        let restore_res = restore_entry(
+                entry_, dst_dir, &dst_path, reflink_dir, link_mode, session );
+        match session.report_error( restore_res, &dst_path ) {
+            Ok( true ) => { restored.push( ( dst_path, entry_ ) ); },
+            Ok( false ) => {},
+            Err( error_ ) => {
+                if is_cancelled( &error_ ) { return Err( error_ ); }
+
+                failure_count += 1;
+            },
+        }
+    }
+
+    // Directory times are applied after their contents have been restored.
+    for ( dst_path_, entry_ ) in restored.iter().rev() {
+        if entry_.file_type == "file" && link_mode == LinkMode::Hardlink {
+            continue;
+        }
+
+        let metadata_res = apply_manifest_metadata( dst_path_, entry_ );
+        if session.report_error( metadata_res, dst_path_ ).is_err() {
+            failure_count += 1;
+        }
+    }
+
+    log::trace!( "End `restore`." );
+
+    return Ok( failure_count );
+}
+
+/// Returns the manifest path of the named snapshot in the reflink directory.
+///
+/// Snapshot names must be nonempty file names not starting with a dot.
+pub fn snapshot_path< P >( reflink_dir: P, name: &str )
+    -> std::io::Result< std::path::PathBuf >
+where
+    P: AsRef< std::path::Path >,
+{
+    if name.is_empty() || name.starts_with( '.' ) || name.contains( '/' ) {
+        let error_msg = format!( "Invalid snapshot name: {:?}", name );
+
+        log::error!( "{}", error_msg );
+
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg ) );
+    }
+
+    return Ok( reflink_dir.as_ref().join( SNAPSHOTS_DIR_NAME ).join( name ) );
+}
+
+/// Creates the named snapshot in the reflink directory,
+/// returning a manifest writer for paths under the root.
+///
+/// Fails if the snapshot exists.
+pub fn create_snapshot< P, Q >( reflink_dir: P, name: &str, root: Q )
+    -> std::io::Result< ManifestWriter >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `create_snapshot`." );
+
+    let path = snapshot_path( &reflink_dir, name )?;
+
+    log::debug!( "Creating snapshot: {:?}", path );
+
+    let snapshots_dir = reflink_dir.as_ref().join( SNAPSHOTS_DIR_NAME );
+    if optional_metadata( &snapshots_dir )?.is_none() {
+        log::info!( "Snapshot directory not found; creating." );
+
+        std::fs::create_dir( &snapshots_dir )?;
+        std::fs::File::open( &snapshots_dir )?.sync_all()?;
+    }
+
+    if optional_metadata( &path )?.is_some() {
+        let error_msg = format!( "Snapshot exists: {:?}", path );
+
+        log::error!( "{}", error_msg );
+
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists, error_msg ) );
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .write( true )
+        .create_new( true )
+        .open( &path )?;
+
+    log::trace!( "End `create_snapshot`." );
+
+    return Ok( ManifestWriter::new(
+            Box::new( std::io::BufWriter::new( file ) ), root ) );
+}
+
+/// Lists the snapshots in the reflink directory, oldest first.
+pub fn list_snapshots< P >( reflink_dir: P ) -> std::io::Result< Vec< Snapshot > >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `list_snapshots`." );
+
+    let snapshots_dir = reflink_dir.as_ref().join( SNAPSHOTS_DIR_NAME );
+    if optional_metadata( &snapshots_dir )?.is_none() { return Ok( Vec::new() ); }
+
+    let mut snapshots = Vec::new();
+    for entry_res_ in std::fs::read_dir( &snapshots_dir )? {
+        let entry = entry_res_?;
+        let name = match entry.file_name().into_string() {
+            Ok( name_ ) if !name_.starts_with( '.' ) => { name_ },
+
+            _ => {
+                log::warn!( "Invalid snapshot name; skipping: {:?}", entry.path() );
+
+                continue;
+            },
+        };
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            log::warn!( "Invalid snapshot file type; skipping: {:?}", entry.path() );
+
+            continue;
+        }
+
+        snapshots.push( Snapshot{ name, path: entry.path(), time: metadata.modified()? } );
+    }
+    snapshots.sort_by( |snapshot_1_, snapshot_2_| {
+        return ( snapshot_1_.time, &snapshot_1_.name )
+            .cmp( &( snapshot_2_.time, &snapshot_2_.name ) );
+    } );
+
+    log::trace!( "End `list_snapshots`." );
+
+    return Ok( snapshots );
+}
+
+/// Selects the snapshots not kept by the retention policy.
+pub fn expired_snapshots< 'a >( snapshots: &'a [ Snapshot ], policy: &RetentionPolicy )
+    -> Vec< &'a Snapshot >
+{
+    use chrono::Datelike;
+
+    let mut newest_first = snapshots.iter().collect::< Vec< _ > >();
+    newest_first.sort_by( |snapshot_1_, snapshot_2_| {
+        return ( snapshot_2_.time, &snapshot_2_.name )
+            .cmp( &( snapshot_1_.time, &snapshot_1_.name ) );
+    } );
+
+    let mut days = std::collections::HashSet::new();
+    let mut weeks = std::collections::HashSet::new();
+    let mut expired = Vec::new();
+    for ( index_, snapshot_ ) in newest_first.into_iter().enumerate() {
+        let time = chrono::DateTime::< chrono::Utc >::from( snapshot_.time );
+        let day = time.date_naive();
+        let week = ( time.iso_week().year(), time.iso_week().week() );
+
+        let mut keep = index_ < policy.keep_last;
+        if days.len() < policy.keep_daily && days.insert( day ) { keep = true; }
+        if weeks.len() < policy.keep_weekly && weeks.insert( week ) { keep = true; }
+
+        if !keep { expired.push( snapshot_ ); }
+    }
+    expired.sort_by( |snapshot_1_, snapshot_2_| {
+        return ( snapshot_1_.time, &snapshot_1_.name )
+            .cmp( &( snapshot_2_.time, &snapshot_2_.name ) );
+    } );
+
+    return expired;
+}
+
+/// Removes the snapshots not kept by the retention policy,
+/// returning the removed snapshots.
+///
+/// If `dry_run` is set, the snapshots are only reported.
+/// Reflink directory files are not removed; see [`collect_garbage`].
+pub fn expire_snapshots< P >(
+    reflink_dir: P,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    session: &mut Session,
+) -> std::io::Result< Vec< Snapshot > >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `expire_snapshots`." );
+
+    let snapshots = list_snapshots( reflink_dir )?;
+    let expired = expired_snapshots( &snapshots, policy ).into_iter()
+        .cloned()
+        .collect::< Vec< _ > >();
+    for snapshot_ in &expired {
+        session.check_cancelled()?;
+
+        log::info!( "Removing expired snapshot: {:?}", snapshot_.path );
+
+        if !dry_run {
+            session.report_error( std::fs::remove_file( &snapshot_.path ), &snapshot_.path )?;
+        }
+
+        session.emit( Event{
+            src_path: Some( snapshot_.path.clone() ),
+            message: Some( "Expired snapshot".to_owned() ),
+            ..Event::new( EventKind::Removed )
+        } );
+    }
+
+    log::trace!( "End `expire_snapshots`." );
+
+    return Ok( expired );
+}
+
+/// Removes the reflink directory files not referenced by any snapshot,
+/// returning the removed paths.
+///
+/// If `dry_run` is set, the files are only reported.
+/// Otherwise `all_unreferenced` must be set to confirm the removal,
+/// since files copied without recording a snapshot are unreferenced too.
+/// Fails without removing anything if there are no snapshots
+/// or if any snapshot cannot be read.
+/// Must not run concurrently with copying into the reflink directory.
+pub fn collect_garbage< P >(
+    reflink_dir: P,
+    dry_run: bool,
+    all_unreferenced: bool,
+    session: &mut Session,
+) -> std::io::Result< Vec< std::path::PathBuf > >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `collect_garbage`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+
+    log::debug!( "Collecting garbage: {:?}", reflink_dir );
+
+    if !dry_run && !all_unreferenced {
+        let error_msg = format!(
+            "Removal of all unreferenced reflink files not confirmed; \
+                refusing to collect garbage: {:?}",
+            reflink_dir,
+        );
+
+        log::error!( "{}", error_msg );
+
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg ) );
+    }
+
+    let snapshots = list_snapshots( reflink_dir )?;
+    if snapshots.is_empty() {
+        let error_msg = format!(
+            "No snapshots found; refusing to collect garbage: {:?}",
+            reflink_dir,
+        );
+
+        log::error!( "{}", error_msg );
+
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg ) );
+    }
+
+    let mut referenced = std::collections::HashSet::new();
+    for snapshot_ in &snapshots {
+        for entry_ in read_manifest( &snapshot_.path )? {
+            if let Some( hash_ ) = entry_.hash { referenced.insert( hash_ ); }
+        }
+    }
+
+    let mut removed = Vec::new();
+    for ( name_, metadata_ ) in read_dir_sorted( reflink_dir, session )? {
+        session.check_cancelled()?;
+
+        let name = match name_.to_str() {
+            Some( name_ ) if is_reflink_name( name_.as_ref() ) => { name_ },
+
+            _ => { continue; },
+        };
+        if !metadata_.is_file() || referenced.contains( name ) { continue; }
+
+        let path = reflink_dir.join( name );
+
+        log::info!( "Removing unreferenced reflink file: {:?}", path );
+
+        if !dry_run {
+            session.report_error( std::fs::remove_file( &path ), &path )?;
+        }
+
+        session.emit( Event{
+            src_path: Some( path.clone() ),
+            hash: Some( name.to_owned() ),
+            size: Some( metadata_.len() ),
+            message: Some( "Unreferenced reflink file".to_owned() ),
+            ..Event::new( EventKind::Removed )
+        } );
+        removed.push( path );
+    }
+
+    log::trace!( "End `collect_garbage`." );
+
+    return Ok( removed );
+}
+
+/// Calculates statistics of the reflink directory files,
+/// listing the `top` largest and most referenced files.
+///
+/// References are counted if there are snapshots.
+/// Entries other than files with well-formed names are ignored;
+/// see [`verify_reflink_dir_with`].
+pub fn calc_store_stats< P >( reflink_dir: P, top: usize, session: &mut Session )
+    -> std::io::Result< StoreStats >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `calc_store_stats`." );
+
+    let reflink_dir = reflink_dir.as_ref();
+
+    let mut stats = StoreStats{
+        size_histogram: SIZE_BUCKET_BOUNDS.iter()
+            .map( |bound_| Some( *bound_ ) )
+            .chain( std::iter::once( None ) )
+            .map( |bound_| ( bound_, EntryCount::default() ) )
+            .collect(),
+        ..StoreStats::default()
+    };
+    let mut sizes = std::collections::HashMap::new();
+    for ( name_, metadata_ ) in read_dir_sorted( reflink_dir, session )? {
+        session.check_cancelled()?;
+
+        let name = match name_.to_str() {
+            Some( name_ ) if is_reflink_name( name_.as_ref() ) => { name_ },
+
+            _ => { continue; },
+        };
+        if !metadata_.is_file() { continue; }
+
+        let size = metadata_.len();
+        stats.total.add( size );
+        let bucket_index = SIZE_BUCKET_BOUNDS.iter()
+            .position( |bound_| size < *bound_ )
+            .unwrap_or( SIZE_BUCKET_BOUNDS.len() );
+        stats.size_histogram[ bucket_index ].1.add( size );
+        let time = chrono::DateTime::< chrono::Utc >::from( metadata_.modified()? );
+        stats.daily.entry( time.date_naive() ).or_default().add( size );
+        sizes.insert( name.to_owned(), size );
+    }
+
+    let mut largest = sizes.iter()
+        .map( |( hash_, size_ )| ( hash_.clone(), *size_ ) )
+        .collect::< Vec< _ > >();
+    largest.sort_by( |( hash_1_, size_1_ ), ( hash_2_, size_2_ )| {
+        return size_2_.cmp( size_1_ ).then( hash_1_.cmp( hash_2_ ) );
+    } );
+    largest.truncate( top );
+    stats.largest = largest;
+
+    let snapshots = list_snapshots( reflink_dir )?;
+    if !snapshots.is_empty() {
+        let mut reference_counts = std::collections::HashMap::< String, u64 >::new();
+        for snapshot_ in &snapshots {
+            session.check_cancelled()?;
+
+            for entry_ in read_manifest( &snapshot_.path )? {
+                if let Some( hash_ ) = entry_.hash {
+                    *reference_counts.entry( hash_ ).or_default() += 1;
+                }
+            }
+        }
+
+        let mut references = ReferenceStats{
+            snapshots: snapshots.len(),
+            references: reference_counts.values().sum(),
+            ..ReferenceStats::default()
+        };
+        for ( hash_, size_ ) in &sizes {
+            match reference_counts.get( hash_ ) {
+                Some( count_ ) => { *references.counts.entry( *count_ ).or_default() += 1; },
+                None => { references.unreferenced.add( *size_ ); },
+            }
+        }
+        references.missing = reference_counts.keys()
+            .filter( |hash_| !sizes.contains_key( *hash_ ) )
+            .count() as u64;
+
+        let mut most_referenced = reference_counts.into_iter()
+            .filter( |( hash_, _ )| sizes.contains_key( hash_ ) )
+            .collect::< Vec< _ > >();
+        most_referenced.sort_by( |( hash_1_, count_1_ ), ( hash_2_, count_2_ )| {
+            return count_2_.cmp( count_1_ ).then( hash_1_.cmp( hash_2_ ) );
+        } );
+        most_referenced.truncate( top );
+        references.most_referenced = most_referenced;
+
+        stats.references = Some( references );
+    }
+
+    log::trace!( "End `calc_store_stats`." );
+
+    return Ok( stats );
+}
+
+/// Compares two manifests, calling `on_entry` for each relative path
+/// in name order, each directory before its contents.
+pub fn diff_manifests< F >(
+    a_manifest: &[ ManifestEntry ],
+    b_manifest: &[ ManifestEntry ],
+    mut on_entry: F,
+)
+where
+    F: FnMut( &DiffEntry ),
+{
+    let a_entries = a_manifest.iter()
+        .map( |entry_| ( entry_.path.as_path(), entry_ ) )
+        .collect::< std::collections::BTreeMap< _, _ > >();
+    let b_entries = b_manifest.iter()
+        .map( |entry_| ( entry_.path.as_path(), entry_ ) )
+        .collect::< std::collections::BTreeMap< _, _ > >();
+    let paths = a_entries.keys().chain( b_entries.keys() )
+        .collect::< std::collections::BTreeSet< _ > >();
+    for path_ in paths {
+        let a_entry = a_entries.get( path_ );
+        let b_entry = b_entries.get( path_ );
+        let kind = match ( a_entry, b_entry ) {
+            ( Some( a_entry_ ), Some( b_entry_ ) ) => {
+                compare_manifest_entries( a_entry_, b_entry_ )
+            },
+            ( Some( _ ), None ) => { DiffKind::OnlyInA },
+
+            _ => { DiffKind::OnlyInB },
+        };
+
+        on_entry( &DiffEntry{
+            a_type: a_entry.map( |entry_| entry_.file_type ),
+            b_type: b_entry.map( |entry_| entry_.file_type ),
+            a_hash: a_entry.and_then( |entry_| entry_.hash.clone() ),
+            b_hash: b_entry.and_then( |entry_| entry_.hash.clone() ),
+            ..DiffEntry::new( kind, path_.to_path_buf() )
+        } );
+    }
+}
+
+/// Formats a line of a hash listing.
+///
+/// In the `gnu` and `bsd` formats, paths containing backslashes or newlines
+/// are escaped and the line is prefixed with a backslash, like coreutils does.
+/// In the `cpdd` format, paths starting with a space or `*` are separated by two spaces,
+/// so that [`parse_hash_line`] reads them back unchanged.
+pub fn format_hash_line< P >(
+    hash: &str,
+    path: P,
+    algorithm: HashAlgorithm,
+    format: HashFormat,
+) -> String
+where
+    P: AsRef< std::path::Path >,
+{
+    let path = path.as_ref().to_string_lossy();
+
+    if format == HashFormat::Cpdd {
+        if path.starts_with( [ ' ', '*' ] ) { return format!( "{}  {}", hash, path ); }
+
+        return format!( "{} {}", hash, path );
+    }
+
+    let ( prefix, path ) =
+            if path.contains( [ '\\', '\n', '\r' ] ) {
+                ( "\\", escape_hash_path( &path ) )
+            }
+            else { ( "", path.into_owned() ) };
+
+    return match format {
+        HashFormat::Bsd => {
+            format!( "{}{} ({}) = {}", prefix, algorithm.tag(), path, hash )
+        },
+
+        _ => { format!( "{}{}  {}", prefix, hash, path ) },
+    };
+}
+
+/// Parses a line of a hash listing in any of the formats.
+///
+/// A space or `*` following the first space after the hash belongs to the `gnu` separator,
+/// so a `cpdd` line whose path starts with either is ambiguous and loses that character;
+/// [`format_hash_line`] never writes such lines.
+/// Returns `None` if the line is not properly formatted.
+pub fn parse_hash_line( line: &str ) -> Option< HashLine >
+{
+    let line = line.strip_suffix( '\r' ).unwrap_or( line );
+    let ( escaped, line ) = match line.strip_prefix( '\\' ) {
+        Some( line_ ) => { ( true, line_ ) },
+        None => { ( false, line ) },
+    };
+    let unescape = |path: &str| {
+        if escaped { unescape_hash_path( path ) } else { Some( path.to_owned() ) }
+    };
+
+    // BSD style: `<TAG> (<path>) = <hash>`.
+    if let Some( ( tag_, rest_ ) ) = line.split_once( " (" ) {
+        if let Some( algorithm_ ) = HashAlgorithm::from_tag( tag_ ) {
+            let ( path, hash ) = rest_.rsplit_once( ") = " )?;
+            if !is_hex( hash ) || path.is_empty() { return None; }
+
+            return Some( HashLine{
+                algorithm: Some( algorithm_ ),
+                hash: hash.to_ascii_lowercase(),
+                path: unescape( path )?.into(),
+            } );
+        }
+    }
+
+    // GNU style: `<hash>  <path>` or `<hash> *<path>`; cpdd style: `<hash> <path>`.
+    let ( hash, rest ) = line.split_once( ' ' )?;
+    let path = rest.strip_prefix( ' ' )
+            .or_else( || rest.strip_prefix( '*' ) )
+            .unwrap_or( rest );
+    if !is_hex( hash ) || path.is_empty() { return None; }
+
+    return Some( HashLine{
+        algorithm: None,
+        hash: hash.to_ascii_lowercase(),
+        path: unescape( path )?.into(),
+    } );
+}
+
+
+
+// PRIVATE FUNCTIONS
+
+fn cpdd_path< P, Q, R >(
+    src_path: P,
+    dst_dir: Q,
+    reflink_dir: R,
+    options: &CpddOptions,
+    guard: &WalkGuard,
+    session: &mut Session,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `cpdd_path`." );
+
+    let src_path = src_path.as_ref();
+    let dst_dir = dst_dir.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    session.check_cancelled()?;
+
+    let dst_name = src_path.file_name().ok_or_else( || {
+        let error_msg = format!( "Invalid source path: {:?}", src_path );
+
+        log::error!( "{}", error_msg );
+
+        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
+    } );
+    let dst_name = session.report_error( dst_name, src_path )?;
+    let dst_path = dst_dir.join( dst_name );
+
+    log::info!( "Copying: {:?} -> {:?}", src_path, dst_path );
+
+    let placed_path;
+    let mut placed_hash = None;
+    let src_metadata =
+            session.report_error( src_path.symlink_metadata(), src_path )?;
+    let src_file_type = src_metadata.file_type();
+    if src_file_type.is_dir() {
+        log::debug!( "Source file type is directory." );
+
+        let dir_res = cpdd_dir( src_path, &dst_path, options, session );
+        placed_path = session.report_error( dir_res, src_path )?;
+
+        if let ( Some( dst_dir ), true ) = ( &placed_path, options.recurse_dirs ) {
+            log::debug!( "Recursing directory." );
+
+            let src_entries =
+                    session.report_error( std::fs::read_dir( src_path ), src_path )?;
+            for src_entry_res_ in src_entries {
+                let src_path =
+                        session.report_error( src_entry_res_, src_path )?.path();
+                let src_metadata = session.report_error(
+                        src_path.symlink_metadata(), &src_path )?;
+                if src_metadata.is_dir() && guard.excludes( &src_metadata ) {
+                    let skip_msg =
+                            "Source directory overlaps destination \
+                                or reflink directory; skipping";
+
+                    log::warn!( "{}: path: {:?}", skip_msg, src_path );
+
+                    session.emit( Event{
+                        src_path: Some( src_path ),
+                        message: Some( skip_msg.to_owned() ),
+                        ..Event::new( EventKind::Skipped )
+                    } );
+
+                    continue;
+                }
+
+                cpdd_path(
+                        &src_path, dst_dir, reflink_dir, options, guard, session )?;
+            }
+        }
+    }
+    else if src_file_type.is_file() {
+        log::debug!( "Source file type is file." );
+
+        // Vetoed files are not counted as processed.
+        let proceed_res = session.before_copy( src_path, &dst_path, &src_metadata );
+        if session.report_error( proceed_res, src_path )? {
+            let file_res =
+                    cpdd_file( src_path, &dst_path, reflink_dir, options, session );
+            placed_path = match session.report_error( file_res, src_path )? {
+                Some( ( path_, hash_ ) ) => {
+                    placed_hash = Some( hash_ );
+
+                    Some( path_ )
+                },
+                None => { None },
+            };
+
+            session.finish_file( src_metadata.len() );
+        }
+        else { placed_path = None; }
+    }
+    else if src_file_type.is_symlink() {
+        log::debug!( "Source file type is symlink." );
+
+        let symlink_res = cpdd_symlink( src_path, &dst_path, options, session );
+        placed_path = session.report_error( symlink_res, src_path )?;
+    }
+    else {
+        let error_msg = format!(
+            "Invalid source file type: not a directory, file, or symlink: \
+                path: {:?}, \
+                type: {:?}",
+            src_path,
+            src_file_type,
+        );
+
+        if options.skip_invalid_file_types {
+            log::warn!( "{}", error_msg );
+
+            session.emit( Event{
+                src_path: Some( src_path.to_owned() ),
+                message: Some( error_msg ),
+                ..Event::new( EventKind::Skipped )
+            } );
+
+            return Ok( () );
+        }
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
+
+        return session.report_error( Err( error ), src_path );
+    }
+
+    match &placed_path {
+        Some( _ ) if src_file_type.is_file()
+                && options.link_mode == LinkMode::Hardlink => {
+            log::debug!( "Destination file is hardlinked; skipping metadata." );
+        },
+        Some( placed_path_ ) => {
+            session.report_error(
+                    copy_metadata( src_path, placed_path_ ), src_path )?;
+        },
+        None => { log::debug!( "Source path not placed; skipping metadata." ); },
+    }
+
+    if let ( Some( placed_path_ ), Some( hash_ ), Some( manifest_ ) ) =
+            ( &placed_path, placed_hash, &mut session.manifest ) {
+        let remember_res = manifest_.remember_hash( placed_path_, hash_ );
+        session.report_error( remember_res, src_path )?;
+    }
+
+    log::trace!( "End `cpdd_path`." );
+
+    return Ok( () );
+}
+
+fn store_path(
+    src_path: &std::path::Path,
+    rel_path: &std::path::Path,
+    reflink_dir: &std::path::Path,
+    options: &CpddOptions,
+    guard: &WalkGuard,
+    session: &mut Session,
+) -> std::io::Result< () >
+{
+    log::trace!( "Begin `store_path`." );
+
+    session.check_cancelled()?;
+
+    let src_metadata =
+            session.report_error( src_path.symlink_metadata(), src_path )?;
+    let mut src_hash = None;
+    if src_metadata.is_file() {
+        log::info!( "Storing: {:?}", src_path );
+
+        let store_res = store_file( src_path, &src_metadata, reflink_dir, options, session );
+        let ( hash, inserted ) = session.report_error( store_res, src_path )?;
+        if !inserted {
+            session.emit( Event{
+                src_path: Some( src_path.to_owned() ),
+                dst_path: Some( reflink_dir.join( &hash ) ),
+                hash: Some( hash.clone() ),
+                size: Some( src_metadata.len() ),
+                message: Some( "Reflink file exists".to_owned() ),
+                ..Event::new( EventKind::Skipped )
+            } );
+        }
+        session.finish_file( src_metadata.len() );
+        src_hash = Some( hash );
+    }
+
+    if let Some( manifest_ ) = &mut session.manifest {
+        let entry_res = ManifestEntry::from_metadata(
+                src_path, rel_path, &src_metadata, src_hash )
+            .and_then( |entry_| manifest_.write_entry( &entry_ ) );
+        session.report_error( entry_res, src_path )?;
+    }
+
+    if !src_metadata.is_dir() {
+        log::trace!( "End `store_path`." );
+
+        return Ok( () );
+    }
+
+    for ( name_, metadata_ ) in read_dir_sorted( src_path, session )? {
+        let entry_path = src_path.join( &name_ );
+        if metadata_.is_dir() && guard.excludes( &metadata_ ) {
+            let skip_msg = "Source directory is the reflink directory; skipping";
+
+            log::warn!( "{}: path: {:?}", skip_msg, entry_path );
+
+            session.emit( Event{
+                src_path: Some( entry_path ),
+                message: Some( skip_msg.to_owned() ),
+                ..Event::new( EventKind::Skipped )
+            } );
+
+            continue;
+        }
+
+        let entry_rel_path = rel_path.join( &name_ );
+        store_path( &entry_path, &entry_rel_path, reflink_dir, options, guard, session )?;
+    }
+
+    log::trace!( "End `store_path`." );
+
+    return Ok( () );
+}
+
+fn scan_path< P >(
+    src_path: P,
+    options: &CpddOptions,
+    guard: &WalkGuard,
+    totals: &mut Totals,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+{
+    let src_path = src_path.as_ref();
+
+    let src_metadata = src_path.symlink_metadata()?;
+    if src_metadata.is_dir() && options.recurse_dirs {
+        for src_entry_res_ in std::fs::read_dir( src_path )? {
+            let src_path = src_entry_res_?.path();
+            let src_metadata = src_path.symlink_metadata()?;
+            if src_metadata.is_dir() && guard.excludes( &src_metadata ) {
+                continue;
+            }
+
+            scan_path( &src_path, options, guard, totals )?;
+        }
+    }
+    else if src_metadata.is_file() {
+        totals.files += 1;
+        totals.bytes += src_metadata.len();
+    }
+
+    return Ok( () );
+}
+
+fn plan_path< P, Q >(
+    src_path: P,
+    reflink_dir: Q,
+    reflinkable: bool,
+    options: &CpddOptions,
+    guard: &WalkGuard,
+    plan: &mut CopyPlan,
+    hashes: &mut std::collections::HashSet< String >,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `plan_path`." );
+
+    let src_path = src_path.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    log::debug!( "Planning: {:?}", src_path );
+
+    let src_metadata = src_path.symlink_metadata()?;
+    if src_metadata.is_dir() && options.recurse_dirs {
+        for src_entry_res_ in std::fs::read_dir( src_path )? {
+            let src_path = src_entry_res_?.path();
+            let src_metadata = src_path.symlink_metadata()?;
+            if src_metadata.is_dir() && guard.excludes( &src_metadata ) {
+                continue;
+            }
+
+            plan_path(
+                &src_path,
+                reflink_dir,
+                reflinkable,
+                options,
+                guard,
+                plan,
+                hashes,
+            )?;
+        }
+    }
+    else if src_metadata.is_file() {
+        let src_size = src_metadata.len();
+        plan.file_count += 1;
+        plan.total_bytes += src_size;
+        if options.link_mode == LinkMode::Copy {
+            plan.dst_dir_bytes += src_size;
+        }
+
+        let src_hash = calc_file_hash( src_path )?;
+        if !reflink_dir.join( &src_hash ).exists()
+                && hashes.insert( src_hash ) {
+            plan.new_bytes += src_size;
+            if !reflinkable {
+                plan.reflink_dir_bytes += src_size;
+            }
+        }
+    }
+
+    log::trace!( "End `plan_path`." );
+
+    return Ok( () );
+}
+
+fn cpdd_dir< P, Q >(
+    src_path: P,
+    dst_path: Q,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< Option< std::path::PathBuf > >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `cpdd_dir`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    let start_time = std::time::Instant::now();
+
+    let src_metadata = src_path.symlink_metadata()?;
+    if !src_metadata.is_dir() {
+        let error_msg = format!(
+            "Invalid source file type: not a directory: \
+                path: {:?}, \
+                type: {:?}",
+            src_path,
+            src_metadata.file_type(),
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
+
+        return Err( error );
+    }
+
+    if !session.before_copy( src_path, dst_path, &src_metadata )? {
+        return Ok( None );
+    }
+
+    log::debug!(
+        "Copying directory: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    let dst_path = match dst_path.symlink_metadata() {
+        Ok( metadata_ ) => {
+            if metadata_.is_dir() {
+                log::info!( "Destination directory already exists; skipping." );
+
+                session.observer.on_skipped_identical( src_path, dst_path );
+                session.emit( Event{
+                    src_path: Some( src_path.to_owned() ),
+                    dst_path: Some( dst_path.to_owned() ),
+                    duration: Some( start_time.elapsed() ),
+                    message: Some( "Destination directory exists".to_owned() ),
+                    ..Event::new( EventKind::Skipped )
+                } );
+
+                return Ok( Some( dst_path.to_owned() ) );
+            }
+
+            match resolve_conflict(
+                src_path,
+                &src_metadata,
+                dst_path,
+                &metadata_,
+                options,
+                session,
+            )? {
+                Resolution::Place( path_ ) => { path_ },
+                Resolution::Keep => { return Ok( None ); },
+            }
+        },
+        Err( error_ ) => {
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => {
+                    log::debug!( "Destination directory not found; creating." );
+
+                    dst_path.to_owned()
+                },
+
+                _ => { return Err( error_ ); },
+            }
+        },
+    };
+
+    std::fs::create_dir( &dst_path )?;
+    std::fs::File::open( &dst_path )?.sync_all()?;
+
+    session.observer.on_dir_created( src_path, &dst_path );
+
+    session.emit( Event{
+        src_path: Some( src_path.to_owned() ),
+        dst_path: Some( dst_path.clone() ),
+        duration: Some( start_time.elapsed() ),
+        ..Event::new( EventKind::Copied )
+    } );
+
+    log::trace!( "End `cpdd_dir`." );
+
+    return Ok( Some( dst_path ) );
+}
+
+fn cpdd_file< P, Q, R >(
+    src_path: P,
+    dst_path: Q,
+    reflink_dir: R,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< Option< ( std::path::PathBuf, String ) > >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+    R: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `cpdd_file`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+    let reflink_dir = reflink_dir.as_ref();
+
+    let start_time = std::time::Instant::now();
+
+    let src_metadata = src_path.symlink_metadata()?;
+    if !src_metadata.is_file() {
+        let error_msg = format!(
+            "Invalid source file type: not a file: \
+                path: {:?}, \
+                type: {:?}",
+            src_path,
+            src_metadata.file_type(),
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
+
+        return Err( error );
+    }
+
+    let ( src_hash, _ ) =
+            store_file( src_path, &src_metadata, reflink_dir, options, session )?;
+    let reflink_path = reflink_dir.join( &src_hash );
+
+    log::debug!(
+        "Linking file: \
+            reflink_path: {:?}, \
+            dst_path: {:?}, \
+            mode: {:?}",
+        reflink_path,
+        dst_path,
+        options.link_mode,
+    );
+
+    let dst_path = match dst_path.symlink_metadata() {
+        Ok( metadata_ ) => {
+            if options.on_conflict != ConflictPolicy::Overwrite
+                    && metadata_.is_file()
+                    && metadata_.len() == src_metadata.len() {
+                let dst_hash = calc_file_hash( dst_path )?;
+                if dst_hash == src_hash {
+                    // This assumes destination has already been reflinked.
+                    log::info!( "Destination file already exists; skipping." );
+
+                    session.observer.on_skipped_identical( src_path, dst_path );
+                    session.emit( Event{
+                        src_path: Some( src_path.to_owned() ),
+                        dst_path: Some( dst_path.to_owned() ),
+                        hash: Some( src_hash.clone() ),
+                        size: Some( src_metadata.len() ),
+                        duration: Some( start_time.elapsed() ),
+                        message: Some( "Destination file exists".to_owned() ),
+                        ..Event::new( EventKind::Skipped )
+                    } );
+
+                    return Ok( Some( ( dst_path.to_owned(), src_hash ) ) );
+                }
+            }
+
+            match resolve_conflict(
+                src_path,
+                &src_metadata,
+                dst_path,
+                &metadata_,
+                options,
+                session,
+            )? {
+                Resolution::Place( path_ ) => { path_ },
+                Resolution::Keep => { return Ok( None ); },
+            }
+        },
+        Err( error_ ) => {
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => {
+                    log::debug!( "Destination file not found; creating." );
+
+                    dst_path.to_owned()
+                },
+
+                _ => { return Err( error_ ); },
+            }
+        },
+    };
+
+    let event_kind = match options.link_mode {
+        LinkMode::Reflink => {
+            reflink_file( &reflink_path, &dst_path )?;
+
+            EventKind::Reflinked
+        },
+        LinkMode::Hardlink => {
+            hardlink_file( &reflink_path, &dst_path )?;
+
+            EventKind::Hardlinked
+        },
+        LinkMode::Copy => {
+            copy_file(
+                &reflink_path,
+                &dst_path,
+                &src_hash,
+                options.min_free_bytes,
+            )?;
+
+            EventKind::Copied
+        },
+        LinkMode::Auto => {
+            let error_msg = format!(
+                "Invalid link mode: not resolved: {:?}", options.link_mode );
+
+            log::error!( "{}", error_msg );
+
+            let error = std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput, error_msg );
+
+            return Err( error );
+        },
+    };
+
+    session.observer.on_reflinked( &reflink_path, &dst_path, options.link_mode );
+    session.emit( Event{
+        src_path: Some( src_path.to_owned() ),
+        dst_path: Some( dst_path.clone() ),
+        hash: Some( src_hash.clone() ),
+        size: Some( src_metadata.len() ),
+        duration: Some( start_time.elapsed() ),
+        ..Event::new( event_kind )
+    } );
+
+    log::trace!( "End `cpdd_file`." );
+
+    return Ok( Some( ( dst_path, src_hash ) ) );
+}
+
+/// Inserts the source file into the reflink directory unless already present,
+/// returning its hash and whether it was inserted.
+fn store_file(
+    src_path: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+    reflink_dir: &std::path::Path,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< ( String, bool ) >
+{
+    log::trace!( "Begin `store_file`." );
+
+    let start_time = std::time::Instant::now();
+
+    let src_hash = calc_file_hash_with( src_path, |count_| {
+        session.advance( count_ );
+
+        return session.check_cancelled();
+    } )?;
+    let reflink_path = reflink_dir.join( &src_hash );
+
+    log::debug!(
+        "Storing file: \
+            src_path: {:?}, \
+            reflink_path: {:?}",
+        src_path,
+        reflink_path,
+    );
+
+    let inserted = match reflink_path.symlink_metadata() {
+        Ok( metadata_ ) => {
+            if !metadata_.is_file() {
+                let error_msg = format!(
+                    "Invalid reflink file type: not a file: \
+                        path: {:?}, \
+                        type: {:?}",
+                    reflink_path,
+                    metadata_.file_type(),
+                );
+
+                log::error!( "{}", error_msg );
+
+                let error = std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput, error_msg );
+
+                return Err( error );
+            }
+
+            log::debug!( "Reflink file already exists; skipping." );
+
+            false
+        },
+        Err( error_ ) => {
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => {
+                    log::debug!( "Reflink file not found; creating." );
+
+                    reflink_or_copy_file(
+                        src_path,
+                        &reflink_path,
+                        &src_hash,
+                        options.min_free_bytes,
+                    )?;
+
+                    session.emit( Event{
+                        src_path: Some( src_path.to_owned() ),
+                        dst_path: Some( reflink_path.clone() ),
+                        hash: Some( src_hash.clone() ),
+                        size: Some( src_metadata.len() ),
+                        duration: Some( start_time.elapsed() ),
+                        ..Event::new( EventKind::Stored )
+                    } );
+                    session.observer.on_store_insert(
+                            src_path, &reflink_path, &src_hash );
+
+                    true
+                },
+
+                _ => { return Err( error_ ); },
+            }
+        },
+    };
+
+    // The file is stored either way, so a failed relink only leaves it unshared.
+    if options.in_place_link {
+        let relink_res =
+                relink_source( src_path, src_metadata, &reflink_path, &src_hash, session );
+        if let Err( error_ ) = session.report_error( relink_res, src_path ) {
+            if is_cancelled( &error_ ) { return Err( error_ ); }
+        }
+    }
+
+    log::trace!( "End `store_file`." );
+
+    return Ok( ( src_hash, inserted ) );
+}
+
+/// Replaces the source file with a reflink of its reflink directory file,
+/// unless they already share all extents or the source file has multiple hardlinks.
+fn relink_source(
+    src_path: &std::path::Path,
+    src_metadata: &std::fs::Metadata,
+    reflink_path: &std::path::Path,
+    hash: &str,
+    session: &mut Session,
+) -> std::io::Result< () >
+{
+    use std::os::unix::fs::MetadataExt;
+
+    log::trace!( "Begin `relink_source`." );
+
+    if src_metadata.nlink() > 1 {
+        log::warn!( "Source file has multiple hardlinks; not relinking: {:?}", src_path );
+
+        log::trace!( "End `relink_source`." );
+
+        return Ok( () );
+    }
+
+    let extents = file_extents( src_path )?;
+    let reflink_extents = file_extents( reflink_path )?;
+    if physical_overlap( &extents, &reflink_extents ) >= src_metadata.len() {
+        log::debug!( "Source file already shares extents; skipping relinking." );
+
+        log::trace!( "End `relink_source`." );
+
+        return Ok( () );
+    }
+    if is_inline( &extents ) && is_inline( &reflink_extents ) {
+        log::debug!( "Source file is stored inline; skipping relinking." );
+
+        log::trace!( "End `relink_source`." );
+
+        return Ok( () );
+    }
+
+    log::info!( "Relinking source: {:?} -> {:?}", reflink_path, src_path );
+
+    let start_time = std::time::Instant::now();
+    relink_file( reflink_path, src_path, src_metadata )?;
+
+    session.emit( Event{
+        src_path: Some( reflink_path.to_owned() ),
+        dst_path: Some( src_path.to_owned() ),
+        hash: Some( hash.to_owned() ),
+        size: Some( src_metadata.len() ),
+        duration: Some( start_time.elapsed() ),
+        message: Some( "Relinked source".to_owned() ),
+        ..Event::new( EventKind::Reflinked )
+    } );
+
+    log::trace!( "End `relink_source`." );
+
+    return Ok( () );
+}
+
+fn cpdd_symlink< P, Q >(
+    src_path: P,
+    dst_path: Q,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< Option< std::path::PathBuf > >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `cpdd_symlink`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    let start_time = std::time::Instant::now();
+
+    let src_metadata = src_path.symlink_metadata()?;
+    if !src_metadata.file_type().is_symlink() {
+        let error_msg = format!(
+            "Invalid source file type: not a symlink: \
+                path: {:?}, \
+                type: {:?}",
+            src_path,
+            src_metadata.file_type(),
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
+
+        return Err( error );
+    }
+
+    if !session.before_copy( src_path, dst_path, &src_metadata )? {
+        return Ok( None );
+    }
+
+    log::debug!(
+        "Copying symlink: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    let src_link = src_path.read_link()?;
+    let dst_path = match dst_path.symlink_metadata() {
+        Ok( metadata_ ) => {
+            if options.on_conflict != ConflictPolicy::Overwrite
+                    && metadata_.file_type().is_symlink() {
+                let dst_link = dst_path.read_link()?;
+                if dst_link == src_link {
+                    log::info!(
+                            "Destination symlink already exists; skipping." );
+
+                    session.observer.on_skipped_identical( src_path, dst_path );
+                    session.emit( Event{
+                        src_path: Some( src_path.to_owned() ),
+                        dst_path: Some( dst_path.to_owned() ),
+                        duration: Some( start_time.elapsed() ),
+                        message: Some( "Destination symlink exists".to_owned() ),
+                        ..Event::new( EventKind::Skipped )
+                    } );
+
+                    return Ok( Some( dst_path.to_owned() ) );
+                }
+            }
+
+            match resolve_conflict(
+                src_path,
+                &src_metadata,
+                dst_path,
+                &metadata_,
+                options,
+                session,
+            )? {
+                Resolution::Place( path_ ) => { path_ },
+                Resolution::Keep => { return Ok( None ); },
+            }
+        },
+        Err( error_ ) => {
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => {
+                    log::debug!( "Destination symlink not found; creating." );
+
+                    dst_path.to_owned()
+                },
+
+                _ => { return Err( error_ ); },
+            }
+        },
+    };
+
+    std::os::unix::fs::symlink( &src_link, &dst_path )?;
+    sync_symlink( &dst_path )?;
+
+    session.emit( Event{
+        src_path: Some( src_path.to_owned() ),
+        dst_path: Some( dst_path.clone() ),
+        duration: Some( start_time.elapsed() ),
+        ..Event::new( EventKind::Copied )
+    } );
+
+    log::trace!( "End `cpdd_symlink`." );
+
+    return Ok( Some( dst_path ) );
+}
+
+fn resolve_conflict< P, Q >(
+    src_path: P,
+    src_metadata: &std::fs::Metadata,
+    dst_path: Q,
+    dst_metadata: &std::fs::Metadata,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< Resolution >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `resolve_conflict`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    log::debug!(
+        "Resolving conflict: \
+            src_path: {:?}, \
+            dst_path: {:?}, \
+            policy: {:?}",
+        src_path,
+        dst_path,
+        options.on_conflict,
+    );
+
+    let src_wins = match options.on_conflict {
+        ConflictPolicy::Newer => {
+            src_metadata.modified()? > dst_metadata.modified()?
+        },
+        ConflictPolicy::Larger => { src_metadata.len() > dst_metadata.len() },
+        ConflictPolicy::Rename
+                | ConflictPolicy::Overwrite
+                | ConflictPolicy::Skip
+                | ConflictPolicy::Fail
+                | ConflictPolicy::Both => { true },
+    };
+
+    let action;
+    let mut backup_path = None;
+    let resolution;
+    match ( options.on_conflict, src_wins ) {
+        ( ConflictPolicy::Overwrite, _ ) if !dst_metadata.is_dir() => {
+            log::info!( "Removing destination path." );
+
+            std::fs::remove_file( dst_path )?;
+
+            action = "removed_dst";
+            resolution = Resolution::Place( dst_path.to_owned() );
+        },
+        ( ConflictPolicy::Rename, _ )
+                | ( ConflictPolicy::Overwrite, _ )
+                | ( ConflictPolicy::Newer, true )
+                | ( ConflictPolicy::Larger, true ) => {
+            log::info!( "Renaming destination path." );
+
+            backup_path =
+                    Some( backup_rename( dst_path, options, session )? );
+
+            action = "renamed_dst";
+            resolution = Resolution::Place( dst_path.to_owned() );
+        },
+        ( ConflictPolicy::Newer, false )
+                | ( ConflictPolicy::Larger, false )
+                | ( ConflictPolicy::Both, _ ) => {
+            log::info!( "Placing source path under backup name." );
+
+            let backup_path_ =
+                    backup_slot( dst_path, src_path, options, session )?;
+            backup_path = Some( backup_path_.clone() );
+
+            action = "placed_src_as_backup";
+            resolution = Resolution::Place( backup_path_ );
+        },
+        ( ConflictPolicy::Skip, _ ) => {
+            log::info!( "Keeping destination path; skipping source path." );
+
+            action = "skipped_src";
+            resolution = Resolution::Keep;
+        },
+        ( ConflictPolicy::Fail, _ ) => {
+            let error_msg = format!(
+                "Conflicting destination path: \
+                    src_path: {:?}, \
+                    dst_path: {:?}",
+                src_path,
+                dst_path,
+            );
+
+            log::error!( "{}", error_msg );
+
+            log_conflict(
+                src_path,
+                src_metadata,
+                dst_path,
+                dst_metadata,
+                "failed",
+                None,
+                options,
+            )?;
+
+            session.emit( Event{
+                src_path: Some( src_path.to_owned() ),
+                dst_path: Some( dst_path.to_owned() ),
+                size: Some( src_metadata.len() ),
+                message: Some( "failed".to_owned() ),
+                ..Event::new( EventKind::Conflict )
+            } );
+
+            let error = std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists, error_msg );
+
+            return Err( error );
+        },
+    }
+
+    log_conflict(
+        src_path,
+        src_metadata,
+        dst_path,
+        dst_metadata,
+        action,
+        backup_path.as_deref(),
+        options,
+    )?;
+
+    session.emit( Event{
+        src_path: Some( src_path.to_owned() ),
+        dst_path: Some( dst_path.to_owned() ),
+        size: Some( src_metadata.len() ),
+        message: Some( action.to_owned() ),
+        ..Event::new( EventKind::Conflict )
+    } );
+
+    log::trace!( "End `resolve_conflict`." );
+
+    return Ok( resolution );
+}
+
+fn log_conflict< P, Q >(
+    src_path: P,
+    src_metadata: &std::fs::Metadata,
+    dst_path: Q,
+    dst_metadata: &std::fs::Metadata,
+    action: &str,
+    backup_path: Option< &std::path::Path >,
+    options: &CpddOptions,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    use std::io::Write;
+
+    log::trace!( "Begin `log_conflict`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    let conflict_log_path = match &options.conflict_log_path {
+        Some( conflict_log_path_ ) => { conflict_log_path_ },
+        None => { return Ok( () ); },
+    };
+
+    log::debug!( "Logging conflict: {:?}", conflict_log_path );
+
+    let record = serde_json::json!( {
+        "time": unix_time( std::time::SystemTime::now() ),
+        "policy": options.on_conflict.as_str(),
+        "action": action,
+        "src_path": src_path.to_string_lossy(),
+        "src_type": file_type_name( src_metadata.file_type() ),
+        "src_size": src_metadata.len(),
+        "src_mtime": unix_time( src_metadata.modified()? ),
+        "dst_path": dst_path.to_string_lossy(),
+        "dst_type": file_type_name( dst_metadata.file_type() ),
+        "dst_size": dst_metadata.len(),
+        "dst_mtime": unix_time( dst_metadata.modified()? ),
+        "backup_path": backup_path.map( |path_| path_.to_string_lossy() ),
+    } );
+
+    let mut file = std::fs::OpenOptions::new()
+            .append( true ).create( true )
+            .open( conflict_log_path )?;
+    writeln!( file, "{}", record )?;
+    file.sync_all()?;
+
+    log::trace!( "End `log_conflict`." );
+
+    return Ok( () );
+}
+
+fn backup_rename< P >( path: P, options: &CpddOptions, session: &mut Session )
+    -> std::io::Result< std::path::PathBuf >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `backup_rename`." );
+
+    let path = path.as_ref();
+
+    let backup_path = backup_slot( path, path, options, session )?;
+
+    log::debug!(
+        "Renaming path: \
+            path: {:?}, \
+            backup_path: {:?}",
+        path,
+        backup_path,
+    );
+
+    std::fs::rename( path, &backup_path )?;
+    if backup_path.symlink_metadata()?.file_type().is_symlink() {
+        sync_symlink( &backup_path )?;
+    }
+    else { std::fs::File::open( &backup_path )?.sync_all()?; }
+
+    session.observer.on_backup_renamed( path, &backup_path );
+    session.emit( Event{
+        src_path: Some( path.to_owned() ),
+        dst_path: Some( backup_path.clone() ),
+        ..Event::new( EventKind::Renamed )
+    } );
+
+    log::trace!( "End `backup_rename`." );
+
+    return Ok( backup_path );
+}
+
+fn backup_slot< P, Q >(
+    path: P,
+    content_path: Q,
+    options: &CpddOptions,
+    session: &mut Session,
+) -> std::io::Result< std::path::PathBuf >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `backup_slot`." );
+
+    let path = path.as_ref();
+    let content_path = content_path.as_ref();
+
+    let name = path.file_name().ok_or_else( || {
+        let error_msg = format!( "Invalid path: {:?}", path );
+
+        log::error!( "{}", error_msg );
+
+        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
+    } )?;
+
+    log::debug!(
+        "Finding backup path: \
+            path: {:?}, \
+            content_path: {:?}, \
+            style: {:?}",
+        path,
+        content_path,
+        options.backup_style,
+    );
+
+    let backup_path;
+    match options.backup_style {
+        BackupStyle::Suffix => {
+            if options.backup_suffix.is_empty() {
+                let error_msg = format!(
+                    "Invalid backup suffix: null string: path: {:?}",
+                    path,
+                );
+
+                log::error!( "{}", error_msg );
+
+                let error = std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput, error_msg );
+
+                return Err( error );
+            }
+
+            let mut backup_name = name.to_owned();
+            backup_name.push( &options.backup_suffix );
+            backup_path = path.with_file_name( backup_name );
+
+            match backup_path.symlink_metadata() {
+                Ok( _ ) => {
+                    log::info!( "Backup path exists; renaming." );
+
+                    backup_rename( &backup_path, options, session )?;
+                },
+                Err( error_ ) => {
+                    match error_.kind() {
+                        std::io::ErrorKind::NotFound => {},
+
+                        _ => { return Err( error_ ); },
+                    }
+                },
+            }
+        },
+        BackupStyle::Numbered => {
+            let number = next_backup_number( path )?;
+            let mut backup_name = name.to_owned();
+            backup_name.push( format!( ".~{}~", number ) );
+            backup_path = path.with_file_name( backup_name );
+        },
+        BackupStyle::Timestamp => {
+            backup_path = free_backup_path( path, &backup_timestamp() )?;
+        },
+        BackupStyle::Hash => {
+            let metadata = content_path.symlink_metadata()?;
+            let tag =
+                    if metadata.is_file() {
+                        calc_file_hash( content_path )?
+                    }
+                    else if metadata.file_type().is_symlink() {
+                        use std::os::unix::ffi::OsStrExt;
+
+                        calc_hash( content_path.read_link()?.as_os_str().as_bytes() )
+                    }
+                    else {
+                        log::debug!( "No content hash; using timestamp." );
+
+                        backup_timestamp()
+                    };
+            let tag = &tag[ ..tag.len().min( BACKUP_HASH_LENGTH ) ];
+            backup_path = free_backup_path( path, tag )?;
+        },
+    }
+
+    log::debug!( "Backup path: {:?}", backup_path );
+
+    log::trace!( "End `backup_slot`." );
+
+    return Ok( backup_path );
+}
+
+fn next_backup_number< P >( path: P ) -> std::io::Result< u64 >
+where
+    P: AsRef< std::path::Path >,
+{
+    use std::os::unix::ffi::OsStrExt;
+
+    log::trace!( "Begin `next_backup_number`." );
+
+    let path = path.as_ref();
+
+    let name = path.file_name().unwrap().as_bytes();
+    let parent_path = match path.parent() {
+        Some( parent_path_ ) if parent_path_ != std::path::Path::new( "" ) => {
+            parent_path_
+        },
+
+        _ => { std::path::Path::new( "." ) },
+    };
+
+    let mut max_number = 0;
+    for entry_res_ in std::fs::read_dir( parent_path )? {
+        let entry_name = entry_res_?.file_name();
+        let entry_name = entry_name.as_bytes();
+        if entry_name.len() <= name.len() + 3
+                || !entry_name.starts_with( name )
+                || !entry_name[ name.len().. ].starts_with( b".~" )
+                || !entry_name.ends_with( b"~" ) {
+            continue;
+        }
+
+        let number = std::str::from_utf8(
+                &entry_name[ name.len() + 2..entry_name.len() - 1 ] )
+            .ok()
+            .and_then( |number_| number_.parse::< u64 >().ok() );
+        if let Some( number_ ) = number {
+            max_number = max_number.max( number_ );
+        }
+    }
+
+    log::trace!( "End `next_backup_number`." );
+
+    return Ok( max_number + 1 );
+}
+
+fn free_backup_path< P >( path: P, tag: &str )
+    -> std::io::Result< std::path::PathBuf >
+where
+    P: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `free_backup_path`." );
+
+    let path = path.as_ref();
+
+    let mut backup_name = path.file_name().unwrap().to_owned();
+    backup_name.push( "." );
+    backup_name.push( tag );
+    let mut backup_path = path.with_file_name( &backup_name );
+    let mut number = 0;
+    loop {
+        match backup_path.symlink_metadata() {
+            Ok( _ ) => {
+                log::debug!( "Backup path exists; numbering." );
+
+                number += 1;
+                let mut numbered_name = backup_name.clone();
+                numbered_name.push( format!( ".{}", number ) );
+                backup_path = path.with_file_name( numbered_name );
+            },
+            Err( error_ ) => {
+                match error_.kind() {
+                    std::io::ErrorKind::NotFound => { break; },
+
+                    _ => { return Err( error_ ); },
+                }
+            },
+        }
+    }
+
+    log::trace!( "End `free_backup_path`." );
+
+    return Ok( backup_path );
+}
+
+fn backup_timestamp() -> String
+{
+    return chrono::Utc::now().format( "%Y-%m-%dT%H:%M:%S%.9fZ" ).to_string();
+}
+
+fn reflink_or_copy_file< P, Q >(
+    src_path: P,
+    dst_path: Q,
+    src_hash: &str,
+    min_free_bytes: u64,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `reflink_or_copy_file`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    log::debug!(
+        "Reflinking or copying file: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    if reflink::reflink( src_path, dst_path ).is_ok() {
+        log::debug!( "Reflinking succeeded." );
+
+        std::fs::File::open( dst_path )?.sync_all()?;
+
+        return Ok( () );
+    }
+
+    log::debug!( "Reflinking failed; copying file." );
+
+    copy_file( src_path, dst_path, src_hash, min_free_bytes )?;
+
+    log::trace!( "End `reflink_or_copy_file`." );
+
+    return Ok( () );
+}
+
+fn copy_file< P, Q >(
+    src_path: P,
+    dst_path: Q,
+    src_hash: &str,
+    min_free_bytes: u64,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `copy_file`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    log::debug!(
+        "Copying file: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    let dst_dir = match dst_path.parent() {
+        Some( dst_dir_ ) if dst_dir_ != std::path::Path::new( "" ) => { dst_dir_ },
+
+        _ => { std::path::Path::new( "." ) },
+    };
+    ensure_free_space( dst_dir, src_path.metadata()?.len(), min_free_bytes )?;
+
+    std::fs::copy( src_path, dst_path )?;
+    std::fs::File::open( dst_path )?.sync_all()?;
+
+    log::debug!( "Verifying destination file hash: src_hash: {:?}", src_hash );
+
+    let dst_hash = calc_file_hash( dst_path )?;
+    if dst_hash != src_hash {
+        let error_msg = format!(
+            "File copy failed: hash mismatch: \
+                src_path: {:?}, \
+                dst_path: {:?}, \
+                src_hash: {:?}, \
+                dst_hash: {:?}",
+            src_path,
+            dst_path,
+            src_hash,
+            dst_hash,
+        );
+
+        log::error!( "{}", error_msg );
+
+        let error = std::io::Error::other( error_msg );
+
+        return Err( error );
+    }
+
+    log::trace!( "End `copy_file`." );
+
+    return Ok( () );
+}
+
+fn reflink_file< P, Q >(
+    src_path: P,
+    dst_path: Q,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `reflink_file`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    log::debug!(
+        "Reflinking file: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    reflink::reflink( src_path, dst_path )?;
+    std::fs::File::open( dst_path )?.sync_all()?;
+
+    log::trace!( "End `reflink_file`." );
+
+    return Ok( () );
+}
+
+fn hardlink_file< P, Q >(
+    src_path: P,
+    dst_path: Q,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `hardlink_file`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    log::debug!(
+        "Hardlinking file: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    // Protect the shared content from in-place modification.
+    let mut permissions = src_path.metadata()?.permissions();
+    if !permissions.readonly() {
+        log::debug!( "Making source file read-only." );
+
+        permissions.set_readonly( true );
+        std::fs::set_permissions( src_path, permissions )?;
+        std::fs::File::open( src_path )?.sync_all()?;
+    }
+
+    std::fs::hard_link( src_path, dst_path )?;
+    std::fs::File::open( dst_path )?.sync_all()?;
+
+    log::trace!( "End `hardlink_file`." );
+
+    return Ok( () );
+}
+
+fn copy_metadata< P, Q >(
+    src_path: P,
+    dst_path: Q,
+) -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    log::trace!( "Begin `copy_metadata`." );
+
+    let src_path = src_path.as_ref();
+    let dst_path = dst_path.as_ref();
+
+    let src_metadata = src_path.symlink_metadata()?;
+    let dst_metadata = dst_path.symlink_metadata()?;
+    let src_type = src_metadata.file_type();
+    let dst_type = dst_metadata.file_type();
+    if src_type.is_dir() && !dst_type.is_dir()
+            || src_type.is_file() && !dst_type.is_file()
+            || src_type.is_symlink() && !dst_type.is_symlink() {
+        // This assumes source is not some other type.
+        let error_msg = format!(
+            "Invalid destination file type: source file type differs: \
+                src_path: {:?}, \
+                dst_path: {:?}, \
+                src_type: {:?}, \
+                dst_type: {:?}",
+            src_path,
+            dst_path,
+            src_type,
+            dst_type,
+        );
 
+        log::error!( "{}", error_msg );
 
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
 
-// PUBLIC FUNCTIONS
+        return Err( error );
+    }
 
-pub fn cpdd< P, Q, R >(
-    src_path: P,
-    dst_dir: Q,
-    reflink_dir: R,
-    recurse_dirs: bool,
-    overwrite_dst: bool,
-    skip_invalid_file_types: bool,
-    backup_suffix: &str,
-) -> std::io::Result< () >
+    log::debug!(
+        "Copying metadata: \
+            src_path: {:?}, \
+            dst_path: {:?}",
+        src_path,
+        dst_path,
+    );
+
+    if !src_metadata.file_type().is_symlink() {
+        std::fs::set_permissions( dst_path, src_metadata.permissions() )?;
+    }
+    filetime::set_symlink_file_times(
+        dst_path,
+        filetime::FileTime::from_last_access_time( &src_metadata ),
+        filetime::FileTime::from_last_modification_time( &src_metadata ),
+    )?;
+    if dst_metadata.file_type().is_symlink() {
+        sync_symlink( dst_path )?;
+    }
+    else { std::fs::File::open( dst_path )?.sync_all()?; }
+
+    log::trace!( "End `copy_metadata`." );
+
+    return Ok( () );
+}
+
+fn sync_symlink< P >( path: P ) -> std::io::Result< () >
 where
     P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
-    R: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `cpdd`." );
+    log::trace!( "Begin `sync_symlink`." );
 
-    let src_path = src_path.as_ref();
-    let dst_dir = dst_dir.as_ref();
-    let reflink_dir = reflink_dir.as_ref();
+    let path = path.as_ref();
 
-    let dst_name = src_path.file_name().ok_or_else( || {
-        let error_msg = format!( "Invalid source path: {:?}", src_path );
+    let metadata = path.symlink_metadata()?;
+    if !metadata.file_type().is_symlink() {
+        let error_msg = format!(
+            "Invalid file type: not a symlink: \
+                path: {:?}, \
+                type: {:?}",
+            path,
+            metadata.file_type(),
+        );
 
         log::error!( "{}", error_msg );
 
-        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
-    } )?;
-    let dst_path = dst_dir.join( dst_name );
+        let error = std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, error_msg );
 
-    log::info!( "Copying: {:?} -> {:?}", src_path, dst_path );
+        return Err( error );
+    }
 
-    let src_file_type = src_path.symlink_metadata()?.file_type();
-    if src_file_type.is_dir() {
-        log::debug!( "Source file type is directory." );
+    let mut abs_path = std::env::current_dir()?;
+    abs_path.push( path );
+    let parent_path = abs_path.parent().unwrap();
+
+    log::debug!( "Syncing symlink parent: {:?}", parent_path );
 
-        cpdd_dir( src_path, &dst_path, overwrite_dst, backup_suffix )?;
+    std::fs::File::open( parent_path )?.sync_all()?;
 
-        if recurse_dirs {
-            log::debug!( "Recursing directory." );
+    log::trace!( "End `sync_symlink`." );
+
+    return Ok( () );
+}
+
+fn file_type_name( file_type: std::fs::FileType ) -> &'static str
+{
+    if file_type.is_dir() { return "dir"; }
+    if file_type.is_file() { return "file"; }
+    if file_type.is_symlink() { return "symlink"; }
+
+    return "other";
+}
+
+fn unix_time( time: std::time::SystemTime ) -> f64
+{
+    return match time.duration_since( std::time::UNIX_EPOCH ) {
+        Ok( duration_ ) => { duration_.as_secs_f64() },
+        Err( error_ ) => { -error_.duration().as_secs_f64() },
+    };
+}
+
+fn calc_hash( bytes: &[ u8 ] ) -> String
+{
+    return blake2b_simd::blake2bp::Params::new()
+            .hash_length( HASH_LENGTH )
+            .hash( bytes )
+            .to_hex().as_str().to_owned();
+}
+
+fn diff_dirs(
+    a_dir: &std::path::Path,
+    b_dir: &std::path::Path,
+    rel_path: &std::path::Path,
+    session: &mut Session,
+    on_entry: &mut dyn FnMut( &DiffEntry ),
+) -> std::io::Result< () >
+{
+    let mut names = std::collections::BTreeSet::new();
+    for dir_ in &[ a_dir, b_dir ] {
+        for entry_res_ in session.report_error( std::fs::read_dir( dir_ ), dir_ )? {
+            names.insert( session.report_error( entry_res_, dir_ )?.file_name() );
+        }
+    }
+
+    for name_ in names {
+        session.check_cancelled()?;
+
+        let a_path = a_dir.join( &name_ );
+        let b_path = b_dir.join( &name_ );
+        let rel_path = rel_path.join( &name_ );
+        let a_metadata = session.report_error( optional_metadata( &a_path ), &a_path )?;
+        let b_metadata = session.report_error( optional_metadata( &b_path ), &b_path )?;
+        match ( a_metadata, b_metadata ) {
+            ( Some( a_metadata_ ), Some( b_metadata_ ) ) => {
+                let entry_res = diff_paths(
+                    &a_path,
+                    &a_metadata_,
+                    &b_path,
+                    &b_metadata_,
+                    rel_path.clone(),
+                    session,
+                );
+                let entry = session.report_error( entry_res, &a_path )?;
+                on_entry( &entry );
 
-            let dst_dir = &dst_path;
-            for src_entry_res_ in std::fs::read_dir( src_path )? {
-                let src_path = src_entry_res_?.path();
-                cpdd(
-                    &src_path,
-                    dst_dir,
-                    reflink_dir,
-                    recurse_dirs,
-                    overwrite_dst,
-                    skip_invalid_file_types,
-                    backup_suffix,
+                if a_metadata_.is_dir() && b_metadata_.is_dir() {
+                    diff_dirs( &a_path, &b_path, &rel_path, session, on_entry )?;
+                }
+            },
+            ( Some( a_metadata_ ), None ) => {
+                diff_only(
+                    DiffKind::OnlyInA,
+                    &a_path,
+                    &a_metadata_,
+                    &rel_path,
+                    session,
+                    on_entry,
                 )?;
-            }
+            },
+            ( None, Some( b_metadata_ ) ) => {
+                diff_only(
+                    DiffKind::OnlyInB,
+                    &b_path,
+                    &b_metadata_,
+                    &rel_path,
+                    session,
+                    on_entry,
+                )?;
+            },
+            ( None, None ) => {
+                log::warn!( "Path removed while comparing; skipping: {:?}", rel_path );
+            },
         }
     }
-    else if src_file_type.is_file() {
-        log::debug!( "Source file type is file." );
 
-        cpdd_file(
-                src_path, &dst_path, reflink_dir, overwrite_dst, backup_suffix )?;
+    return Ok( () );
+}
+
+/// Reports the path, and its contents if a directory, as present in one tree.
+fn diff_only(
+    kind: DiffKind,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    rel_path: &std::path::Path,
+    session: &mut Session,
+    on_entry: &mut dyn FnMut( &DiffEntry ),
+) -> std::io::Result< () >
+{
+    let file_type = Some( file_type_name( metadata.file_type() ) );
+    let mut entry = DiffEntry::new( kind, rel_path.to_owned() );
+    if kind == DiffKind::OnlyInA { entry.a_type = file_type; }
+    else { entry.b_type = file_type; }
+    on_entry( &entry );
+
+    if !metadata.is_dir() { return Ok( () ); }
+
+    for ( name_, metadata_ ) in read_dir_sorted( path, session )? {
+        session.check_cancelled()?;
+
+        diff_only(
+            kind,
+            &path.join( &name_ ),
+            &metadata_,
+            &rel_path.join( &name_ ),
+            session,
+            on_entry,
+        )?;
     }
-    else if src_file_type.is_symlink() {
-        log::debug!( "Source file type is symlink." );
 
-        cpdd_symlink( src_path, &dst_path, overwrite_dst, backup_suffix )?;
+    return Ok( () );
+}
+
+/// Classifies two paths present in both trees.
+fn diff_paths(
+    a_path: &std::path::Path,
+    a_metadata: &std::fs::Metadata,
+    b_path: &std::path::Path,
+    b_metadata: &std::fs::Metadata,
+    rel_path: std::path::PathBuf,
+    session: &mut Session,
+) -> std::io::Result< DiffEntry >
+{
+    let a_type = file_type_name( a_metadata.file_type() );
+    let b_type = file_type_name( b_metadata.file_type() );
+    let mut entry = DiffEntry{
+        a_type: Some( a_type ),
+        b_type: Some( b_type ),
+        ..DiffEntry::new( DiffKind::Identical, rel_path )
+    };
+
+    if a_type != b_type { entry.kind = DiffKind::TypeDiffers; }
+    else if a_metadata.is_file() {
+        if a_metadata.len() != b_metadata.len() {
+            entry.kind = DiffKind::ContentDiffers;
+        }
+        else {
+            let size = a_metadata.len();
+            let a_hash = track_file_digest( a_path, HashAlgorithm::Blake2bp, size, session )?;
+            let b_hash = track_file_digest( b_path, HashAlgorithm::Blake2bp, size, session )?;
+            if a_hash != b_hash { entry.kind = DiffKind::ContentDiffers; }
+            entry.a_hash = Some( a_hash );
+            entry.b_hash = Some( b_hash );
+        }
     }
-    else {
+    else if a_metadata.file_type().is_symlink()
+            && a_path.read_link()? != b_path.read_link()? {
+        entry.kind = DiffKind::SymlinkTargetDiffers;
+    }
+
+    return Ok( entry );
+}
+
+fn verify_tree_dir(
+    dir: &std::path::Path,
+    rel_path: &std::path::Path,
+    expected: &mut std::collections::BTreeMap< &std::path::Path, &ManifestEntry >,
+    session: &mut Session,
+    on_entry: &mut dyn FnMut( &DiffEntry ),
+) -> std::io::Result< () >
+{
+    for ( name_, metadata_ ) in read_dir_sorted( dir, session )? {
+        session.check_cancelled()?;
+
+        let path = dir.join( &name_ );
+        let rel_path = rel_path.join( &name_ );
+        let entry = match expected.remove( rel_path.as_path() ) {
+            Some( expected_entry_ ) => {
+                let hash =
+                        if metadata_.is_file()
+                                && expected_entry_.file_type == "file"
+                                && expected_entry_.size == metadata_.len() {
+                            let hash_res = track_file_digest(
+                                &path,
+                                HashAlgorithm::Blake2bp,
+                                metadata_.len(),
+                                session,
+                            );
+                            Some( session.report_error( hash_res, &path )? )
+                        }
+                        else { None };
+                let actual_res = ManifestEntry::from_metadata(
+                        &path, &rel_path, &metadata_, hash );
+                let actual_entry = session.report_error( actual_res, &path )?;
+
+                DiffEntry{
+                    a_type: Some( expected_entry_.file_type ),
+                    b_type: Some( actual_entry.file_type ),
+                    a_hash: expected_entry_.hash.clone(),
+                    b_hash: actual_entry.hash.clone(),
+                    ..DiffEntry::new(
+                        compare_manifest_entries( expected_entry_, &actual_entry ),
+                        rel_path.clone(),
+                    )
+                }
+            },
+            None => {
+                DiffEntry{
+                    b_type: Some( file_type_name( metadata_.file_type() ) ),
+                    ..DiffEntry::new( DiffKind::OnlyInB, rel_path.clone() )
+                }
+            },
+        };
+        on_entry( &entry );
+
+        if metadata_.is_dir() {
+            verify_tree_dir( &path, &rel_path, expected, session, on_entry )?;
+        }
+    }
+
+    return Ok( () );
+}
+
+/// Restores a single manifest entry, without its metadata.
+///
+/// Returns `false` if the entry was skipped.
+fn restore_entry(
+    entry: &ManifestEntry,
+    dst_dir: &std::path::Path,
+    dst_path: &std::path::Path,
+    reflink_dir: &std::path::Path,
+    link_mode: LinkMode,
+    session: &mut Session,
+) -> std::io::Result< bool >
+{
+    let start_time = std::time::Instant::now();
+
+    let is_relative = entry.path.components().all( |component_| {
+        matches!( component_, std::path::Component::Normal( _ ) )
+    } );
+    if !is_relative || entry.path.as_os_str().is_empty() {
         let error_msg = format!(
-            "Invalid source file type: not a directory, file, or symlink: \
-                path: {:?}, \
-                type: {:?}",
-            src_path,
-            src_file_type,
+            "Invalid manifest path: not a relative path below the root: {:?}",
+            entry.path,
         );
 
-        if skip_invalid_file_types {
-            log::warn!( "{}", error_msg );
+        log::error!( "{}", error_msg );
 
-            return Ok( () );
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::InvalidData, error_msg ) );
+    }
+
+    // A symlink restored earlier, or already present, must not redirect the path
+    // outside of the destination directory.
+    let mut ancestor_path = dst_dir.to_owned();
+    for component_ in entry.path.parent().into_iter().flat_map( |path_| path_.iter() ) {
+        ancestor_path.push( component_ );
+        let is_symlink = optional_metadata( &ancestor_path )?
+            .is_some_and( |metadata_| metadata_.file_type().is_symlink() );
+        if is_symlink {
+            let error_msg = format!(
+                "Invalid manifest path: ancestor is a symlink: \
+                    path: {:?}, \
+                    ancestor_path: {:?}",
+                entry.path,
+                ancestor_path,
+            );
+
+            log::error!( "{}", error_msg );
+
+            return Err( std::io::Error::new(
+                    std::io::ErrorKind::InvalidData, error_msg ) );
+        }
+    }
+
+    log::info!( "Restoring: {:?}", dst_path );
+
+    if let Some( metadata_ ) = optional_metadata( dst_path )? {
+        if entry.file_type == "dir" && metadata_.is_dir() {
+            log::info!( "Destination directory already exists; merging." );
+
+            return Ok( true );
         }
 
+        let error_msg = format!(
+            "Destination path exists: \
+                path: {:?}, \
+                type: {:?}",
+            dst_path,
+            metadata_.file_type(),
+        );
+
         log::error!( "{}", error_msg );
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
+        return Err( std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists, error_msg ) );
+    }
 
-        return Err( error );
+    match ( entry.file_type, &entry.hash, &entry.target ) {
+        ( "dir", _, _ ) => {
+            std::fs::create_dir( dst_path )?;
+            std::fs::File::open( dst_path )?.sync_all()?;
+
+            session.emit( Event{
+                dst_path: Some( dst_path.to_owned() ),
+                duration: Some( start_time.elapsed() ),
+                ..Event::new( EventKind::Copied )
+            } );
+        },
+        ( "file", Some( hash_ ), _ ) => {
+            let reflink_path = reflink_dir.join( hash_ );
+            let reflink_metadata = match optional_metadata( &reflink_path )? {
+                Some( metadata_ ) if metadata_.is_file()
+                        && metadata_.len() == entry.size => { metadata_ },
+                reflink_metadata_ => {
+                    let error_msg = format!(
+                        "Reflink file missing or invalid: \
+                            path: {:?}, \
+                            size: {:?}, \
+                            expected_size: {}",
+                        reflink_path,
+                        reflink_metadata_.map( |metadata_| metadata_.len() ),
+                        entry.size,
+                    );
+
+                    log::error!( "{}", error_msg );
+
+                    return Err( std::io::Error::new(
+                            std::io::ErrorKind::NotFound, error_msg ) );
+                },
+            };
+
+            // A corrupt reflink directory file would be restored silently otherwise.
+            let reflink_hash = calc_file_hash_with( &reflink_path, |count_| {
+                session.advance( count_ );
+
+                return session.check_cancelled();
+            } )?;
+            if reflink_hash != *hash_ {
+                let error_msg = format!(
+                    "Reflink file corrupt: hash mismatch: \
+                        path: {:?}, \
+                        hash: {:?}",
+                    reflink_path,
+                    reflink_hash,
+                );
+
+                log::error!( "{}", error_msg );
+
+                return Err( std::io::Error::new(
+                        std::io::ErrorKind::InvalidData, error_msg ) );
+            }
+
+            let event_kind = match link_mode {
+                LinkMode::Reflink => {
+                    reflink_file( &reflink_path, dst_path )?;
+
+                    EventKind::Reflinked
+                },
+                LinkMode::Hardlink => {
+                    hardlink_file( &reflink_path, dst_path )?;
+
+                    EventKind::Hardlinked
+                },
+                LinkMode::Copy => {
+                    copy_file( &reflink_path, dst_path, hash_, 0 )?;
+
+                    EventKind::Copied
+                },
+                LinkMode::Auto => {
+                    let error_msg =
+                            format!( "Invalid link mode: not resolved: {:?}", link_mode );
+
+                    log::error!( "{}", error_msg );
+
+                    return Err( std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput, error_msg ) );
+                },
+            };
+
+            session.observer.on_reflinked( &reflink_path, dst_path, link_mode );
+            session.emit( Event{
+                src_path: Some( reflink_path ),
+                dst_path: Some( dst_path.to_owned() ),
+                hash: Some( hash_.clone() ),
+                size: Some( reflink_metadata.len() ),
+                duration: Some( start_time.elapsed() ),
+                ..Event::new( event_kind )
+            } );
+            session.finish_file( reflink_metadata.len() );
+        },
+        ( "symlink", _, Some( target_ ) ) => {
+            std::os::unix::fs::symlink( target_, dst_path )?;
+            sync_symlink( dst_path )?;
+
+            session.emit( Event{
+                dst_path: Some( dst_path.to_owned() ),
+                duration: Some( start_time.elapsed() ),
+                ..Event::new( EventKind::Copied )
+            } );
+        },
+        ( "other", _, _ ) => {
+            let skip_msg = "Invalid manifest file type: not a directory, file, or symlink";
+
+            log::warn!( "{}; skipping: path: {:?}", skip_msg, dst_path );
+
+            session.emit( Event{
+                dst_path: Some( dst_path.to_owned() ),
+                message: Some( skip_msg.to_owned() ),
+                ..Event::new( EventKind::Skipped )
+            } );
+
+            return Ok( false );
+        },
+
+        _ => {
+            let error_msg = format!(
+                "Invalid manifest entry: missing hash or link target: {:?}",
+                entry.path,
+            );
+
+            log::error!( "{}", error_msg );
+
+            return Err( std::io::Error::new(
+                    std::io::ErrorKind::InvalidData, error_msg ) );
+        },
     }
 
-    copy_metadata( src_path, &dst_path )?;
+    return Ok( true );
+}
 
-    log::trace!( "End `cpdd`." );
+/// Applies the permissions and times of a manifest entry to the path.
+fn apply_manifest_metadata( path: &std::path::Path, entry: &ManifestEntry )
+    -> std::io::Result< () >
+{
+    use std::os::unix::fs::PermissionsExt;
+
+    log::debug!( "Applying metadata: {:?}", path );
+
+    if entry.file_type != "symlink" {
+        std::fs::set_permissions(
+                path, std::fs::Permissions::from_mode( entry.mode ) )?;
+    }
+    let to_file_time = |time_ns_: i64| {
+        return filetime::FileTime::from_unix_time(
+            time_ns_.div_euclid( 1_000_000_000 ),
+            time_ns_.rem_euclid( 1_000_000_000 ) as u32,
+        );
+    };
+    filetime::set_symlink_file_times(
+        path,
+        to_file_time( entry.atime_ns ),
+        to_file_time( entry.mtime_ns ),
+    )?;
+    if entry.file_type == "symlink" { sync_symlink( path )?; }
+    else { std::fs::File::open( path )?.sync_all()?; }
 
     return Ok( () );
 }
 
-pub fn verify_reflink_dir< P >( path: P )
-    -> std::io::Result< Vec< std::path::PathBuf > >
+/// Classifies two manifest entries for the same relative path.
+fn compare_manifest_entries( a_entry: &ManifestEntry, b_entry: &ManifestEntry )
+    -> DiffKind
+{
+    if a_entry.file_type != b_entry.file_type { return DiffKind::TypeDiffers; }
+    if a_entry.file_type == "file"
+            && ( a_entry.size != b_entry.size || a_entry.hash != b_entry.hash ) {
+        return DiffKind::ContentDiffers;
+    }
+    if a_entry.target != b_entry.target { return DiffKind::SymlinkTargetDiffers; }
+    if a_entry.mode != b_entry.mode || a_entry.mtime_ns != b_entry.mtime_ns {
+        return DiffKind::MetadataDiffers;
+    }
+
+    return DiffKind::Identical;
+}
+
+/// Checks that none of the source directories contains any of the named directories,
+/// which must be canonical.
+fn check_src_paths< P >( src_paths: &[ P ], dirs: &[ ( &str, &std::path::Path ) ] )
+    -> std::io::Result< () >
 where
     P: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `verify_reflink_dir`." );
+    for src_path_ in src_paths {
+        let src_path = src_path_.as_ref();
+
+        // Symlinks are never walked.
+        if !src_path.symlink_metadata()?.is_dir() { continue; }
+
+        let src_path = src_path.canonicalize()?;
+        for ( name_, dir_ ) in dirs {
+            if dir_.starts_with( &src_path ) {
+                let error_msg = format!(
+                    "Invalid source path: contains {} directory: \
+                        src_path: {:?}, \
+                        dir: {:?}",
+                    name_,
+                    src_path,
+                    dir_,
+                );
+
+                log::error!( "{}", error_msg );
+
+                let error = std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput, error_msg );
+
+                return Err( error );
+            }
+        }
+    }
+
+    return Ok( () );
+}
+
+/// Collects the files in the tree, with their sizes, not following symlinks.
+fn collect_files(
+    path: &std::path::Path,
+    files: &mut Vec< ( std::path::PathBuf, u64 ) >,
+    session: &mut Session,
+) -> std::io::Result< () >
+{
+    let metadata = session.report_error( path.symlink_metadata(), path )?;
+    if metadata.is_file() {
+        files.push( ( path.to_owned(), metadata.len() ) );
+
+        return Ok( () );
+    }
+    if !metadata.is_dir() { return Ok( () ); }
+
+    for ( name_, metadata_ ) in read_dir_sorted( path, session )? {
+        let entry_path = path.join( &name_ );
+        if metadata_.is_file() { files.push( ( entry_path, metadata_.len() ) ); }
+        else if metadata_.is_dir() { collect_files( &entry_path, files, session )?; }
+    }
+
+    return Ok( () );
+}
+
+fn check_file_sharing(
+    path: &std::path::Path,
+    size: u64,
+    reflink_dir: &std::path::Path,
+    relink: bool,
+    session: &mut Session,
+) -> std::io::Result< SharingEntry >
+{
+    use std::os::unix::fs::MetadataExt;
 
-    let path = path.as_ref();
+    let metadata = path.symlink_metadata()?;
+    let hash = track_file_digest( path, HashAlgorithm::Blake2bp, size, session )?;
+    let reflink_path = reflink_dir.join( &hash );
+    let mut entry = SharingEntry{
+        path: path.to_owned(),
+        hash,
+        size,
+        shared_bytes: None,
+        inline: false,
+        relinked: false,
+    };
+    if !optional_metadata( &reflink_path )?.is_some_and( |metadata_| metadata_.is_file() ) {
+        log::warn!( "Reflink file not found: {:?}", reflink_path );
+
+        return Ok( entry );
+    }
 
-    log::debug!( "Verifying reflink directory: {:?}", path );
+    let reflink_extents = file_extents( &reflink_path )?;
+    let extents = file_extents( path )?;
+    let shared_bytes = physical_overlap( &extents, &reflink_extents );
+    entry.shared_bytes = Some( shared_bytes.min( size ) );
+    // Inline data is copied by reflinking, so relinking would not help.
+    entry.inline = is_inline( &extents ) && is_inline( &reflink_extents );
+    if entry.is_shared() || entry.inline || !relink { return Ok( entry ); }
 
-    let mut mismatches = Vec::new();
-    for reflink_entry_res_ in std::fs::read_dir( path )? {
-        let reflink_entry = reflink_entry_res_?;
-        let reflink_path = reflink_entry.path();
-        let reflink_name = reflink_entry.file_name();
+    // Replacing one name of a hardlinked file would split it without freeing space.
+    if metadata.nlink() > 1 {
+        log::warn!( "File has multiple hardlinks; not relinking: {:?}", path );
 
-        log::info!( "Verifying: {:?}", reflink_path );
+        return Ok( entry );
+    }
 
-        let hash: std::ffi::OsString = calc_file_hash( &reflink_path )?.into();
-        if hash != reflink_name {
-            log::warn!(
-                "Hash mismatch: file name differs from hash: \
-                    path: {:?}, \
-                    name: {:?}, \
-                    hash: {:?}",
-                reflink_path,
-                reflink_name,
-                hash,
-            );
+    log::info!( "Relinking: {:?} -> {:?}", reflink_path, path );
 
-            mismatches.push( reflink_path );
-        }
-    }
+    relink_file( &reflink_path, path, &metadata )?;
+    entry.relinked = true;
+    let shared_bytes = physical_overlap( &file_extents( path )?, &reflink_extents );
+    entry.shared_bytes = Some( shared_bytes.min( size ) );
 
-    log::trace!( "End `verify_reflink_dir`." );
+    session.emit( Event{
+        src_path: Some( reflink_path ),
+        dst_path: Some( path.to_owned() ),
+        hash: Some( entry.hash.clone() ),
+        size: Some( size ),
+        message: Some( "Relinked".to_owned() ),
+        ..Event::new( EventKind::Reflinked )
+    } );
 
-    return Ok( mismatches );
+    return Ok( entry );
 }
 
-pub fn calc_file_hash< P >( path: P ) -> std::io::Result< String >
+fn usage_path< F >(
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+    depth: usize,
+    inodes: &mut std::collections::HashSet< ( u64, u64 ) >,
+    ranges: &mut std::collections::HashMap< u64, RangeSet >,
+    session: &mut Session,
+    on_entry: &mut F,
+) -> std::io::Result< SpaceUsage >
 where
-    P: AsRef< std::path::Path >,
+    F: FnMut( &UsageEntry ),
 {
-    use std::io::Read;
+    use std::os::unix::fs::MetadataExt;
 
-    log::trace!( "Begin `calc_file_hash`." );
+    session.check_cancelled()?;
 
-    let path = path.as_ref();
+    let mut usage = SpaceUsage::default();
+    if metadata.is_file() {
+        if !inodes.insert( ( metadata.dev(), metadata.ino() ) ) { return Ok( usage ); }
 
-    log::debug!( "Calculating file hash: {:?}", path );
+        let extents = match session.report_error( file_extents( path ), path ) {
+            Ok( extents_ ) => { extents_ },
+            Err( error_ ) => {
+                if is_cancelled( &error_ ) { return Err( error_ ); }
 
-    let mut file = std::fs::File::open( path )?;  // FIX?: refactor read into fn
-    let mut buffer = vec![ 0; BUFFER_LENGTH ];
-    let mut state = blake2b_simd::blake2bp::Params::new()
-            .hash_length( HASH_LENGTH )
-            .to_state();
-    loop {
-        match file.read( &mut buffer ) {
-            Ok( 0 ) => { break; },
-            Ok( count_ ) => { state.update( &buffer[ ..count_ ] ); },
+                return Ok( usage );
+            },
+        };
+
+        usage.files = 1;
+        usage.logical_bytes = metadata.len();
+        // Shared extents are counted for the first file found using them.
+        let device_ranges = ranges.entry( metadata.dev() ).or_default();
+        for extent_ in extents.iter().filter( |extent_| extent_.is_located() ) {
+            let new_bytes = device_ranges.insert(
+                    extent_.physical, extent_.physical.saturating_add( extent_.length ) );
+            if extent_.is_shared() || metadata.nlink() > 1 {
+                usage.shared_bytes += new_bytes;
+            }
+            else { usage.exclusive_bytes += new_bytes; }
+        }
+    }
+    else if metadata.is_dir() {
+        let entries = match read_dir_sorted( path, session ) {
+            Ok( entries_ ) => { entries_ },
             Err( error_ ) => {
-                match error_.kind() {
-                    std::io::ErrorKind::Interrupted => { continue; },
+                if is_cancelled( &error_ ) { return Err( error_ ); }
 
-                    _ => { return Err( error_ ); },
-                }
+                return Ok( usage );
             },
+        };
+
+        for ( name_, metadata_ ) in entries {
+            let entry_path = path.join( &name_ );
+            let entry_usage = usage_path(
+                    &entry_path, &metadata_, depth + 1, inodes, ranges, session, on_entry )?;
+            usage.merge( &entry_usage );
         }
     }
-    let hash = state.finalize().to_hex().as_str().to_owned();
+    else { return Ok( usage ); }
 
-    log::debug!( "File hash: {:?}", hash );
+    if metadata.is_dir() || depth == 0 {
+        on_entry( &UsageEntry{ path: path.to_owned(), depth, usage } );
+    }
 
-    log::trace!( "End `calc_file_hash`." );
+    return Ok( usage );
+}
 
-    return Ok( hash );
+/// Returns whether all of the file data is stored inline.
+fn is_inline( extents: &[ Extent ] ) -> bool
+{
+    return !extents.is_empty() && extents.iter().all( |extent_| extent_.is_inline() );
 }
 
+/// Returns the number of bytes in the physical ranges of both extent lists.
+fn physical_overlap( extents_1: &[ Extent ], extents_2: &[ Extent ] ) -> u64
+{
+    let mut overlap = 0;
+    for extent_1_ in extents_1.iter().filter( |extent_| extent_.is_located() ) {
+        for extent_2_ in extents_2.iter().filter( |extent_| extent_.is_located() ) {
+            let start = extent_1_.physical.max( extent_2_.physical );
+            let end = ( extent_1_.physical + extent_1_.length )
+                .min( extent_2_.physical + extent_2_.length );
+            if end > start { overlap += end - start; }
+        }
+    }
 
+    return overlap;
+}
 
-// PRIVATE FUNCTIONS
+/// Replaces the file by a reflink of the reflink directory file,
+/// via a temporary file that is compared with the file before replacing it,
+/// keeping the file ownership, permissions, times, and extended attributes,
+/// including POSIX ACLs and security labels.
+///
+/// The file gets a new inode number.
+/// Fails if the file was modified since `metadata` was read,
+/// or if its inode flags, as set by `chattr`, differ from those of the reflink.
+fn relink_file(
+    reflink_path: &std::path::Path,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
+) -> std::io::Result< () >
+{
+    log::trace!( "Begin `relink_file`." );
 
-fn cpdd_dir< P, Q >(
-    src_path: P,
-    dst_path: Q,
-    overwrite_dst: bool,
-    backup_suffix: &str,
+    let mut temp_name = std::ffi::OsString::from( RELINK_PREFIX );
+    temp_name.push( path.file_name().unwrap_or_default() );
+    let temp_path = path.with_file_name( temp_name );
+    if optional_metadata( &temp_path )?.is_some() { std::fs::remove_file( &temp_path )?; }
+
+    reflink_file( reflink_path, &temp_path )?;
+    let place_res = place_relinked_file( &temp_path, path, metadata );
+    if place_res.is_err() { std::fs::remove_file( &temp_path ).ok(); }
+
+    log::trace!( "End `relink_file`." );
+
+    return place_res;
+}
+
+fn place_relinked_file(
+    temp_path: &std::path::Path,
+    path: &std::path::Path,
+    metadata: &std::fs::Metadata,
 ) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `cpdd_dir`." );
+    use std::os::unix::fs::{ MetadataExt, OpenOptionsExt };
 
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
+    log::trace!( "Begin `place_relinked_file`." );
 
-    let src_metadata = src_path.symlink_metadata()?;
-    if !src_metadata.is_dir() {
+    // Flags such as no-copy-on-write cannot be set once the file has data.
+    let flags = inode_flags( path )?;
+    let temp_flags = inode_flags( temp_path )?;
+    if temp_flags != flags {
         let error_msg = format!(
-            "Invalid source file type: not a directory: \
+            "Relink failed: inode flags differ: \
                 path: {:?}, \
-                type: {:?}",
-            src_path,
-            src_metadata.file_type(),
+                flags: {:#x}, \
+                reflink_flags: {:#x}",
+            path,
+            flags,
+            temp_flags,
         );
 
         log::error!( "{}", error_msg );
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
-
-        return Err( error );
+        return Err( std::io::Error::other( error_msg ) );
     }
 
-    log::debug!(
-        "Copying directory: \
-            src_path: {:?}, \
-            dst_path: {:?}",
-        src_path,
-        dst_path,
-    );
-
-    match dst_path.symlink_metadata() {
-        Ok( metadata_ ) => {
-            if overwrite_dst  && !metadata_.is_dir() {
-                log::info!( "Removing destination path." );
+    // Changing the owner may clear set-user-ID bits, so it precedes the permissions.
+    let temp_metadata = temp_path.symlink_metadata()?;
+    if ( temp_metadata.uid(), temp_metadata.gid() ) != ( metadata.uid(), metadata.gid() ) {
+        std::os::unix::fs::chown( temp_path, Some( metadata.uid() ), Some( metadata.gid() ) )?;
+    }
+    copy_xattrs( path, temp_path )?;
+    copy_metadata( path, temp_path )?;
+
+    // The file may have changed since it was hashed, and the reflink directory file
+    // may be corrupt, so the content is compared right before the rename.
+    // Reading the reflink must not change the access time just copied to it.
+    let mut file = std::fs::File::open( path )?;
+    let mut temp_file = std::fs::OpenOptions::new()
+        .read( true )
+        .custom_flags( libc::O_NOATIME )
+        .open( temp_path )?;
+    if !same_content( &mut file, &mut temp_file )? {
+        let error_msg = format!( "Relink failed: content mismatch: {:?}", path );
 
-                std::fs::remove_file( dst_path )?;
-            }
-            else {
-                if metadata_.is_dir() {
-                    log::info!(
-                            "Destination directory already exists; skipping." );
+        log::error!( "{}", error_msg );
 
-                    return Ok( () );
-                }
+        return Err( std::io::Error::other( error_msg ) );
+    }
 
-                log::info!( "Renaming destination path." );
+    let current_metadata = file.metadata()?;
+    if ( current_metadata.dev(), current_metadata.ino() ) != ( metadata.dev(), metadata.ino() )
+            || current_metadata.len() != metadata.len()
+            || current_metadata.modified()? != metadata.modified()? {
+        let error_msg = format!( "File modified while relinking: {:?}", path );
 
-                backup_rename( dst_path, backup_suffix )?;
-            }
-        },
-        Err( error_ ) => {
-            match error_.kind() {
-                std::io::ErrorKind::NotFound => {
-                    log::debug!( "Destination directory not found; creating." );
-                },
+        log::error!( "{}", error_msg );
 
-                _ => { return Err( error_ ); },
-            }
-        },
+        return Err( std::io::Error::other( error_msg ) );
     }
 
-    std::fs::create_dir( dst_path )?;
-    std::fs::File::open( dst_path )?.sync_all()?;
+    std::fs::rename( temp_path, path )?;
+    let parent_path = path.parent()
+        .filter( |parent_path_| !parent_path_.as_os_str().is_empty() )
+        .unwrap_or( std::path::Path::new( "." ) );
+    std::fs::File::open( parent_path )?.sync_all()?;
 
-    log::trace!( "End `cpdd_dir`." );
+    log::trace!( "End `place_relinked_file`." );
 
     return Ok( () );
 }
 
-fn cpdd_file< P, Q, R >(
-    src_path: P,
-    dst_path: Q,
-    reflink_dir: R,
-    overwrite_dst: bool,
-    backup_suffix: &str,
-) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
-    R: AsRef< std::path::Path >,
+/// Returns whether the rest of both files is the same.
+fn same_content( file_1: &mut std::fs::File, file_2: &mut std::fs::File )
+    -> std::io::Result< bool >
 {
-    log::trace!( "Begin `cpdd_file`." );
-
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
-    let reflink_dir = reflink_dir.as_ref();
+    let mut buffer_1 = vec![ 0; COMPARE_BUFFER_LENGTH ];
+    let mut buffer_2 = vec![ 0; COMPARE_BUFFER_LENGTH ];
+    loop {
+        let count_1 = read_full( file_1, &mut buffer_1 )?;
+        let count_2 = read_full( file_2, &mut buffer_2 )?;
+        if buffer_1[ ..count_1 ] != buffer_2[ ..count_2 ] { return Ok( false ); }
+        if count_1 == 0 { return Ok( true ); }
+    }
+}
 
-    let src_metadata = src_path.symlink_metadata()?;
-    if !src_metadata.is_file() {
-        let error_msg = format!(
-            "Invalid source file type: not a file: \
-                path: {:?}, \
-                type: {:?}",
-            src_path,
-            src_metadata.file_type(),
-        );
+/// Reads until the buffer is full or the end of the file is reached,
+/// returning the byte count.
+fn read_full( file: &mut std::fs::File, buffer: &mut [ u8 ] ) -> std::io::Result< usize >
+{
+    use std::io::Read;
 
-        log::error!( "{}", error_msg );
+    let mut count = 0;
+    while count < buffer.len() {
+        match file.read( &mut buffer[ count.. ] ) {
+            Ok( 0 ) => { break; },
+            Ok( count_ ) => { count += count_; },
+            Err( error_ ) if error_.kind() == std::io::ErrorKind::Interrupted => {},
+            Err( error_ ) => { return Err( error_ ); },
+        }
+    }
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
+    return Ok( count );
+}
 
-        return Err( error );
-    }
+/// Returns the inode flags of the file, as set by `chattr`,
+/// or `0` if the file system does not support them.
+fn inode_flags( path: &std::path::Path ) -> std::io::Result< u32 >
+{
+    use std::os::unix::io::AsRawFd;
 
-    let src_hash = calc_file_hash( src_path )?;
-    let reflink_path = reflink_dir.join( &src_hash );
+    let file = std::fs::File::open( path )?;
+    let mut flags: libc::c_int = 0;
+    // SAFETY: `flags` is a valid out pointer for the `int` written by FS_IOC_GETFLAGS.
+    if unsafe { libc::ioctl( file.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut flags ) } != 0 {
+        let error = std::io::Error::last_os_error();
 
-    log::debug!(
-        "Copying file: \
-            src_path: {:?}, \
-            reflink_path: {:?}",
-        src_path,
-        reflink_path,
-    );
+        return match error.raw_os_error() {
+            Some( libc::ENOTTY ) | Some( libc::EOPNOTSUPP ) => { Ok( 0 ) },
 
-    match reflink_path.symlink_metadata() {
-        Ok( metadata_ ) => {
-            if !metadata_.is_file() {
-                let error_msg = format!(
-                    "Invalid reflink file type: not a file: \
-                        path: {:?}, \
-                        type: {:?}",
-                    reflink_path,
-                    metadata_.file_type(),
-                );
+            _ => { Err( error ) },
+        };
+    }
 
-                log::error!( "{}", error_msg );
+    return Ok( flags as u32 & FS_FL_USER_VISIBLE );
+}
 
-                let error = std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput, error_msg );
+/// Copies the extended attributes of the file, not following symlinks.
+///
+/// These include POSIX ACLs and security labels.
+fn copy_xattrs( src_path: &std::path::Path, dst_path: &std::path::Path )
+    -> std::io::Result< () >
+{
+    use std::os::unix::ffi::OsStrExt;
 
-                return Err( error );
-            }
+    log::trace!( "Begin `copy_xattrs`." );
 
-            log::debug!( "Reflink file already exists; skipping." );
-        },
-        Err( error_ ) => {
-            match error_.kind() {
-                std::io::ErrorKind::NotFound => {
-                    log::debug!( "Reflink file not found; creating." );
+    let c_src_path = std::ffi::CString::new( src_path.as_os_str().as_bytes() )?;
+    let c_dst_path = std::ffi::CString::new( dst_path.as_os_str().as_bytes() )?;
 
-                    reflink_or_copy_file( src_path, &reflink_path, &src_hash )?;
-                },
+    // SAFETY: `c_src_path` is a valid C string and the buffer is valid for `size` bytes.
+    let names_res = read_xattr_buffer( |buffer_, size_| unsafe {
+        libc::llistxattr( c_src_path.as_ptr(), buffer_.cast(), size_ )
+    } );
+    let names = match names_res {
+        Ok( names_ ) => { names_ },
+        Err( error_ ) if error_.raw_os_error() == Some( libc::EOPNOTSUPP ) => {
+            log::trace!( "End `copy_xattrs`." );
 
-                _ => { return Err( error_ ); },
-            }
+            return Ok( () );
         },
+        Err( error_ ) => { return Err( error_ ); },
+    };
+
+    for name_ in names.split( |byte_| *byte_ == 0 ).filter( |name_| !name_.is_empty() ) {
+        let c_name = std::ffi::CString::new( name_ )?;
+
+        log::debug!( "Copying extended attribute: {:?}", c_name );
+
+        // SAFETY: as above, and `c_name` is a valid C string.
+        let value = read_xattr_buffer( |buffer_, size_| unsafe {
+            libc::lgetxattr( c_src_path.as_ptr(), c_name.as_ptr(), buffer_, size_ )
+        } )?;
+        // SAFETY: `c_dst_path` and `c_name` are valid C strings,
+        // and `value` is valid for its length.
+        let set_res = unsafe {
+            libc::lsetxattr(
+                c_dst_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0 )
+        };
+        if set_res != 0 { return Err( std::io::Error::last_os_error() ); }
     }
 
-    log::debug!(
-        "Reflinking file: \
-            reflink_path: {:?}, \
-            dst_path: {:?}",
-        reflink_path,
-        dst_path,
-    );
-
-    match dst_path.symlink_metadata() {
-        Ok( metadata_ ) => {
-            if overwrite_dst  && !metadata_.is_dir() {
-                log::info!( "Removing destination path." );
+    log::trace!( "End `copy_xattrs`." );
 
-                std::fs::remove_file( dst_path )?;
-            }
-            else {
-                if metadata_.is_file()
-                        && metadata_.len() == src_metadata.len() {
-                    let dst_hash = calc_file_hash( dst_path )?;
-                    if dst_hash == src_hash {
-                        // This assumes destination has already been reflinked.
-                        log::info!( "Destination file already exists; skipping." );
+    return Ok( () );
+}
 
-                        return Ok( () );
-                    }
-                }
+/// Reads a variable-length extended attribute buffer with `read`,
+/// which is given a buffer and its size, or a null buffer to query the size.
+fn read_xattr_buffer< F >( read: F ) -> std::io::Result< Vec< u8 > >
+where
+    F: Fn( *mut libc::c_void, usize ) -> libc::ssize_t,
+{
+    loop {
+        let size = read( std::ptr::null_mut(), 0 );
+        if size < 0 { return Err( std::io::Error::last_os_error() ); }
 
-                log::info!( "Renaming destination path." );
+        let mut buffer = vec![ 0_u8; size as usize ];
+        let count = read( buffer.as_mut_ptr().cast(), buffer.len() );
+        if count < 0 {
+            let error = std::io::Error::last_os_error();
+            // The buffer grew since its size was queried.
+            if error.raw_os_error() == Some( libc::ERANGE ) { continue; }
 
-                backup_rename( dst_path, backup_suffix )?;
-            }
-        },
-        Err( error_ ) => {
-            match error_.kind() {
-                std::io::ErrorKind::NotFound => {
-                    log::debug!( "Destination file not found; creating." );
-                },
+            return Err( error );
+        }
+        buffer.truncate( count as usize );
 
-                _ => { return Err( error_ ); },
-            }
-        },
+        return Ok( buffer );
     }
-
-    reflink_file( &reflink_path, dst_path )?;
-
-    log::trace!( "End `cpdd_file`." );
-
-    return Ok( () );
 }
 
-fn cpdd_symlink< P, Q >(
-    src_path: P,
-    dst_path: Q,
-    overwrite_dst: bool,
-    backup_suffix: &str,
+/// Replaces the reflink directory file with a copy of the source file,
+/// via a temporary file whose hash is validated first.
+fn replace_reflink_file(
+    src_path: &std::path::Path,
+    reflink_path: &std::path::Path,
+    hash: &str,
 ) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `cpdd_symlink`." );
+    let reflink_dir = reflink_path.parent().unwrap_or( std::path::Path::new( "." ) );
+    let temp_path = reflink_dir.join( format!( "{}{}", REPAIR_PREFIX, hash ) );
+    if optional_metadata( &temp_path )?.is_some() { std::fs::remove_file( &temp_path )?; }
 
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
+    reflink_or_copy_file( src_path, &temp_path, hash, 0 )?;
+
+    // The source file may have changed since it was hashed.
+    let temp_hash = calc_file_hash( &temp_path )?;
+    if temp_hash != hash {
+        std::fs::remove_file( &temp_path )?;
 
-    let src_metadata = src_path.symlink_metadata()?;
-    if !src_metadata.file_type().is_symlink() {
         let error_msg = format!(
-            "Invalid source file type: not a symlink: \
-                path: {:?}, \
-                type: {:?}",
+            "Repair failed: hash mismatch: \
+                src_path: {:?}, \
+                hash: {:?}, \
+                copy_hash: {:?}",
             src_path,
-            src_metadata.file_type(),
+            hash,
+            temp_hash,
         );
 
         log::error!( "{}", error_msg );
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
-
-        return Err( error );
+        return Err( std::io::Error::other( error_msg ) );
     }
 
-    log::debug!(
-        "Copying symlink: \
-            src_path: {:?}, \
-            dst_path: {:?}",
-        src_path,
-        dst_path,
-    );
-
-    let src_link;
-    match dst_path.symlink_metadata() {
-        Ok( metadata_ ) => {
-            src_link = src_path.read_link()?;
-            if overwrite_dst  && !metadata_.is_dir() {
-                log::info!( "Removing destination path." );
+    std::fs::rename( &temp_path, reflink_path )?;
+    std::fs::File::open( reflink_dir )?.sync_all()?;
 
-                std::fs::remove_file( dst_path )?;
-            }
-            else {
-                if metadata_.file_type().is_symlink() {
-                    let dst_link = dst_path.read_link()?;
-                    if dst_link == src_link {
-                        log::info!(
-                                "Destination symlink already exists; skipping." );
+    return Ok( () );
+}
 
-                        return Ok( () );
-                    }
-                }
+/// Reads the directory entries with their metadata, sorted by name.
+fn read_dir_sorted( path: &std::path::Path, session: &mut Session )
+    -> std::io::Result< Vec< ( std::ffi::OsString, std::fs::Metadata ) > >
+{
+    let mut entries = Vec::new();
+    for entry_res_ in session.report_error( std::fs::read_dir( path ), path )? {
+        let entry = session.report_error( entry_res_, path )?;
+        let metadata = session.report_error( entry.metadata(), entry.path() )?;
+        entries.push( ( entry.file_name(), metadata ) );
+    }
+    entries.sort_by( |( name_1_, _ ), ( name_2_, _ )| name_1_.cmp( name_2_ ) );
 
-                log::info!( "Renaming destination path." );
+    return Ok( entries );
+}
 
-                backup_rename( dst_path, backup_suffix )?;
-            }
-        },
+/// Returns the metadata of the path, not following symlinks,
+/// or `None` if the path does not exist.
+fn optional_metadata< P >( path: P ) -> std::io::Result< Option< std::fs::Metadata > >
+where
+    P: AsRef< std::path::Path >,
+{
+    return match path.as_ref().symlink_metadata() {
+        Ok( metadata_ ) => { Ok( Some( metadata_ ) ) },
         Err( error_ ) => {
-            match error_.kind() {
-                std::io::ErrorKind::NotFound => {
-                    log::debug!( "Destination symlink not found; creating." );
-
-                    src_link = src_path.read_link()?;
-                },
+            match error_.kind() {
+                std::io::ErrorKind::NotFound => { Ok( None ) },
 
-                _ => { return Err( error_ ); },
+                _ => { Err( error_ ) },
             }
         },
-    }
+    };
+}
+
+/// Calculates the file hash, reporting progress and a `Hashed` event.
+fn hash_file< P >(
+    path: P,
+    algorithm: HashAlgorithm,
+    size: u64,
+    session: &mut Session,
+) -> std::io::Result< String >
+where
+    P: AsRef< std::path::Path >,
+{
+    let path = path.as_ref();
 
-    std::os::unix::fs::symlink( &src_link, dst_path )?;
-    sync_symlink( dst_path )?;
+    let start_time = std::time::Instant::now();
+    let hash_res = track_file_digest( path, algorithm, size, session );
+    let hash = session.report_error( hash_res, path )?;
 
-    log::trace!( "End `cpdd_symlink`." );
+    session.emit( Event{
+        src_path: Some( path.to_owned() ),
+        hash: Some( hash.clone() ),
+        size: Some( size ),
+        duration: Some( start_time.elapsed() ),
+        ..Event::new( EventKind::Hashed )
+    } );
 
-    return Ok( () );
+    return Ok( hash );
 }
 
-fn backup_rename< P >( path: P, suffix: &str )
-    -> std::io::Result< std::path::PathBuf >
+/// Calculates the file hash, reporting progress and checking for cancellation.
+fn track_file_digest< P >(
+    path: P,
+    algorithm: HashAlgorithm,
+    size: u64,
+    session: &mut Session,
+) -> std::io::Result< String >
 where
     P: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `backup_rename`." );
-
-    let path = path.as_ref();
+    let hash = calc_file_digest( path, algorithm, |count_| {
+        session.advance( count_ );
 
-    assert!( !suffix.is_empty() );  // FIX?: change to error
+        return session.check_cancelled();
+    } )?;
+    session.finish_file( size );
 
-    let mut backup_name = path.file_name().ok_or_else( || {
-        let error_msg = format!( "Invalid path: {:?}", path );
+    return Ok( hash );
+}
 
-        log::error!( "{}", error_msg );
+/// Returns whether the name is a well-formed reflink directory file name.
+fn is_reflink_name( name: &std::ffi::OsStr ) -> bool
+{
+    return name.to_str().is_some_and( |name_| {
+        name_.len() == HASH_LENGTH * 2
+            && name_.chars().all( |c_| matches!( c_, '0'..='9' | 'a'..='f' ) )
+    } );
+}
 
-        std::io::Error::new( std::io::ErrorKind::InvalidInput, error_msg )
-    } )?.to_owned();
-    backup_name.push( suffix );
-    let backup_path = path.with_file_name( backup_name );
+fn is_hex( s: &str ) -> bool
+{
+    return !s.is_empty() && s.chars().all( |c_| c_.is_ascii_hexdigit() );
+}
 
-    log::debug!(
-        "Renaming path: \
-            path: {:?}, \
-            backup_path: {:?}",
-        path,
-        backup_path,
-    );
+fn escape_hash_path( path: &str ) -> String
+{
+    return path.replace( '\\', "\\\\" )
+        .replace( '\n', "\\n" )
+        .replace( '\r', "\\r" );
+}
 
-    match backup_path.symlink_metadata() {
-        Ok( _ ) => {
-            log::info!( "Backup path exists; renaming." );
+fn unescape_hash_path( path: &str ) -> Option< String >
+{
+    let mut unescaped = String::with_capacity( path.len() );
+    let mut chars = path.chars();
+    while let Some( c_ ) = chars.next() {
+        if c_ != '\\' { unescaped.push( c_ ); continue; }
 
-            backup_rename( &backup_path, suffix )?;
-        },
-        Err( error_ ) => {
-            match error_.kind() {
-                std::io::ErrorKind::NotFound => {},
+        match chars.next()? {
+            '\\' => { unescaped.push( '\\' ); },
+            'n' => { unescaped.push( '\n' ); },
+            'r' => { unescaped.push( '\r' ); },
 
-                _ => { return Err( error_ ); },
-            }
-        },
+            _ => { return None; },
+        }
     }
 
-    std::fs::rename( path, &backup_path )?;
-    if backup_path.symlink_metadata()?.file_type().is_symlink() {
-        sync_symlink( &backup_path )?;
-    }
-    else { std::fs::File::open( &backup_path )?.sync_all()?; }
+    return Some( unescaped );
+}
 
-    log::trace!( "End `backup_rename`." );
+fn dir_id( metadata: &std::fs::Metadata ) -> ( u64, u64 )
+{
+    use std::os::unix::fs::MetadataExt;
 
-    return Ok( backup_path );
+    return ( metadata.dev(), metadata.ino() );
 }
 
-fn reflink_or_copy_file< P, Q >(
-    src_path: P,
-    dst_path: Q,
-    src_hash: &str,
-) -> std::io::Result< () >
+fn statvfs< P >( path: P ) -> std::io::Result< libc::statvfs >
 where
     P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
 {
-    log::trace!( "Begin `reflink_or_copy_file`." );
+    use std::os::unix::ffi::OsStrExt;
 
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
+    let path = path.as_ref();
+
+    let c_path = std::ffi::CString::new( path.as_os_str().as_bytes() )?;
+    let mut stat = std::mem::MaybeUninit::< libc::statvfs >::uninit();
+    // SAFETY: `c_path` is a valid C string and `stat` is a valid out pointer.
+    if unsafe { libc::statvfs( c_path.as_ptr(), stat.as_mut_ptr() ) } != 0 {
+        return Err( std::io::Error::last_os_error() );
+    }
 
-    // // FIX?: remove
-    // if dst_path.exists()
-    //         && src_path.canonicalize()? == dst_path.canonicalize()? {
-    //     let error_msg = format!(
-    //         "Invalid destination path: source resolves to the same path: \
-    //             src_path: {:?}, \
-    //             dst_path: {:?}, \
-    //             resolved_path: {:?}",
-    //         src_path,
-    //         dst_path,
-    //         src_path.canonicalize()?,
-    //     );
+    // SAFETY: `statvfs` succeeded and thus initialized `stat`.
+    return Ok( unsafe { stat.assume_init() } );
+}
 
-    //     log::error!( "{}", error_msg );
+fn is_same_file_system< P, Q >( path_1: P, path_2: Q ) -> std::io::Result< bool >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    use std::os::unix::fs::MetadataExt;
 
-    //     let error = std::io::Error::new(
-    //             std::io::ErrorKind::InvalidInput, error_msg );
+    let path_1 = path_1.as_ref();
+    let path_2 = path_2.as_ref();
 
-    //     return Err( error );
-    // }
+    let dev_1 = path_1.metadata()?.dev();
+    let dev_2 = path_2.metadata()?.dev();
+    let fsid_1 = statvfs( path_1 )?.f_fsid;
+    let fsid_2 = statvfs( path_2 )?.f_fsid;
 
     log::debug!(
-        "Reflinking or copying file: \
-            src_path: {:?}, \
-            dst_path: {:?}",
-        src_path,
-        dst_path,
+        "File system ids: \
+            path_1: {:?}, \
+            path_2: {:?}, \
+            dev_1: {:?}, \
+            dev_2: {:?}, \
+            fsid_1: {:?}, \
+            fsid_2: {:?}",
+        path_1,
+        path_2,
+        dev_1,
+        dev_2,
+        fsid_1,
+        fsid_2,
     );
 
-    if reflink::reflink( src_path, dst_path ).is_ok() {
-        log::debug!( "Reflinking succeeded." );
+    // Paths in different Btrfs subvolumes differ in both device and file system id,
+    // so a `false` result does not rule out reflinking; see `probe_reflink`.
+    return Ok( dev_1 == dev_2 || fsid_1 == fsid_2 );
+}
 
-        std::fs::File::open( dst_path )?.sync_all()?;
+fn probe_reflink< P, Q >( reflink_dir: P, dst_dir: Q ) -> std::io::Result< bool >
+where
+    P: AsRef< std::path::Path >,
+    Q: AsRef< std::path::Path >,
+{
+    let reflink_dir = reflink_dir.as_ref();
+    let dst_dir = dst_dir.as_ref();
 
-        return Ok( () );
+    let probe_name = format!( "{}{}", PREFLIGHT_PREFIX, std::process::id() );
+    let reflink_probe_path = reflink_dir.join( &probe_name );
+    let dst_probe_path = dst_dir.join( &probe_name );
+
+    log::debug!(
+        "Reflinking probe file: \
+            reflink_probe_path: {:?}, \
+            dst_probe_path: {:?}",
+        reflink_probe_path,
+        dst_probe_path,
+    );
+
+    std::fs::write( &reflink_probe_path, PREFLIGHT_CONTENT )?;
+    let reflink_res = reflink::reflink( &reflink_probe_path, &dst_probe_path );
+    let remove_res = std::fs::remove_file( &reflink_probe_path );
+    if reflink_res.is_ok() { std::fs::remove_file( &dst_probe_path )?; }
+    remove_res?;
+
+    if let Err( error_ ) = &reflink_res {
+        log::debug!( "Reflinking probe file failed: {:?}", error_ );
     }
 
-    log::debug!( "Reflinking failed; copying file." );
+    return Ok( reflink_res.is_ok() );
+}
 
-    std::fs::copy( src_path, dst_path )?;
-    std::fs::File::open( dst_path )?.sync_all()?;
+fn ensure_free_space< P >( path: P, bytes: u64, min_free_bytes: u64 )
+    -> std::io::Result< () >
+where
+    P: AsRef< std::path::Path >,
+{
+    let path = path.as_ref();
 
-    log::debug!( "Verifying destination file hash: src_hash: {:?}", src_hash );
+    let stat = statvfs( path )?;
+    let free_bytes = stat.f_bavail as u64 * stat.f_frsize as u64;
 
-    let dst_hash = calc_file_hash( dst_path )?;
-    if dst_hash != src_hash {
+    log::debug!(
+        "Checking free space: \
+            path: {:?}, \
+            bytes: {:?}, \
+            free_bytes: {:?}, \
+            min_free_bytes: {:?}",
+        path,
+        bytes,
+        free_bytes,
+        min_free_bytes,
+    );
+
+    if free_bytes < bytes.saturating_add( min_free_bytes ) {
         let error_msg = format!(
-            "File copy failed: hash mismatch: \
-                src_path: {:?}, \
-                dst_path: {:?}, \
-                src_hash: {:?}, \
-                dst_hash: {:?}",
-            src_path,
-            dst_path,
-            src_hash,
-            dst_hash,
+            "Insufficient free space: \
+                path: {:?}, \
+                bytes: {:?}, \
+                free_bytes: {:?}, \
+                min_free_bytes: {:?}",
+            path,
+            bytes,
+            free_bytes,
+            min_free_bytes,
         );
 
         log::error!( "{}", error_msg );
 
-        let error = std::io::Error::new( std::io::ErrorKind::Other, error_msg );
+        let error = std::io::Error::other( error_msg );
 
         return Err( error );
     }
 
-    log::trace!( "End `reflink_or_copy_file`." );
-
     return Ok( () );
 }
 
-fn reflink_file< P, Q >(
-    src_path: P,
-    dst_path: Q,
-) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
+
+#[ cfg( test ) ]
+mod tests
 {
-    log::trace!( "Begin `reflink_file`." );
+    use super::*;
+    use std::io::Write;
+
+    /// Creates an empty directory unique to the test.
+    fn test_dir( name: &str ) -> std::path::PathBuf
+    {
+        let path = std::env::temp_dir()
+            .join( format!( "cpdd-test-{}-{}", std::process::id(), name ) );
+        if path.exists() { std::fs::remove_dir_all( &path ).unwrap(); }
+        std::fs::create_dir_all( &path ).unwrap();
+
+        return path;
+    }
 
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
+    #[ test ]
+    fn checkpoint_round_trip()
+    {
+        let dir = test_dir( "checkpoint_round_trip" );
+        let path = dir.join( "checkpoint.jsonl" );
+        let interval = std::time::Duration::from_secs( 60 );
+
+        let mut checkpoint = Checkpoint::open( &path ).unwrap();
+        assert!( !checkpoint.verified_within( "a".as_ref(), interval ) );
+        checkpoint.record( "a".as_ref() ).unwrap();
+        checkpoint.record( "b".as_ref() ).unwrap();
+        checkpoint.record( "a".as_ref() ).unwrap();
+        drop( checkpoint );
+
+        std::fs::OpenOptions::new().append( true ).open( &path ).unwrap()
+            .write_all( b"not json\n" ).unwrap();
+
+        let checkpoint = Checkpoint::open( &path ).unwrap();
+        assert!( checkpoint.verified_within( "a".as_ref(), interval ) );
+        assert!( checkpoint.verified_within( "b".as_ref(), interval ) );
+        assert!( !checkpoint.verified_within( "c".as_ref(), interval ) );
+        assert!( !checkpoint.verified_within( "a".as_ref(), std::time::Duration::ZERO ) );
+        drop( checkpoint );
+
+        // Compaction keeps one line per hash and drops invalid lines.
+        let contents = std::fs::read_to_string( &path ).unwrap();
+        assert_eq!( contents.lines().count(), 2 );
+        assert!( !dir.join( "checkpoint.jsonl.tmp" ).exists() );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-    log::debug!(
-        "Reflinking file: \
-            src_path: {:?}, \
-            dst_path: {:?}",
-        src_path,
-        dst_path,
-    );
+    fn extent( physical: u64, length: u64, flags: u32 ) -> Extent
+    {
+        return Extent{ logical: 0, physical, length, flags };
+    }
 
-    reflink::reflink( src_path, dst_path )?;
-    std::fs::File::open( dst_path )?.sync_all()?;
+    #[ test ]
+    fn is_reflink_name_format()
+    {
+        let hash = "0123456789abcdef".repeat( 4 );
+        assert!( is_reflink_name( hash.as_ref() ) );
+        assert!( !is_reflink_name( hash[ 1.. ].as_ref() ) );
+        assert!( !is_reflink_name( format!( "{}0", hash ).as_ref() ) );
+        assert!( !is_reflink_name( hash.to_uppercase().as_ref() ) );
+        assert!( !is_reflink_name( format!( "{}{}", REPAIR_PREFIX, hash ).as_ref() ) );
+        assert!( !is_reflink_name( "".as_ref() ) );
+    }
 
-    log::trace!( "End `reflink_file`." );
+    #[ test ]
+    fn physical_overlap_ranges()
+    {
+        let extents = [ extent( 0, 100, 0 ), extent( 1000, 100, FIEMAP_EXTENT_LAST ) ];
+        assert_eq!( physical_overlap( &extents, &extents ), 200 );
+        assert_eq!( physical_overlap( &extents, &[ extent( 50, 1000, 0 ) ] ), 100 );
+        assert_eq!( physical_overlap( &extents, &[ extent( 100, 900, 0 ) ] ), 0 );
+        assert_eq!( physical_overlap( &extents, &[] ), 0 );
+    }
 
-    return Ok( () );
-}
+    #[ test ]
+    fn physical_overlap_unlocated()
+    {
+        for flags_ in &[
+            FIEMAP_EXTENT_UNKNOWN,
+            FIEMAP_EXTENT_DELALLOC,
+            FIEMAP_EXTENT_DATA_INLINE,
+        ] {
+            let extents = [ extent( 0, 100, *flags_ ) ];
+            assert_eq!( physical_overlap( &extents, &extents ), 0 );
+        }
+    }
 
-fn copy_metadata< P, Q >(
-    src_path: P,
-    dst_path: Q,
-) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-    Q: AsRef< std::path::Path >,
-{
-    log::trace!( "Begin `copy_metadata`." );
+    #[ test ]
+    fn is_inline_extents()
+    {
+        assert!( is_inline( &[ extent( 0, 100, FIEMAP_EXTENT_DATA_INLINE ) ] ) );
+        assert!( !is_inline( &[
+            extent( 0, 100, FIEMAP_EXTENT_DATA_INLINE ),
+            extent( 4096, 100, 0 ),
+        ] ) );
+        assert!( !is_inline( &[] ) );
+    }
 
-    let src_path = src_path.as_ref();
-    let dst_path = dst_path.as_ref();
+    #[ test ]
+    fn range_set_insert()
+    {
+        let mut ranges = RangeSet::default();
+        assert_eq!( ranges.insert( 100, 200 ), 100 );
+        assert_eq!( ranges.insert( 100, 200 ), 0 );
+        assert_eq!( ranges.insert( 150, 250 ), 50 );
+        assert_eq!( ranges.insert( 300, 400 ), 100 );
+        assert_eq!( ranges.insert( 250, 300 ), 50 );
+        assert_eq!( ranges.ranges.len(), 1 );
+        assert_eq!( ranges.insert( 0, 500 ), 200 );
+        assert_eq!( ranges.insert( 600, 600 ), 0 );
+        assert_eq!( ranges.ranges.into_iter().collect::< Vec< _ > >(), vec![ ( 0, 500 ) ] );
+    }
 
-    let src_metadata = src_path.symlink_metadata()?;
-    let dst_metadata = dst_path.symlink_metadata()?;
-    let src_type = src_metadata.file_type();
-    let dst_type = dst_metadata.file_type();
-    if src_type.is_dir() && !dst_type.is_dir()
-            || src_type.is_file() && !dst_type.is_file()
-            || src_type.is_symlink() && !dst_type.is_symlink() {
-        // This assumes source is not some other type.
-        let error_msg = format!(
-            "Invalid destination file type: source file type differs: \
-                src_path: {:?}, \
-                dst_path: {:?}, \
-                src_type: {:?}, \
-                dst_type: {:?}",
-            src_path,
-            dst_path,
-            src_type,
-            dst_type,
-        );
+    #[ test ]
+    fn check_paths_containment()
+    {
+        let dir = test_dir( "check_paths_containment" );
+        let src_dir = dir.join( "src" );
+        for path_ in &[ "src/dst", "src/sub/store", "dst", "store" ] {
+            std::fs::create_dir_all( dir.join( path_ ) ).unwrap();
+        }
+        std::os::unix::fs::symlink( src_dir.join( "dst" ), dir.join( "dst-link" ) ).unwrap();
+
+        let check = |dst_dir_: &str, reflink_dir_: &str, recurse_dirs_: bool| {
+            let check_res = check_paths(
+                &[ &src_dir ],
+                dir.join( dst_dir_ ),
+                dir.join( reflink_dir_ ),
+                recurse_dirs_,
+            );
 
-        log::error!( "{}", error_msg );
+            return check_res.map_err( |error_| error_.kind() );
+        };
+        let invalid = Err( std::io::ErrorKind::InvalidInput );
+        assert_eq!( check( "src/dst", "store", true ), invalid );
+        assert_eq!( check( "dst", "src/sub/store", true ), invalid );
+        assert_eq!( check( "src", "store", true ), invalid );
+        assert_eq!( check( "dst-link", "store", true ), invalid );
+        assert_eq!( check( "dst", "store", true ), Ok( () ) );
+        // Without recursion, the source directory itself is not walked.
+        assert_eq!( check( "src/dst", "store", false ), Ok( () ) );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
+    #[ test ]
+    fn check_paths_shared_prefix()
+    {
+        let dir = test_dir( "check_paths_shared_prefix" );
+        for path_ in &[ "src", "src2/dst", "src2/store" ] {
+            std::fs::create_dir_all( dir.join( path_ ) ).unwrap();
+        }
 
-        return Err( error );
+        check_paths(
+            &[ dir.join( "src" ) ], dir.join( "src2/dst" ), dir.join( "src2/store" ), true,
+        ).unwrap();
+        check_paths(
+            &[ dir.join( "src2/dst" ) ], dir.join( "src" ), dir.join( "src2/store" ), true,
+        ).unwrap();
+
+        std::fs::remove_dir_all( &dir ).unwrap();
     }
 
-    log::debug!(
-        "Copying metadata: \
-            src_path: {:?}, \
-            dst_path: {:?}",
-        src_path,
-        dst_path,
-    );
+    #[ test ]
+    fn parse_hash_line_formats()
+    {
+        type Expected = Option< ( Option< HashAlgorithm >, &'static str, &'static str ) >;
+
+        let cases: &[ ( &str, Expected ) ] = &[
+            // GNU text and binary modes, and cpdd.
+            ( "ABCdef01  dir/file name", Some( ( None, "abcdef01", "dir/file name" ) ) ),
+            ( "abcdef01 *dir/file", Some( ( None, "abcdef01", "dir/file" ) ) ),
+            ( "abcdef01 dir/file", Some( ( None, "abcdef01", "dir/file" ) ) ),
+            ( "abcdef01   leading space", Some( ( None, "abcdef01", " leading space" ) ) ),
+            // BSD, with a path containing the separators.
+            (
+                "BLAKE2b (dir/a) = b) = x) = ABCDEF01",
+                Some( ( Some( HashAlgorithm::Blake2b ), "abcdef01", "dir/a) = b) = x" ) ),
+            ),
+            (
+                "SHA256 (f) = abcdef01",
+                Some( ( Some( HashAlgorithm::Sha256 ), "abcdef01", "f" ) ),
+            ),
+            // Escaped paths.
+            ( "\\abcdef01  a\\nb\\\\c", Some( ( None, "abcdef01", "a\nb\\c" ) ) ),
+            (
+                "\\BLAKE2bp-256 (a\\rb) = abcdef01",
+                Some( ( Some( HashAlgorithm::Blake2bp ), "abcdef01", "a\rb" ) ),
+            ),
+            ( "abcdef01  a\\nb", Some( ( None, "abcdef01", "a\\nb" ) ) ),
+            // CRLF line endings.
+            ( "abcdef01  file\r", Some( ( None, "abcdef01", "file" ) ) ),
+            (
+                "SHA256 (file) = abcdef01\r",
+                Some( ( Some( HashAlgorithm::Sha256 ), "abcdef01", "file" ) ),
+            ),
+            // Malformed lines.
+            ( "", None ),
+            ( "abcdef01", None ),
+            ( "abcdef01 ", None ),
+            ( "abcdef01  ", None ),
+            ( "xyz  file", None ),
+            ( "  file", None ),
+            ( "SHA256 (file) = xyz", None ),
+            ( "SHA256 () = abcdef01", None ),
+            ( "SHA256 (file) abcdef01", None ),
+            ( "\\abcdef01  a\\tb", None ),
+            ( "\\abcdef01  a\\", None ),
+        ];
+        for ( line_, expected_ ) in cases {
+            let parsed = parse_hash_line( line_ ).map( |hash_line_| {
+                ( hash_line_.algorithm, hash_line_.hash, hash_line_.path )
+            } );
+            let expected = expected_.map( |( algorithm_, hash_, path_ )| {
+                ( algorithm_, hash_.to_owned(), std::path::PathBuf::from( path_ ) )
+            } );
+            assert_eq!( parsed, expected, "{:?}", line_ );
+        }
+    }
 
-    if !src_metadata.file_type().is_symlink() {
-        std::fs::set_permissions( dst_path, src_metadata.permissions() )?;
+    #[ test ]
+    fn hash_line_round_trip()
+    {
+        let formats = [ HashFormat::Cpdd, HashFormat::Gnu, HashFormat::Bsd ];
+        let paths = [ "file", " space", "*star", "  two", "a\nb", "back\\slash", "(x) = y" ];
+        for path_ in &paths {
+            for format_ in &formats {
+                let line =
+                        format_hash_line( "abcdef01", path_, HashAlgorithm::Sha256, *format_ );
+                let hash_line = parse_hash_line( &line ).unwrap();
+                assert_eq!( hash_line.hash, "abcdef01" );
+                // The `cpdd` format does not escape newlines.
+                if *format_ == HashFormat::Cpdd && path_.contains( '\n' ) { continue; }
+                assert_eq!( hash_line.path, std::path::Path::new( path_ ), "{:?}", line );
+            }
+        }
     }
-    filetime::set_symlink_file_times(
-        dst_path,
-        filetime::FileTime::from_last_access_time( &src_metadata ),
-        filetime::FileTime::from_last_modification_time( &src_metadata ),
-    )?;
-    if dst_metadata.file_type().is_symlink() {
-        sync_symlink( dst_path )?;
+
+    /// Renames `file` in the directory to a backup path twice,
+    /// recreating it with new content in between,
+    /// and returns the backup paths.
+    fn rename_twice( dir: &std::path::Path, backup_style: BackupStyle )
+        -> ( std::path::PathBuf, std::path::PathBuf )
+    {
+        let path = dir.join( "file" );
+        let options = CpddOptions{ backup_style, ..CpddOptions::default() };
+        let mut observer = ();
+        let mut session = Session::new( &mut observer );
+
+        std::fs::write( &path, b"first" ).unwrap();
+        let backup_path_1 = backup_rename( &path, &options, &mut session ).unwrap();
+        std::fs::write( &path, b"second" ).unwrap();
+        let backup_path_2 = backup_rename( &path, &options, &mut session ).unwrap();
+
+        // Earlier backups keep their names and content.
+        assert_ne!( backup_path_1, backup_path_2 );
+        assert_eq!( std::fs::read( &backup_path_1 ).unwrap(), b"first" );
+        assert_eq!( std::fs::read( &backup_path_2 ).unwrap(), b"second" );
+        assert!( !path.exists() );
+
+        return ( backup_path_1, backup_path_2 );
     }
-    else { std::fs::File::open( dst_path )?.sync_all()?; }
 
-    log::trace!( "End `copy_metadata`." );
+    #[ test ]
+    fn backup_numbered()
+    {
+        let dir = test_dir( "backup_numbered" );
+
+        let ( backup_path_1, backup_path_2 ) = rename_twice( &dir, BackupStyle::Numbered );
+        assert_eq!( backup_path_1, dir.join( "file.~1~" ) );
+        assert_eq!( backup_path_2, dir.join( "file.~2~" ) );
+
+        // Numbering continues after the largest existing number.
+        std::fs::write( dir.join( "file.~7~" ), b"seventh" ).unwrap();
+        std::fs::write( dir.join( "file.~x~" ), b"other" ).unwrap();
+        std::fs::write( dir.join( "file" ), b"third" ).unwrap();
+        let options = CpddOptions{
+            backup_style: BackupStyle::Numbered,
+            ..CpddOptions::default()
+        };
+        let mut observer = ();
+        let mut session = Session::new( &mut observer );
+        let backup_path_3 =
+                backup_rename( dir.join( "file" ), &options, &mut session ).unwrap();
+        assert_eq!( backup_path_3, dir.join( "file.~8~" ) );
+        assert_eq!( std::fs::read( dir.join( "file.~7~" ) ).unwrap(), b"seventh" );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-    return Ok( () );
-}
+    #[ test ]
+    fn backup_empty_suffix()
+    {
+        let dir = test_dir( "backup_empty_suffix" );
+        let path = dir.join( "file" );
+        std::fs::write( &path, b"content" ).unwrap();
+        let options = CpddOptions{ backup_suffix: String::new(), ..CpddOptions::default() };
+        let mut observer = ();
+        let mut session = Session::new( &mut observer );
+
+        let error = backup_rename( &path, &options, &mut session ).unwrap_err();
+        assert_eq!( error.kind(), std::io::ErrorKind::InvalidInput );
+        assert_eq!( std::fs::read( &path ).unwrap(), b"content" );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-fn sync_symlink< P >( path: P ) -> std::io::Result< () >
-where
-    P: AsRef< std::path::Path >,
-{
-    log::trace!( "Begin `sync_symlink`." );
+    #[ test ]
+    fn backup_timestamp_names()
+    {
+        let dir = test_dir( "backup_timestamp_names" );
 
-    let path = path.as_ref();
+        let ( backup_path_1, backup_path_2 ) = rename_twice( &dir, BackupStyle::Timestamp );
+        for backup_path_ in &[ backup_path_1, backup_path_2 ] {
+            let name = backup_path_.file_name().unwrap().to_str().unwrap();
+            assert!( name.starts_with( "file." ), "{:?}", name );
+            assert!( name.contains( 'T' ) && name.contains( 'Z' ), "{:?}", name );
+        }
 
-    let metadata = path.symlink_metadata()?;
-    if !metadata.file_type().is_symlink() {
-        let error_msg = format!(
-            "Invalid file type: not a symlink: \
-                path: {:?}, \
-                type: {:?}",
-            path,
-            metadata.file_type(),
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
+
+    #[ test ]
+    fn backup_hash_names()
+    {
+        let dir = test_dir( "backup_hash_names" );
+
+        let ( backup_path_1, backup_path_2 ) = rename_twice( &dir, BackupStyle::Hash );
+        let hash_1 = calc_hash( b"first" );
+        let hash_2 = calc_hash( b"second" );
+        assert_eq!(
+            backup_path_1,
+            dir.join( format!( "file.{}", &hash_1[ ..BACKUP_HASH_LENGTH ] ) ),
+        );
+        assert_eq!(
+            backup_path_2,
+            dir.join( format!( "file.{}", &hash_2[ ..BACKUP_HASH_LENGTH ] ) ),
         );
 
-        log::error!( "{}", error_msg );
+        // The same content again is numbered rather than overwriting the backup.
+        std::fs::write( dir.join( "file" ), b"first" ).unwrap();
+        let options = CpddOptions{ backup_style: BackupStyle::Hash, ..CpddOptions::default() };
+        let mut observer = ();
+        let mut session = Session::new( &mut observer );
+        let backup_path_3 =
+                backup_rename( dir.join( "file" ), &options, &mut session ).unwrap();
+        assert_eq!( backup_path_3, backup_path_1.with_file_name(
+                format!( "file.{}.1", &hash_1[ ..BACKUP_HASH_LENGTH ] ) ) );
+        assert_eq!( std::fs::read( &backup_path_1 ).unwrap(), b"first" );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-        let error = std::io::Error::new(
-                std::io::ErrorKind::InvalidInput, error_msg );
+    /// Sets a user extended attribute, returning whether the file system supports it.
+    fn set_user_xattr( path: &std::path::Path, value: &[ u8 ] ) -> bool
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new( path.as_os_str().as_bytes() ).unwrap();
+        let c_name = std::ffi::CString::new( "user.cpdd-test" ).unwrap();
+        // SAFETY: the strings are valid C strings, and `value` is valid for its length.
+        let set_res = unsafe {
+            libc::lsetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+
+        return set_res == 0;
+    }
 
-        return Err( error );
+    fn user_xattr( path: &std::path::Path ) -> Vec< u8 >
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let c_path = std::ffi::CString::new( path.as_os_str().as_bytes() ).unwrap();
+        let c_name = std::ffi::CString::new( "user.cpdd-test" ).unwrap();
+        // SAFETY: the strings are valid C strings, and the buffer is valid for `size` bytes.
+        return read_xattr_buffer( |buffer_, size_| unsafe {
+            libc::lgetxattr( c_path.as_ptr(), c_name.as_ptr(), buffer_, size_ )
+        } ).unwrap();
     }
 
-    let mut abs_path = std::env::current_dir()?;
-    abs_path.push( path );
-    let parent_path = abs_path.parent().unwrap();
+    #[ test ]
+    fn relink_preserves_content_and_metadata()
+    {
+        use std::os::unix::fs::{ MetadataExt, PermissionsExt };
+
+        let dir = test_dir( "relink_preserves_content_and_metadata" );
+        let path = dir.join( "file" );
+        let temp_path = dir.join( format!( "{}file", RELINK_PREFIX ) );
+        std::fs::write( &path, b"relinked content\n" ).unwrap();
+        std::fs::set_permissions( &path, std::fs::Permissions::from_mode( 0o640 ) ).unwrap();
+        let mtime = filetime::FileTime::from_unix_time( 1_000_000_000, 0 );
+        filetime::set_file_mtime( &path, mtime ).unwrap();
+        let has_xattr = set_user_xattr( &path, b"value" );
+        let metadata = path.symlink_metadata().unwrap();
+        std::fs::copy( &path, &temp_path ).unwrap();
+
+        place_relinked_file( &temp_path, &path, &metadata ).unwrap();
+
+        let relinked_metadata = path.symlink_metadata().unwrap();
+        assert_eq!( std::fs::read( &path ).unwrap(), b"relinked content\n" );
+        assert_eq!( relinked_metadata.mode(), metadata.mode() );
+        assert_eq!( relinked_metadata.modified().unwrap(), metadata.modified().unwrap() );
+        assert_ne!( relinked_metadata.ino(), metadata.ino() );
+        if has_xattr { assert_eq!( user_xattr( &path ), b"value" ); }
+        assert!( !temp_path.exists() );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-    log::debug!( "Syncing symlink parent: {:?}", parent_path );
+    #[ test ]
+    fn relink_skips_hardlinked_files()
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = test_dir( "relink_skips_hardlinked_files" );
+        let reflink_dir = dir.join( "reflink" );
+        let path = dir.join( "file" );
+        let other_path = dir.join( "other" );
+        let content = vec![ b'x'; 65536 ];
+        std::fs::create_dir( &reflink_dir ).unwrap();
+        std::fs::write( &path, &content ).unwrap();
+        std::fs::hard_link( &path, &other_path ).unwrap();
+        let reflink_path = reflink_dir.join( calc_file_hash( &path ).unwrap() );
+        std::fs::write( &reflink_path, &content ).unwrap();
+        let ino = path.symlink_metadata().unwrap().ino();
+
+        let mut observer = ();
+        let mut session = Session::new( &mut observer );
+        let entry = check_file_sharing(
+            &path, content.len() as u64, &reflink_dir, true, &mut session ).unwrap();
+
+        assert!( !entry.relinked );
+        assert_eq!( path.symlink_metadata().unwrap().ino(), ino );
+        assert_eq!( other_path.symlink_metadata().unwrap().ino(), ino );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-    std::fs::File::open( parent_path )?.sync_all()?;
+    #[ test ]
+    fn relink_aborts_on_content_mismatch()
+    {
+        let dir = test_dir( "relink_aborts_on_content_mismatch" );
+        let path = dir.join( "file" );
+        let temp_path = dir.join( format!( "{}file", RELINK_PREFIX ) );
+        std::fs::write( &path, b"original" ).unwrap();
+        let metadata = path.symlink_metadata().unwrap();
+        std::fs::copy( &path, &temp_path ).unwrap();
+
+        // A modification of the same size keeping the modification time.
+        std::fs::write( &path, b"modified" ).unwrap();
+        filetime::set_file_mtime(
+            &path, filetime::FileTime::from_last_modification_time( &metadata ) ).unwrap();
+
+        assert!( place_relinked_file( &temp_path, &path, &metadata ).is_err() );
+        assert_eq!( std::fs::read( &path ).unwrap(), b"modified" );
+
+        std::fs::remove_dir_all( &dir ).unwrap();
+    }
 
-    log::trace!( "End `sync_symlink`." );
+    fn snapshot_at( name: &str, time: &str ) -> Snapshot
+    {
+        let time = chrono::DateTime::parse_from_rfc3339( time ).unwrap();
+        return Snapshot
+        {
+            name: name.to_string(),
+            path: std::path::PathBuf::from( name ),
+            time: std::time::SystemTime::from( time ),
+        };
+    }
 
-    return Ok( () );
+    fn expired_names( snapshots: &[Snapshot], policy: &RetentionPolicy ) -> Vec< String >
+    {
+        return expired_snapshots( snapshots, policy ).iter()
+            .map( |snapshot_| snapshot_.name.clone() ).collect();
+    }
+
+    #[ test ]
+    fn snapshot_retention()
+    {
+        let policy = | keep_last, keep_daily, keep_weekly |
+            RetentionPolicy{ keep_last, keep_daily, keep_weekly };
+
+        // Keep-last keeps the newest snapshots whatever their order in the slice.
+        let snapshots = vec![
+            snapshot_at( "c", "2025-03-03T10:00:00Z" ),
+            snapshot_at( "a", "2025-03-01T10:00:00Z" ),
+            snapshot_at( "d", "2025-03-04T10:00:00Z" ),
+            snapshot_at( "b", "2025-03-02T10:00:00Z" ),
+        ];
+        assert_eq!( expired_names( &snapshots, &policy( 2, 0, 0 ) ), [ "a", "b" ] );
+
+        // Keep-daily keeps only the last snapshot of each day.
+        let snapshots = vec![
+            snapshot_at( "a", "2025-03-01T08:00:00Z" ),
+            snapshot_at( "b", "2025-03-01T12:00:00Z" ),
+            snapshot_at( "c", "2025-03-02T09:00:00Z" ),
+            snapshot_at( "d", "2025-03-02T18:00:00Z" ),
+            snapshot_at( "e", "2025-03-03T07:00:00Z" ),
+        ];
+        assert_eq!( expired_names( &snapshots, &policy( 0, 3, 0 ) ), [ "a", "c" ] );
+        assert_eq!( expired_names( &snapshots, &policy( 0, 2, 0 ) ), [ "a", "b", "c" ] );
+
+        // Keep-weekly uses ISO weeks: 2024-12-30 is in week 1 of ISO year 2025.
+        let snapshots = vec![
+            snapshot_at( "a", "2024-12-29T10:00:00Z" ),
+            snapshot_at( "b", "2024-12-30T10:00:00Z" ),
+            snapshot_at( "c", "2025-01-02T10:00:00Z" ),
+            snapshot_at( "d", "2025-01-06T10:00:00Z" ),
+        ];
+        assert_eq!( expired_names( &snapshots, &policy( 0, 0, 3 ) ), [ "b" ] );
+        assert_eq!( expired_names( &snapshots, &policy( 0, 0, 2 ) ), [ "a", "b" ] );
+
+        // A snapshot kept by one rule still counts towards the others.
+        let snapshots = vec![
+            snapshot_at( "a", "2025-01-01T10:00:00Z" ),
+            snapshot_at( "b", "2025-01-08T10:00:00Z" ),
+            snapshot_at( "c", "2025-01-14T10:00:00Z" ),
+            snapshot_at( "d", "2025-01-15T09:00:00Z" ),
+            snapshot_at( "e", "2025-01-15T18:00:00Z" ),
+        ];
+        assert_eq!( expired_names( &snapshots, &policy( 1, 2, 2 ) ), [ "a", "d" ] );
+        assert_eq!( expired_names( &snapshots, &policy( 2, 2, 2 ) ), [ "a" ] );
+        assert!( expired_names( &snapshots, &policy( 5, 0, 0 ) ).is_empty() );
+    }
 }